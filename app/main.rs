@@ -1,24 +1,585 @@
-use anyhow::Result;
-use ccompiler::gen::compile_from_source;
+use anyhow::{bail, Context, Result};
+use ccompiler::ast::dump_ast;
+use ccompiler::compiler::{CompileFailure, Compiler};
+use ccompiler::diagnostic::{Diagnostic, Diagnostics, SourceMap};
+use ccompiler::explain::explain;
+use ccompiler::format::{format_source, FormatOptions};
+use ccompiler::gen::{lex, parse};
+use ccompiler::preprocess::Preprocessor;
+use ccompiler::token::{dump_tokens, split_lines, MAX_ERRORS};
+use ccompiler::warning::WarningOptions;
 
-use std::{
-    fs::File,
-    io::{BufRead, BufReader},
-};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::{env, fs, thread};
 
-fn main() -> Result<()> {
-    let mut source = Vec::new();
-    let file = File::open("./input")?;
-    let reader = BufReader::new(file);
+const USAGE: &str = "usage: ccompiler [options] <file>...
+       ccompiler fmt [--check] [--width=<n>] [-I<dir>] <file>...
 
-    for line in reader.lines() {
-        source.push(line?);
+  <file>        a C source, an assembly file (.s) or an object file or
+                library (.o, .a, .so); '-' reads C from stdin
+  -o <file>     write the output to <file>; with -S, '-' prints it
+  -S            stop at assembly
+  -c            stop at object files
+  --emit=tokens|ast|ir|asm
+                print the tokens, the syntax tree once types are added, or
+                the assembly; there is no IR
+  -O<level>     optimize; from -O1 on constant expressions are folded
+  -l<lib>       link with <lib>
+  -L<dir>       search <dir> for libraries
+  -save-temps   keep the intermediate assembly and object files
+  -j <n>        compile up to <n> files at the same time; by default as
+                many as there are processors
+  -I<dir>       search <dir> for included files
+  -W<name>      turn a warning category on, -Wno-<name> turns it off
+  -Werror       make warnings errors
+  -fmax-errors=<n>
+                stop after <n> syntax errors; 0 means no limit
+  --diagnostics-format=human|json
+  --explain <code>
+                explain an error code
+
+fmt lays out each file in place, or prints it if it is '-'. It only formats
+files that parse.
+  --check       write nothing, and fail if any file would change
+  --width=<n>   wrap lines longer than <n> columns, 80 by default";
+
+/// What the compiler stops at.
+#[derive(Clone, Copy, PartialEq)]
+enum Emit {
+    Tokens,
+    Ast,
+    Assembly,
+    Object,
+    Executable,
+}
+
+impl Emit {
+    /// Whether the output is text written by the compiler itself, so that
+    /// the assembler is not run.
+    fn is_text(self) -> bool {
+        matches!(self, Emit::Tokens | Emit::Ast | Emit::Assembly)
+    }
+
+    fn flag(self) -> &'static str {
+        match self {
+            Emit::Tokens => "--emit=tokens",
+            Emit::Ast => "--emit=ast",
+            Emit::Assembly => "-S",
+            Emit::Object => "-c",
+            Emit::Executable => "",
+        }
+    }
+}
+
+/// The settings every C source is compiled with.
+struct Options {
+    include_paths: Vec<String>,
+    max_errors: usize,
+    warnings: WarningOptions,
+    opt_level: u8,
+    json: bool,
+}
+
+impl Options {
+    fn compiler(&self) -> Compiler {
+        let compiler = Compiler::new()
+            .with_warnings(self.warnings.clone())
+            .with_max_errors(self.max_errors)
+            .with_opt_level(self.opt_level);
+        self.include_paths
+            .iter()
+            .fold(compiler, |compiler, dir| compiler.with_include_path(dir))
+    }
+}
+
+/// Intermediate files, removed when dropped unless `-save-temps` was given.
+struct Temps {
+    files: Vec<PathBuf>,
+    keep: bool,
+}
+
+impl Temps {
+    /// A file for the intermediate output of `input`. Kept files are named
+    /// after the input in the current directory, as `foo.c` gives `foo.s`,
+    /// so two inputs named alike, or a kept file that is itself an input,
+    /// are an error; the others go to the temporary directory under a
+    /// unique name.
+    fn path(&mut self, input: &str, extension: &str, inputs: &[String]) -> Result<PathBuf> {
+        let stem = match Path::new(input).file_stem() {
+            Some(stem) if input != "-" => stem.to_string_lossy(),
+            _ => "stdin".into(),
+        };
+        let path = if self.keep {
+            let path = PathBuf::from(format!("{}.{}", stem, extension));
+            if self.files.contains(&path) || inputs.iter().any(|other| Path::new(other) == path) {
+                bail!(
+                    "-save-temps would keep an intermediate file of '{}' as '{}', which is already an input or kept for another one",
+                    input,
+                    path.display()
+                );
+            }
+            path
+        } else {
+            env::temp_dir().join(format!(
+                "ccompiler-{}-{}-{}.{}",
+                process::id(),
+                self.files.len(),
+                stem,
+                extension
+            ))
+        };
+        self.files.push(path.clone());
+        Ok(path)
+    }
+}
+
+impl Drop for Temps {
+    fn drop(&mut self) {
+        if !self.keep {
+            for file in &self.files {
+                let _ = fs::remove_file(file);
+            }
+        }
+    }
+}
+
+fn main() {
+    match run() {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(error) => {
+            eprintln!("ccompiler: error: {:#}", error);
+            process::exit(1);
+        }
+    }
+}
+
+/// Compiles as the arguments say. Returns whether it succeeded; errors in
+/// the program are printed as diagnostics, while errors of the driver itself
+/// are returned.
+fn run() -> Result<bool> {
+    if env::args().nth(1).as_deref() == Some("fmt") {
+        return fmt(env::args().skip(2));
+    }
+    let mut options = Options {
+        include_paths: Vec::new(),
+        max_errors: MAX_ERRORS,
+        warnings: WarningOptions::new(),
+        opt_level: 0,
+        json: false,
+    };
+    let mut emit = Emit::Executable;
+    let mut inputs = Vec::new();
+    let mut output = None;
+    let mut link_args = Vec::new();
+    let mut save_temps = false;
+    let mut jobs_at_once = thread::available_parallelism().map_or(1, |n| n.get());
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if options
+            .warnings
+            .parse_flag(&arg)
+            .map_err(anyhow::Error::msg)?
+        {
+            continue;
+        }
+        match arg.as_str() {
+            "--explain" => {
+                let code = args.next().unwrap_or_default();
+                let explanation =
+                    explain(&code).with_context(|| format!("no explanation for '{}'", code))?;
+                println!("{}", explanation);
+                return Ok(true);
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(true);
+            }
+            "-o" => output = Some(args.next().context("missing filename after '-o'")?),
+            "-S" => emit = Emit::Assembly,
+            "-c" => emit = Emit::Object,
+            "-save-temps" => save_temps = true,
+            "-j" => jobs_at_once = parse_jobs(&args.next().unwrap_or_default())?,
+            "--emit=tokens" => emit = Emit::Tokens,
+            "--emit=ast" => emit = Emit::Ast,
+            "--emit=asm" => emit = Emit::Assembly,
+            "--emit=ir" => bail!(
+                "there is no IR: code is generated from the syntax tree, \
+                 which '--emit=ast' shows"
+            ),
+            "-" => inputs.push(arg),
+            _ => {
+                if let Some(dir) = arg.strip_prefix("-I") {
+                    options.include_paths.push(dir.to_string());
+                } else if arg.starts_with("-l") || arg.starts_with("-L") {
+                    link_args.push(arg);
+                } else if let Some(level) = arg.strip_prefix("-O") {
+                    options.opt_level = match level {
+                        "" => 1,
+                        _ => level
+                            .parse()
+                            .with_context(|| format!("invalid optimization level '{}'", arg))?,
+                    };
+                } else if let Some(n) = arg.strip_prefix("-j") {
+                    jobs_at_once = parse_jobs(n)?;
+                } else if let Some(n) = arg.strip_prefix("-fmax-errors=") {
+                    options.max_errors = n
+                        .parse()
+                        .with_context(|| format!("invalid value '{}' for -fmax-errors", n))?;
+                } else if let Some(format) = arg.strip_prefix("--diagnostics-format=") {
+                    options.json = match format {
+                        "json" => true,
+                        "human" => false,
+                        _ => bail!("unknown diagnostics format '{}'", format),
+                    };
+                } else if arg.starts_with('-') {
+                    bail!("unknown option '{}'\n\n{}", arg, USAGE);
+                } else {
+                    inputs.push(arg);
+                }
+            }
+        }
+    }
+    if inputs.is_empty() {
+        bail!("no input files\n\n{}", USAGE);
     }
+    if inputs.iter().filter(|input| *input == "-").count() > 1 {
+        bail!("stdin can only be read once");
+    }
+    if emit != Emit::Executable && output.is_some() && inputs.len() > 1 {
+        bail!("cannot specify '-o' with '-S' or '-c' and more than one input file");
+    }
+
+    let mut temps = Temps {
+        files: Vec::new(),
+        keep: save_temps,
+    };
+    // Where everything goes is decided up front, so that the files can then
+    // be built in any order.
+    let mut jobs = Vec::new();
+    let mut objects = Vec::new();
+    for input in &inputs {
+        let kind = InputKind::of(input);
+        if emit.is_text() && kind != InputKind::Source {
+            bail!(
+                "'{}' is not a C source, so there is nothing to do with {}",
+                input,
+                emit.flag()
+            );
+        }
+        if emit == Emit::Object && kind == InputKind::Linker {
+            bail!(
+                "'{}' is already linker input, so there is nothing to do with -c",
+                input
+            );
+        }
 
-    match compile_from_source(source) {
-        Ok(dest) => println!("{}", dest),
-        Err(err) => println!("{}", err),
+        let assembly = match kind {
+            InputKind::Source => match (emit, &output) {
+                (_, Some(output)) if emit.is_text() => PathBuf::from(output),
+                (Emit::Tokens | Emit::Ast, None) => PathBuf::from("-"),
+                (Emit::Assembly, None) if input == "-" => PathBuf::from("-"),
+                (Emit::Assembly, None) => output_path(input, "s"),
+                _ => temps.path(input, "s", &inputs)?,
+            },
+            InputKind::Assembly => PathBuf::from(input),
+            InputKind::Linker => {
+                objects.push(PathBuf::from(input));
+                continue;
+            }
+        };
+        let object = match (emit, &output) {
+            _ if emit.is_text() => None,
+            (Emit::Object, Some(output)) => Some(PathBuf::from(output)),
+            (Emit::Object, None) if input == "-" => Some(PathBuf::from("a.o")),
+            (Emit::Object, None) => Some(output_path(input, "o")),
+            _ => Some(temps.path(input, "o", &inputs)?),
+        };
+        objects.extend(object.clone());
+        jobs.push(Job {
+            input,
+            kind,
+            assembly,
+            object,
+        });
     }
 
-    Ok(())
+    // Files are compiled each on their own, so they can be compiled at the
+    // same time. Diagnostics are shown in the order of the files.
+    let mut success = true;
+    for batch in jobs.chunks(jobs_at_once) {
+        let results: Vec<Result<(bool, String)>> = thread::scope(|scope| {
+            let handles: Vec<_> = batch
+                .iter()
+                .map(|job| scope.spawn(|| job.build(emit, &options)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("a compiler thread panicked"))
+                .collect()
+        });
+        // A job that fails does not stop the others in its batch, so show
+        // every log before giving up.
+        let mut error = None;
+        for result in results {
+            match result {
+                Ok((built, log)) => {
+                    eprint!("{}", log);
+                    success &= built;
+                }
+                Err(e) => {
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        if let Some(error) = error {
+            return Err(error);
+        }
+    }
+    if !success || emit != Emit::Executable {
+        return Ok(success);
+    }
+
+    let mut log = String::new();
+    let linked = run_tool(
+        Command::new("cc")
+            .arg("-o")
+            .arg(output.as_deref().unwrap_or("a.out"))
+            .args(&objects)
+            .args(&link_args),
+        &mut log,
+    )?;
+    eprint!("{}", log);
+    Ok(linked)
+}
+
+/// A file to compile or assemble, and where its outputs go.
+struct Job<'a> {
+    input: &'a str,
+    kind: InputKind,
+    /// Where a C source is compiled to, or the input itself if it is
+    /// assembly.
+    assembly: PathBuf,
+    /// Where the assembly is assembled to, if it is.
+    object: Option<PathBuf>,
+}
+
+impl Job<'_> {
+    /// Returns whether it succeeded, and what should be shown on stderr.
+    fn build(&self, emit: Emit, options: &Options) -> Result<(bool, String)> {
+        let mut log = String::new();
+        if self.kind == InputKind::Source
+            && !compile(self.input, &self.assembly, emit, options, &mut log)?
+        {
+            return Ok((false, log));
+        }
+        let Some(object) = &self.object else {
+            return Ok((true, log));
+        };
+        let assembled = run_tool(
+            Command::new("as").arg("-o").arg(object).arg(&self.assembly),
+            &mut log,
+        )?;
+        Ok((assembled, log))
+    }
+}
+
+/// Formats each file, or with `--check` only reports those that would
+/// change. Returns whether every file parsed, and with `--check` whether
+/// every one was formatted already.
+fn fmt(args: impl Iterator<Item = String>) -> Result<bool> {
+    let mut check_only = false;
+    let mut options = FormatOptions::default();
+    let mut include_paths = Vec::new();
+    let mut inputs = Vec::new();
+    for arg in args {
+        if arg == "--check" {
+            check_only = true;
+        } else if let Some(width) = arg.strip_prefix("--width=") {
+            options.width = width
+                .parse()
+                .with_context(|| format!("invalid width '{}'", width))?;
+        } else if let Some(dir) = arg.strip_prefix("-I") {
+            include_paths.push(dir.to_string());
+        } else if arg.starts_with('-') && arg != "-" {
+            bail!("unknown option '{}'\n\n{}", arg, USAGE);
+        } else {
+            inputs.push(arg);
+        }
+    }
+    if inputs.is_empty() {
+        bail!("no input files\n\n{}", USAGE);
+    }
+
+    let mut success = true;
+    for input in &inputs {
+        let (file, text) = read_input(input)?;
+        let mut preprocessor = Preprocessor::new();
+        for dir in &include_paths {
+            preprocessor.add_include_path(dir);
+        }
+        let formatted = lex(&mut preprocessor, file, split_lines(&text))
+            .map_err(|error| vec![error])
+            .and_then(|mut ts| parse(&mut ts, MAX_ERRORS))
+            .and_then(|_| format_source(file, &text, &options).map_err(|error| vec![error]));
+        let formatted = match formatted {
+            Ok(formatted) => formatted,
+            Err(errors) => {
+                let diagnostics: Diagnostics = errors.iter().map(Diagnostic::from).collect();
+                eprint!("{}", diagnostics.render(preprocessor.sources()));
+                success = false;
+                continue;
+            }
+        };
+
+        if check_only {
+            if formatted != text {
+                eprintln!("{}: would be reformatted", file);
+                success = false;
+            }
+        } else if input == "-" {
+            print!("{}", formatted);
+        } else if formatted != text {
+            fs::write(input, formatted).with_context(|| format!("cannot write '{}'", input))?;
+        }
+    }
+    Ok(success)
+}
+
+fn parse_jobs(n: &str) -> Result<usize> {
+    match n.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => bail!("invalid number of jobs '{}'", n),
+    }
+}
+
+/// How an input file is handled, told by its extension. Anything that is
+/// not assembly or linker input is taken to be C.
+#[derive(PartialEq)]
+enum InputKind {
+    Source,
+    Assembly,
+    Linker,
+}
+
+impl InputKind {
+    fn of(input: &str) -> Self {
+        match Path::new(input).extension().and_then(|ext| ext.to_str()) {
+            Some("s") => InputKind::Assembly,
+            Some("o" | "a" | "so") => InputKind::Linker,
+            _ => InputKind::Source,
+        }
+    }
+}
+
+/// The output file named after the input in the current directory, as
+/// `src/foo.c` gives `foo.o`.
+fn output_path(input: &str, extension: &str) -> PathBuf {
+    let name = Path::new(input).file_name().unwrap_or(input.as_ref());
+    Path::new(name).with_extension(extension)
+}
+
+/// Compiles the C source `input` to assembly in `output`, or on stdout if it
+/// is `-`, or as far as `emit` says. Returns whether it compiled; the
+/// diagnostics are written to `log`.
+fn compile(
+    input: &str,
+    output: &Path,
+    emit: Emit,
+    options: &Options,
+    log: &mut String,
+) -> Result<bool> {
+    let (file, text) = read_input(input)?;
+    let result = match emit {
+        Emit::Tokens | Emit::Ast => dump(file, &text, emit, options),
+        _ => options
+            .compiler()
+            .compile(file, &text)
+            .map(|output| (output.assembly, output.diagnostics, output.sources)),
+    };
+    let dest = match result {
+        Ok((dest, warnings, sources)) => {
+            log.push_str(&render(&warnings, &sources, options.json));
+            dest
+        }
+        Err(CompileFailure::Diagnostics {
+            diagnostics,
+            sources,
+        }) => {
+            log.push_str(&render(&diagnostics, &sources, options.json));
+            return Ok(false);
+        }
+        Err(error) => return Err(error.into()),
+    };
+
+    if output == Path::new("-") {
+        print!("{}", dest);
+    } else {
+        fs::write(output, dest).with_context(|| format!("cannot write '{}'", output.display()))?;
+    }
+    Ok(true)
+}
+
+/// The name diagnostics give `input`, and its contents.
+fn read_input(input: &str) -> Result<(&str, String)> {
+    if input == "-" {
+        let mut text = String::new();
+        io::stdin()
+            .read_to_string(&mut text)
+            .context("cannot read stdin")?;
+        Ok(("<stdin>", text))
+    } else {
+        let text = fs::read_to_string(input).with_context(|| format!("cannot read '{}'", input))?;
+        Ok((input, text))
+    }
+}
+
+/// Runs the stages of the compiler up to the one `emit` stops at, and
+/// returns its output as text along with the warnings.
+fn dump(
+    file: &str,
+    text: &str,
+    emit: Emit,
+    options: &Options,
+) -> Result<(String, Diagnostics, SourceMap), CompileFailure> {
+    let compiler = options.compiler();
+    let lexed = compiler.lex(file, text)?;
+    if emit == Emit::Tokens {
+        let tokens = dump_tokens(lexed.tokens.tokens());
+        return Ok((tokens, Diagnostics::default(), lexed.sources));
+    }
+    let checked = compiler.check(lexed)?;
+    Ok((
+        dump_ast(&checked.program),
+        checked.diagnostics,
+        checked.sources,
+    ))
+}
+
+/// Runs the assembler or linker. Returns whether it succeeded; what it
+/// prints is written to `log`.
+fn run_tool(command: &mut Command, log: &mut String) -> Result<bool> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let output = command
+        .output()
+        .with_context(|| format!("cannot run '{}'", program))?;
+    log.push_str(&String::from_utf8_lossy(&output.stdout));
+    log.push_str(&String::from_utf8_lossy(&output.stderr));
+    if !output.status.success() {
+        log.push_str(&format!(
+            "ccompiler: error: '{}' failed with {}\n",
+            program, output.status
+        ));
+    }
+    Ok(output.status.success())
+}
+
+fn render(diagnostics: &Diagnostics, sources: &SourceMap, json: bool) -> String {
+    if json {
+        diagnostics.to_json(sources)
+    } else {
+        diagnostics.render(sources)
+    }
 }
@@ -0,0 +1,319 @@
+use crate::token::{Span, TokenKind};
+use crate::types::Type;
+use std::fmt::{self, Display};
+
+/// A function definition, or a statement outside of any function. Those
+/// statements make up the body of an implicit `main`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Item {
+    Function(FunctionDef),
+    Stmt(Stmt),
+}
+
+/// `type name(params) { body }`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionDef {
+    pub name: String,
+    /// The type of the function itself, e.g. `int (int, char *)`.
+    pub ty: Type,
+    pub params: Vec<Decl>,
+    pub body: Vec<Stmt>,
+    pub span: Span,
+}
+
+/// The declaration of one variable or function, e.g. `int x = 1;`, `int
+/// f(int);` or a parameter.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decl {
+    pub name: String,
+    pub ty: Type,
+    /// The variable is at `rbp - offset` once `sema` has resolved it.
+    /// Functions have no slot and keep 0.
+    pub offset: u32,
+    pub init: Option<Expr>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StmtKind {
+    Expr(Expr),
+    Decl(Decl),
+    /// `{ stmt* }`
+    Block(Vec<Stmt>),
+    If {
+        cond: Expr,
+        then: Box<Stmt>,
+        otherwise: Option<Box<Stmt>>,
+    },
+    Return(Option<Expr>),
+    Asm(InlineAsm),
+}
+
+/// `asm("template" : outputs : inputs : clobbers)`
+#[derive(Debug, Clone, PartialEq)]
+pub struct InlineAsm {
+    pub template: String,
+    /// The output constraints followed by the input constraints.
+    pub constraints: Vec<String>,
+    /// The expression of each constraint, in the same order.
+    pub operands: Vec<Expr>,
+    pub outputs: usize,
+    pub clobbers: Vec<String>,
+    /// Whether there is a `:` at all. The template of a basic `asm` is
+    /// copied as it is, without looking at `%`.
+    pub extended: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+    /// Filled in by `types::add_type`, or by the parser for casts.
+    pub ty: Option<Type>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Num(u32),
+    /// A string literal, adjacent ones joined. Its value is the address of
+    /// its first character, in read-only memory.
+    Str(String),
+    /// A variable or function name, at `rbp - offset` for a variable once
+    /// `sema` has resolved it.
+    Var {
+        name: String,
+        offset: u32,
+    },
+    Binary {
+        op: BinaryOp,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    Assign {
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    /// `*expr`
+    Deref(Box<Expr>),
+    /// `&expr`
+    Addr(Box<Expr>),
+    /// A conversion to the type of the expression, written in the source or
+    /// added by `types::add_type`.
+    Cast(Box<Expr>),
+    Call {
+        callee: Box<Expr>,
+        args: Vec<Expr>,
+    },
+}
+
+/// `a > b` and `a >= b` are parsed as `b < a` and `b <= a`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Equal,
+    NEqual,
+    Less,
+    LessOrEqual,
+}
+
+impl BinaryOp {
+    pub fn spelling(&self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Equal => "==",
+            BinaryOp::NEqual => "!=",
+            BinaryOp::Less => "<",
+            BinaryOp::LessOrEqual => "<=",
+        }
+    }
+}
+
+impl Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "binary {}", self.spelling())
+    }
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Self {
+            kind,
+            span,
+            ty: None,
+        }
+    }
+
+    pub fn num(val: u32, span: Span) -> Self {
+        Self::new(ExprKind::Num(val), span)
+    }
+
+    /// A name, which `sema` resolves later.
+    pub fn var(name: String, span: Span) -> Self {
+        Self::new(ExprKind::Var { name, offset: 0 }, span)
+    }
+
+    pub fn binary(op: BinaryOp, lhs: Expr, rhs: Expr) -> Self {
+        let span = lhs.span.to(&rhs.span);
+        Self::new(
+            ExprKind::Binary {
+                op,
+                lhs: Box::new(lhs),
+                rhs: Box::new(rhs),
+            },
+            span,
+        )
+    }
+
+    /// Moves the expression out, leaving a `0` in its place.
+    pub fn take(&mut self) -> Expr {
+        let span = self.span.clone();
+        std::mem::replace(self, Expr::num(0, span))
+    }
+
+    /// Wraps the expression in another one of type `ty` made from it, e.g.
+    /// a cast.
+    pub fn wrap(&mut self, make: impl FnOnce(Box<Expr>) -> ExprKind, ty: Type) {
+        let span = self.span.clone();
+        let inner = self.take();
+        *self = Expr {
+            kind: make(Box::new(inner)),
+            span,
+            ty: Some(ty),
+        };
+    }
+}
+
+impl Stmt {
+    pub fn new(kind: StmtKind, span: Span) -> Self {
+        Self { kind, span }
+    }
+}
+
+/// The program as an indented tree, one node per line with its type if it
+/// has one, as shown by `--emit=ast`.
+pub fn dump_ast(program: &[Item]) -> String {
+    let mut output = String::new();
+    for item in program {
+        match item {
+            Item::Function(function) => {
+                line(&mut output, 0, &format!("Function {}", function.name));
+                output.push_str(&format!(": {}\n", function.ty));
+                for param in &function.params {
+                    dump_decl(param, 1, &mut output);
+                }
+                for stmt in &function.body {
+                    dump_stmt(stmt, 1, &mut output);
+                }
+            }
+            Item::Stmt(stmt) => dump_stmt(stmt, 0, &mut output),
+        }
+    }
+    output
+}
+
+fn line(output: &mut String, depth: usize, label: &str) {
+    output.push_str(&"  ".repeat(depth));
+    output.push_str(label);
+}
+
+fn dump_decl(decl: &Decl, depth: usize, output: &mut String) {
+    // Functions, and names not resolved yet, have no slot.
+    match decl.offset {
+        0 => line(output, depth, &format!("Decl {}", decl.name)),
+        offset => line(
+            output,
+            depth,
+            &format!("Decl {} [rbp - {}]", decl.name, offset),
+        ),
+    }
+    output.push_str(&format!(": {}\n", decl.ty));
+    if let Some(init) = &decl.init {
+        dump_expr(init, depth + 1, output);
+    }
+}
+
+fn dump_stmt(stmt: &Stmt, depth: usize, output: &mut String) {
+    match &stmt.kind {
+        StmtKind::Expr(expr) => dump_expr(expr, depth, output),
+        StmtKind::Decl(decl) => dump_decl(decl, depth, output),
+        StmtKind::Block(stmts) => {
+            line(output, depth, "Block\n");
+            for stmt in stmts {
+                dump_stmt(stmt, depth + 1, output);
+            }
+        }
+        StmtKind::If {
+            cond,
+            then,
+            otherwise,
+        } => {
+            line(output, depth, "If\n");
+            dump_expr(cond, depth + 1, output);
+            dump_stmt(then, depth + 1, output);
+            if let Some(otherwise) = otherwise {
+                line(output, depth, "Else\n");
+                dump_stmt(otherwise, depth + 1, output);
+            }
+        }
+        StmtKind::Return(value) => {
+            line(output, depth, "Return\n");
+            if let Some(value) = value {
+                dump_expr(value, depth + 1, output);
+            }
+        }
+        StmtKind::Asm(asm) => {
+            line(output, depth, &format!("InlineAsm {:?}\n", asm.template));
+            for operand in &asm.operands {
+                dump_expr(operand, depth + 1, output);
+            }
+        }
+    }
+}
+
+fn dump_expr(expr: &Expr, depth: usize, output: &mut String) {
+    let label = match &expr.kind {
+        ExprKind::Num(n) => format!("Num {}", n),
+        ExprKind::Str(text) => format!("Str {}", TokenKind::Str(text.clone()).spelling()),
+        ExprKind::Var { name, offset: 0 } => format!("Var {}", name),
+        ExprKind::Var { name, offset } => format!("Var {} [rbp - {}]", name, offset),
+        ExprKind::Binary { op, .. } => format!("Binary {}", op.spelling()),
+        ExprKind::Assign { .. } => "Assign".to_string(),
+        ExprKind::Deref(_) => "Deref".to_string(),
+        ExprKind::Addr(_) => "Addr".to_string(),
+        ExprKind::Cast(_) => "Cast".to_string(),
+        ExprKind::Call { .. } => "Call".to_string(),
+    };
+    line(output, depth, &label);
+    if let Some(ty) = &expr.ty {
+        output.push_str(&format!(": {}", ty));
+    }
+    output.push('\n');
+    match &expr.kind {
+        ExprKind::Num(_) | ExprKind::Str(_) | ExprKind::Var { .. } => {}
+        ExprKind::Binary { lhs, rhs, .. } | ExprKind::Assign { lhs, rhs } => {
+            dump_expr(lhs, depth + 1, output);
+            dump_expr(rhs, depth + 1, output);
+        }
+        ExprKind::Deref(operand) | ExprKind::Addr(operand) | ExprKind::Cast(operand) => {
+            dump_expr(operand, depth + 1, output);
+        }
+        ExprKind::Call { callee, args } => {
+            dump_expr(callee, depth + 1, output);
+            for arg in args {
+                dump_expr(arg, depth + 1, output);
+            }
+        }
+    }
+}
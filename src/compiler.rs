@@ -0,0 +1,414 @@
+use crate::ast::{Expr, ExprKind, Item, Stmt, StmtKind};
+use crate::diagnostic::{Diagnostic, Diagnostics, SourceMap};
+use crate::gen::{check, gen_program, lex, parse};
+use crate::preprocess::Preprocessor;
+use crate::token::{split_lines, TokenStream, MAX_ERRORS};
+use crate::types::{const_value, Type};
+use crate::visit::{walk_expr_mut, VisitorMut};
+use crate::warning::WarningOptions;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// The machine code is generated for. Only one is supported so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Target {
+    /// x86-64 with the System V calling convention, as on Linux.
+    #[default]
+    X86_64Linux,
+}
+
+/// Compiles C source to assembly with a set of options, any number of times.
+///
+/// ```
+/// use ccompiler::compiler::Compiler;
+///
+/// let output = Compiler::new()
+///     .with_define("N", "42")
+///     .compile("main.c", "int main() { return N; }")
+///     .unwrap();
+/// assert!(output.assembly.contains("main:"));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Compiler {
+    include_paths: Vec<PathBuf>,
+    defines: Vec<(String, String)>,
+    warnings: WarningOptions,
+    max_errors: usize,
+    target: Target,
+    opt_level: u8,
+    timestamp: Option<i64>,
+}
+
+/// What a successful compilation produced.
+#[derive(Debug, Clone)]
+pub struct CompileOutput {
+    pub assembly: String,
+    /// The enabled warnings.
+    pub diagnostics: Diagnostics,
+    /// The functions the program declares or defines, in order.
+    pub symbols: Vec<Symbol>,
+    /// Every file read, for rendering the diagnostics.
+    pub sources: SourceMap,
+}
+
+/// A source after preprocessing, as `Compiler::lex` gives it.
+#[derive(Debug)]
+pub struct Lexed {
+    pub tokens: TokenStream,
+    /// Every file read, for rendering diagnostics.
+    pub sources: SourceMap,
+}
+
+/// A program that has been parsed and checked, and had its constants folded
+/// from `-O1` on, as `Compiler::check` gives it. This is the tree the
+/// assembly is generated from.
+#[derive(Debug, Clone)]
+pub struct Checked {
+    pub program: Vec<Item>,
+    /// The enabled warnings.
+    pub diagnostics: Diagnostics,
+    pub sources: SourceMap,
+}
+
+/// A function of the program. Variables all live on the stack of their
+/// function, so functions are the only symbols the assembly has.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub ty: Type,
+    /// Whether the program defines the function, rather than only declaring
+    /// it. Defined functions are global in the assembly.
+    pub defined: bool,
+}
+
+#[derive(Debug, Error)]
+pub enum CompileFailure {
+    #[error("cannot read the source: {0}")]
+    Io(#[from] io::Error),
+    /// The program has errors, or warnings with `-Werror`.
+    #[error("{diagnostics}")]
+    Diagnostics {
+        diagnostics: Diagnostics,
+        sources: SourceMap,
+    },
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self {
+            include_paths: Vec::new(),
+            defines: Vec::new(),
+            warnings: WarningOptions::new(),
+            max_errors: MAX_ERRORS,
+            target: Target::default(),
+            opt_level: 0,
+            timestamp: None,
+        }
+    }
+}
+
+impl Compiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a directory searched for included files.
+    pub fn with_include_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.include_paths.push(path.into());
+        self
+    }
+
+    /// Defines an object-like macro, as `#define name body` would.
+    pub fn with_define(mut self, name: &str, body: &str) -> Self {
+        self.defines.push((name.to_string(), body.to_string()));
+        self
+    }
+
+    /// Which warnings are reported, and whether they are errors.
+    pub fn with_warnings(mut self, warnings: WarningOptions) -> Self {
+        self.warnings = warnings;
+        self
+    }
+
+    /// How many syntax errors are reported before giving up, or with 0, all
+    /// of them.
+    pub fn with_max_errors(mut self, max_errors: usize) -> Self {
+        self.max_errors = max_errors;
+        self
+    }
+
+    pub fn with_target(mut self, target: Target) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// 0 generates code for every expression as written. From 1 on,
+    /// constant expressions are computed at compile time.
+    pub fn with_opt_level(mut self, opt_level: u8) -> Self {
+        self.opt_level = opt_level;
+        self
+    }
+
+    /// The time `__DATE__` expands to, in seconds since the Unix epoch. See
+    /// `Preprocessor::set_timestamp`.
+    pub fn with_timestamp(mut self, seconds: i64) -> Self {
+        self.timestamp = Some(seconds);
+        self
+    }
+
+    pub fn target(&self) -> Target {
+        self.target
+    }
+
+    /// Compiles `source`, labelled `file` in diagnostics and used to find
+    /// files it includes with quotes. This is `lex`, `check` and `generate`
+    /// in turn.
+    pub fn compile(&self, file: &str, source: &str) -> Result<CompileOutput, CompileFailure> {
+        self.generate(self.check(self.lex(file, source)?)?)
+    }
+
+    /// The first stage of `compile`: preprocesses `source` and splits it
+    /// into tokens.
+    pub fn lex(&self, file: &str, source: &str) -> Result<Lexed, CompileFailure> {
+        let mut preprocessor = Preprocessor::new();
+        for path in &self.include_paths {
+            preprocessor.add_include_path(path.clone());
+        }
+        if let Some(seconds) = self.timestamp {
+            preprocessor.set_timestamp(seconds);
+        }
+        let failure = |diagnostics, preprocessor: &Preprocessor| CompileFailure::Diagnostics {
+            diagnostics,
+            sources: preprocessor.sources().clone(),
+        };
+        for (name, body) in &self.defines {
+            if let Err(error) = preprocessor.define(name, body) {
+                return Err(failure(error.into(), &preprocessor));
+            }
+        }
+
+        match lex(&mut preprocessor, file, split_lines(source)) {
+            Ok(tokens) => Ok(Lexed {
+                tokens,
+                sources: preprocessor.sources().clone(),
+            }),
+            Err(error) => Err(failure(error.into(), &preprocessor)),
+        }
+    }
+
+    /// The second stage of `compile`: parses the tokens, resolves names,
+    /// adds types and looks for warnings. With `-Werror` any warning fails.
+    pub fn check(&self, lexed: Lexed) -> Result<Checked, CompileFailure> {
+        let Lexed {
+            mut tokens,
+            sources,
+        } = lexed;
+        let failure = |diagnostics| CompileFailure::Diagnostics {
+            diagnostics,
+            sources: sources.clone(),
+        };
+        let mut program = parse(&mut tokens, self.max_errors)
+            .map_err(|errors| failure(errors.iter().map(Diagnostic::from).collect()))?;
+        let warnings = check(&mut program).map_err(|error| failure(error.into()))?;
+        if self.opt_level > 0 {
+            ConstantFolder.visit_program_mut(&mut program);
+        }
+
+        let warnings = self.warnings.filter(warnings);
+        let diagnostics: Diagnostics = warnings
+            .iter()
+            .map(|warning| self.warnings.diagnostic(warning))
+            .collect();
+        if self.warnings.werror && !diagnostics.is_empty() {
+            return Err(failure(diagnostics));
+        }
+
+        Ok(Checked {
+            program,
+            diagnostics,
+            sources,
+        })
+    }
+
+    /// The last stage of `compile`: generates the assembly.
+    pub fn generate(&self, checked: Checked) -> Result<CompileOutput, CompileFailure> {
+        let Checked {
+            program,
+            diagnostics,
+            sources,
+        } = checked;
+        match gen_program(&program) {
+            Ok(assembly) => Ok(CompileOutput {
+                assembly,
+                diagnostics,
+                symbols: symbols(&program),
+                sources,
+            }),
+            Err(error) => Err(CompileFailure::Diagnostics {
+                diagnostics: error.into(),
+                sources,
+            }),
+        }
+    }
+
+    /// Compiles the source read from `reader`, as `compile` does.
+    pub fn compile_reader(
+        &self,
+        file: &str,
+        mut reader: impl Read,
+    ) -> Result<CompileOutput, CompileFailure> {
+        let mut source = String::new();
+        reader.read_to_string(&mut source)?;
+        self.compile(file, &source)
+    }
+}
+
+/// Replaces constant `int` expressions with their value. Only values that
+/// fit in the immediate operand of `push` are folded.
+struct ConstantFolder;
+
+impl VisitorMut for ConstantFolder {
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        if !matches!(expr.kind, ExprKind::Num(_)) && expr.ty == Some(Type::INT) {
+            if let Some(value) = const_value(expr).and_then(|value| u32::try_from(value).ok()) {
+                if value <= i32::MAX as u32 {
+                    expr.kind = ExprKind::Num(value);
+                    return;
+                }
+            }
+        }
+        walk_expr_mut(self, expr);
+    }
+}
+
+/// The functions of a checked program. Statements outside of any function
+/// define `main`.
+fn symbols(program: &[Item]) -> Vec<Symbol> {
+    let mut symbols: Vec<Symbol> = Vec::new();
+    let mut add = |name: &str, ty: &Type, defined: bool| match symbols
+        .iter_mut()
+        .find(|symbol| symbol.name == name)
+    {
+        Some(symbol) => symbol.defined |= defined,
+        None => symbols.push(Symbol {
+            name: name.to_string(),
+            ty: ty.clone(),
+            defined,
+        }),
+    };
+    let mut has_main = false;
+    for item in program {
+        match item {
+            Item::Function(function) => add(&function.name, &function.ty, true),
+            Item::Stmt(Stmt {
+                kind: StmtKind::Decl(decl),
+                ..
+            }) if decl.ty.is_func() => add(&decl.name, &decl.ty, false),
+            Item::Stmt(_) => has_main = true,
+        }
+    }
+    if has_main {
+        add("main", &Type::func(Type::INT, None), true);
+    }
+    symbols
+}
+
+#[test]
+fn test_compiler() {
+    let compiler = Compiler::new();
+    let output = compiler
+        .compile("main.c", "int f(int x);\nint g() { return 1; }\nf(g());")
+        .unwrap();
+    assert!(output.diagnostics.is_empty());
+    let symbols: Vec<(&str, String, bool)> = output
+        .symbols
+        .iter()
+        .map(|symbol| (&*symbol.name, symbol.ty.to_string(), symbol.defined))
+        .collect();
+    assert_eq!(
+        symbols,
+        [
+            ("f", "int (int)".to_string(), false),
+            ("g", "int ()".to_string(), true),
+            ("main", "int ()".to_string(), true),
+        ]
+    );
+
+    let output = compiler
+        .compile_reader("main.c", "int a = 1; int b; a;".as_bytes())
+        .unwrap();
+    assert_eq!(output.diagnostics.len(), 1);
+    assert_eq!(
+        output.diagnostics.render(&output.sources),
+        "warning[unused-variable]: unused variable 'b'\n  --> main.c:1:12\n  |\n\
+         1 | int a = 1; int b; a;\n  |            ^~~~~~\n"
+    );
+    let output = compiler
+        .compile("main.c", "int a = 0;\nif (a = 1) return 2;\nreturn 3;\n4;")
+        .unwrap();
+    let rendered = output.diagnostics.render(&output.sources);
+    assert!(rendered.contains("parentheses]: suggest parentheses around assignment used as truth value\n  --> main.c:2:5\n"));
+    assert!(rendered.contains(
+        "warning[unreachable-code]: statement will never be executed\n  --> main.c:4:1\n"
+    ));
+
+    let Err(CompileFailure::Diagnostics {
+        diagnostics,
+        sources,
+    }) = compiler.compile("main.c", "1 +; 2 +;")
+    else {
+        panic!("expected errors");
+    };
+    assert_eq!(diagnostics.len(), 2);
+    assert!(diagnostics.render(&sources).contains("--> main.c:1:4"));
+
+    let mut warnings = WarningOptions::new();
+    warnings.parse_flag("-Werror").unwrap();
+    let result = compiler
+        .clone()
+        .with_warnings(warnings)
+        .compile("main.c", "int b;");
+    assert!(matches!(result, Err(CompileFailure::Diagnostics { .. })));
+
+    let result = compiler.compile_reader("main.c", io::repeat(0xff).take(4));
+    assert!(matches!(result, Err(CompileFailure::Io(_))));
+
+    let folded = |opt_level| {
+        let output = Compiler::new()
+            .with_define("N", "6")
+            .with_opt_level(opt_level)
+            .compile("", "(N + 1) * 6;")
+            .unwrap();
+        output.assembly.contains("push 42")
+    };
+    assert!(!folded(0));
+    assert!(folded(1));
+}
+
+#[test]
+fn test_threads() {
+    // There is no global state, so each compilation is the same on any
+    // thread as on its own.
+    let compiler = Compiler::new();
+    let sources = [
+        "int f(int a) { if (a) return 1; return 2; } f(0);",
+        "int x = 1; if (x) x = 2; x;",
+        "int g() { return 3; } g();",
+    ];
+    let alone: Vec<String> = sources
+        .iter()
+        .map(|source| compiler.compile("", source).unwrap().assembly)
+        .collect();
+    let together: Vec<String> = std::thread::scope(|scope| {
+        let handles: Vec<_> = sources
+            .iter()
+            .map(|source| scope.spawn(|| compiler.compile("", source).unwrap().assembly))
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect()
+    });
+    assert_eq!(alone, together);
+}
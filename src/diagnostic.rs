@@ -0,0 +1,519 @@
+use crate::result::CompileError;
+use crate::token::{Span, TokenKind};
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Error,
+    Warning,
+    Note,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Level::Error => write!(f, "error"),
+            Level::Warning => write!(f, "warning"),
+            Level::Note => write!(f, "note"),
+        }
+    }
+}
+
+/// A message about the source, shown with the line it is about and the
+/// offending bytes underlined.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub level: Level,
+    /// Identifies the kind of problem, such as the category of a warning.
+    pub code: Option<String>,
+    pub message: String,
+    pub span: Option<Span>,
+    /// Shown after the message, such as where an unclosed bracket was opened.
+    pub notes: Vec<Diagnostic>,
+    /// Edits that would fix the problem.
+    pub fixes: Vec<Fix>,
+}
+
+/// Replacing the bytes of `span` with `replacement`. An empty span inserts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fix {
+    pub message: String,
+    pub span: Span,
+    pub replacement: String,
+}
+
+impl Diagnostic {
+    pub fn new(level: Level, message: impl Into<String>, span: Option<Span>) -> Self {
+        Self {
+            level,
+            code: None,
+            message: message.into(),
+            span,
+            notes: Vec::new(),
+            fixes: Vec::new(),
+        }
+    }
+
+    pub fn with_code(mut self, code: impl Into<String>) -> Self {
+        self.code = Some(code.into());
+        self
+    }
+
+    /// Suggests inserting `text` at the start of `span`.
+    pub fn with_insertion(mut self, text: &str, span: &Span) -> Self {
+        self.fixes.push(Fix {
+            message: format!("insert '{}'", text),
+            span: Span {
+                end: span.start,
+                ..span.clone()
+            },
+            replacement: text.to_string(),
+        });
+        self
+    }
+
+    pub fn with_note(mut self, message: impl Into<String>, span: Option<Span>) -> Self {
+        self.notes.push(Diagnostic::new(Level::Note, message, span));
+        self
+    }
+
+    /// Formats the diagnostic like clang and rustc do:
+    ///
+    /// ```text
+    /// error[C0001]: expect Semicolon: ;, found Return
+    ///   --> main.c:2:1
+    ///   |
+    /// 2 | return 1;
+    ///   | ^~~~~~
+    /// ```
+    ///
+    /// Lines and columns count from 1. Without the source of the file only
+    /// the location is shown.
+    pub fn render(&self, sources: &SourceMap) -> String {
+        let mut output = String::new();
+        self.render_into(sources, &mut output)
+            .expect("writing to a String cannot fail");
+        output
+    }
+
+    /// The diagnostic as one line of JSON:
+    ///
+    /// ```text
+    /// {"severity":"error","code":null,"message":"...","primary_span":{...},
+    ///  "secondary_spans":[{"label":"...",...}],"notes":["..."],
+    ///  "fixes":[{"message":"...","span":{...},"replacement":"..."}]}
+    /// ```
+    ///
+    /// A span has `file`, `byte_start` and `byte_end`, and `line`, `column`,
+    /// `end_line` and `end_column` counting from 1, which are `null` when the
+    /// source of the file is not known. Notes without a span go in `notes`.
+    pub fn to_json(&self, sources: &SourceMap) -> String {
+        let span = |span: &Option<Span>| match span {
+            Some(span) => span_json(span, sources, None),
+            None => "null".to_string(),
+        };
+        let secondary: Vec<String> = self
+            .notes
+            .iter()
+            .filter_map(|note| {
+                let label = json_string(&note.message);
+                note.span
+                    .as_ref()
+                    .map(|span| span_json(span, sources, Some(&label)))
+            })
+            .collect();
+        let notes: Vec<String> = self
+            .notes
+            .iter()
+            .filter(|note| note.span.is_none())
+            .map(|note| json_string(&note.message))
+            .collect();
+        let fixes: Vec<String> = self
+            .fixes
+            .iter()
+            .map(|fix| {
+                format!(
+                    "{{\"message\":{},\"span\":{},\"replacement\":{}}}",
+                    json_string(&fix.message),
+                    span_json(&fix.span, sources, None),
+                    json_string(&fix.replacement)
+                )
+            })
+            .collect();
+        format!(
+            "{{\"severity\":{},\"code\":{},\"message\":{},\"primary_span\":{},\
+             \"secondary_spans\":[{}],\"notes\":[{}],\"fixes\":[{}]}}",
+            json_string(&self.level.to_string()),
+            self.code.as_deref().map_or("null".to_string(), json_string),
+            json_string(&self.message),
+            span(&self.span),
+            secondary.join(","),
+            notes.join(","),
+            fixes.join(",")
+        )
+    }
+
+    fn render_into(&self, sources: &SourceMap, output: &mut String) -> fmt::Result {
+        match &self.code {
+            Some(code) => writeln!(output, "{}[{}]: {}", self.level, code, self.message)?,
+            None => writeln!(output, "{}: {}", self.level, self.message)?,
+        }
+        if let Some(span) = &self.span {
+            match sources.lookup(span) {
+                Some((row, line, col)) => {
+                    let name = if span.file.is_empty() {
+                        String::new()
+                    } else {
+                        format!("{}:", span.file)
+                    };
+                    let number = (row + 1).to_string();
+                    let gutter = " ".repeat(number.len());
+                    let col_chars = line[..col].chars().count();
+                    writeln!(
+                        output,
+                        "{} --> {}{}:{}",
+                        gutter,
+                        name,
+                        row + 1,
+                        col_chars + 1
+                    )?;
+                    writeln!(output, "{} |", gutter)?;
+                    writeln!(output, "{} | {}", number, line)?;
+                    writeln!(output, "{} | {}", gutter, underline(line, col, span))?;
+                }
+                None if !span.file.is_empty() => writeln!(output, "  --> {}", span.file)?,
+                None => {}
+            }
+        }
+        for note in &self.notes {
+            note.render_into(sources, output)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<&CompileError> for Diagnostic {
+    fn from(error: &CompileError) -> Self {
+        let diagnostic =
+            Diagnostic::new(Level::Error, error.to_string(), error.span()).with_code(error.code());
+        let Some(span) = error.span() else {
+            return diagnostic;
+        };
+        match error {
+            CompileError::Unclosed { open, error } => {
+                let diagnostic = match Diagnostic::from(&**error).fixes.pop() {
+                    Some(fix) => Diagnostic {
+                        fixes: vec![fix],
+                        ..diagnostic
+                    },
+                    None => diagnostic,
+                };
+                diagnostic.with_note(
+                    format!("to match this '{}'", open.kind().spelling()),
+                    Some(open.span().clone()),
+                )
+            }
+            // A missing token that is always spelled the same can be put in.
+            CompileError::Expected { expect, .. } | CompileError::Unexpected { expect, .. }
+                if is_fixed_spelling(expect) =>
+            {
+                diagnostic.with_insertion(&expect.spelling(), &span)
+            }
+            _ => diagnostic,
+        }
+    }
+}
+
+fn is_fixed_spelling(kind: &TokenKind) -> bool {
+    !matches!(
+        kind,
+        TokenKind::Num(_) | TokenKind::Str(_) | TokenKind::Ident(_)
+    ) && !kind.spelling().is_empty()
+}
+
+impl From<&anyhow::Error> for Diagnostic {
+    fn from(error: &anyhow::Error) -> Self {
+        match error.downcast_ref::<CompileError>() {
+            Some(error) => error.into(),
+            None => Diagnostic::new(Level::Error, error.to_string(), None),
+        }
+    }
+}
+
+/// Everything found wrong with a program, in the order it was found.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Diagnostics(pub Vec<Diagnostic>);
+
+impl Diagnostics {
+    pub fn iter(&self) -> impl Iterator<Item = &Diagnostic> {
+        self.0.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn render(&self, sources: &SourceMap) -> String {
+        self.iter()
+            .map(|diagnostic| diagnostic.render(sources))
+            .collect()
+    }
+
+    /// One line of JSON for each diagnostic.
+    pub fn to_json(&self, sources: &SourceMap) -> String {
+        self.iter()
+            .map(|diagnostic| diagnostic.to_json(sources) + "\n")
+            .collect()
+    }
+}
+
+/// Each message on a line of its own, without the source.
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, diagnostic) in self.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}: {}", diagnostic.level, diagnostic.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostics {}
+
+impl From<anyhow::Error> for Diagnostics {
+    fn from(error: anyhow::Error) -> Self {
+        Diagnostics(vec![Diagnostic::from(&error)])
+    }
+}
+
+impl FromIterator<Diagnostic> for Diagnostics {
+    fn from_iter<I: IntoIterator<Item = Diagnostic>>(iter: I) -> Self {
+        Diagnostics(iter.into_iter().collect())
+    }
+}
+
+/// A span as a JSON object, with a `label` first if there is one.
+fn span_json(span: &Span, sources: &SourceMap, label: Option<&str>) -> String {
+    let position = |offset| {
+        sources.position(&span.file, offset).map_or(
+            ("null".to_string(), "null".to_string()),
+            |(line, column)| (line.to_string(), column.to_string()),
+        )
+    };
+    let (line, column) = position(span.start);
+    let (end_line, end_column) = position(span.end);
+    format!(
+        "{{{}\"file\":{},\"byte_start\":{},\"byte_end\":{},\"line\":{},\"column\":{},\
+         \"end_line\":{},\"end_column\":{}}}",
+        label.map_or(String::new(), |label| format!("\"label\":{},", label)),
+        json_string(&span.file),
+        span.start,
+        span.end,
+        line,
+        column,
+        end_line,
+        end_column
+    )
+}
+
+/// `text` as a JSON string literal.
+fn json_string(text: &str) -> String {
+    let mut result = String::with_capacity(text.len() + 2);
+    result.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+/// A caret under the first character of `span` and tildes under the rest.
+/// Tabs before it are kept so that it lines up however tabs are shown.
+fn underline(line: &str, col: usize, span: &Span) -> String {
+    let mut result: String = line[..col]
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let end = (col + span.end.saturating_sub(span.start)).min(line.len());
+    let width = line.get(col..end).map_or(0, |text| text.chars().count());
+    result.push('^');
+    result.push_str(&"~".repeat(width.saturating_sub(1)));
+    result
+}
+
+/// The contents of every file read during a compilation, for showing the
+/// source a diagnostic is about.
+#[derive(Debug, Default, Clone)]
+pub struct SourceMap {
+    files: HashMap<String, String>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, file: &str, text: String) {
+        self.files.insert(file.to_string(), text);
+    }
+
+    /// The line and column of byte `offset` of `file`, counting from 1.
+    pub fn position(&self, file: &str, offset: usize) -> Option<(usize, usize)> {
+        let span = Span {
+            file: file.into(),
+            start: offset,
+            end: offset,
+        };
+        let (row, line, col) = self.lookup(&span)?;
+        Some((row + 1, line[..col].chars().count() + 1))
+    }
+
+    /// The line `span` starts on, with its 0-based number and the byte
+    /// offset of the span in it.
+    pub fn lookup(&self, span: &Span) -> Option<(usize, &str, usize)> {
+        let text = self.files.get(&*span.file)?;
+        if span.start > text.len() {
+            return None;
+        }
+        let row = text[..span.start].matches('\n').count();
+        let start = text[..span.start].rfind('\n').map_or(0, |i| i + 1);
+        let line = text[start..].split('\n').next().unwrap_or_default();
+        let col = span.start - start;
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        line.is_char_boundary(col.min(line.len()))
+            .then_some((row, line, col.min(line.len())))
+    }
+}
+
+#[test]
+fn test_render() {
+    use crate::token::tokenize;
+
+    let mut sources = SourceMap::new();
+    let source = "int x;\nx = (1 +\n\t2;";
+    sources.add("", source.to_string());
+    let mut tokenstream = tokenize(source.split('\n').map(str::to_string).collect()).unwrap();
+    let error = crate::node::program(&mut tokenstream).unwrap_err();
+    assert_eq!(
+        Diagnostic::from(&error).render(&sources),
+        [
+            "error[C0003]: expect Operation: ), found Semicolon: ;",
+            "  --> 3:3",
+            "  |",
+            "3 | \t2;",
+            "  | \t ^",
+            "note: to match this '('",
+            "  --> 2:5",
+            "  |",
+            "2 | x = (1 +",
+            "  |     ^",
+            "",
+        ]
+        .join("\n")
+    );
+
+    let span = Span {
+        file: "a.c".into(),
+        start: 4,
+        end: 8,
+    };
+    let diagnostic = Diagnostic::new(Level::Warning, "unused", Some(span));
+    sources.add("a.c", "int main() {\n  return 0;\n}\n".to_string());
+    assert_eq!(
+        diagnostic.render(&sources),
+        "warning: unused\n  --> a.c:1:5\n  |\n1 | int main() {\n  |     ^~~~\n"
+    );
+
+    let diagnostic = Diagnostic::new(Level::Error, "oops", None);
+    assert_eq!(diagnostic.render(&SourceMap::new()), "error: oops\n");
+}
+
+#[test]
+fn test_json() {
+    use crate::token::{split_lines, tokenize};
+
+    let mut sources = SourceMap::new();
+    let source = "f(1\n";
+    sources.add("", source.to_string());
+    let mut tokenstream = tokenize(split_lines(source)).unwrap();
+    let error = crate::node::program(&mut tokenstream).unwrap_err();
+    let span = |line, column, end_column, start, end| {
+        format!(
+            r#""file":"","byte_start":{start},"byte_end":{end},"line":{line},"column":{column},"end_line":{line},"end_column":{end_column}"#
+        )
+    };
+    assert_eq!(
+        Diagnostic::from(&error).to_json(&sources),
+        format!(
+            concat!(
+                r#"{{"severity":"error","code":"C0003","message":"expect Operation: )","#,
+                r#""primary_span":{{{}}},"#,
+                r#""secondary_spans":[{{"label":"to match this '('",{}}}],"notes":[],"#,
+                r#""fixes":[{{"message":"insert ')'","span":{{{}}},"replacement":")"}}]}}"#
+            ),
+            span(1, 4, 4, 3, 3),
+            span(1, 2, 3, 1, 2),
+            span(1, 4, 4, 3, 3)
+        )
+    );
+
+    let diagnostic = Diagnostic::new(Level::Warning, "a \"quoted\"\tname\\", None)
+        .with_code("unused-value")
+        .with_note("in \u{1}here", None);
+    assert_eq!(
+        Diagnostics(vec![diagnostic]).to_json(&sources),
+        concat!(
+            r#"{"severity":"warning","code":"unused-value","message":"a \"quoted\"\tname\\","#,
+            r#""primary_span":null,"secondary_spans":[],"notes":["in \u0001here"],"fixes":[]}"#,
+            "\n"
+        )
+    );
+    // Errors found once the program has parsed point into it too, as do
+    // warnings.
+    use crate::gen::{compile_from_source, compile_with_warnings};
+    use crate::warning::WarningOptions;
+    let source = "int a;\nint a = 1; int b; a;\n";
+    sources.add("", source.to_string());
+    let error = compile_from_source(split_lines(source)).unwrap_err();
+    let error = error.downcast_ref::<CompileError>().unwrap();
+    assert_eq!(
+        Diagnostic::from(error).to_json(&sources),
+        format!(
+            concat!(
+                r#"{{"severity":"error","code":"C0005","message":"redefinition of 'a'","#,
+                r#""primary_span":{{{}}},"secondary_spans":[],"notes":[],"fixes":[]}}"#
+            ),
+            span(2, 1, 11, 7, 17)
+        )
+    );
+    let source = "int a = 1;\nint b; a;\n";
+    sources.add("", source.to_string());
+    let (_, warnings) = compile_with_warnings(split_lines(source)).unwrap();
+    assert_eq!(
+        WarningOptions::new()
+            .diagnostic(&warnings[0])
+            .to_json(&sources),
+        format!(
+            concat!(
+                r#"{{"severity":"warning","code":"unused-variable","message":"unused variable 'b'","#,
+                r#""primary_span":{{{}}},"secondary_spans":[],"notes":[],"fixes":[]}}"#
+            ),
+            span(2, 1, 7, 11, 17)
+        )
+    );
+}
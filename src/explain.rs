@@ -0,0 +1,345 @@
+/// A long-form explanation of every error code, with an example of code
+/// that causes it. Codes are listed in order and never removed.
+pub const EXPLANATIONS: [(&str, &str); 29] = [
+    (
+        "C0001",
+        "A token appeared where the grammar requires a different one.
+
+```c
+int a = 1
+return a;   // error: expect Semicolon: ;, found Return
+```
+
+The error points at the unexpected token. Often the expected token is
+missing just before it, as with the `;` above.",
+    ),
+    (
+        "C0002",
+        "The source ended while a construct was still incomplete.
+
+```c
+int a = 1   // error: expect Semicolon: ;
+```
+
+The error points just past the last token of the file.",
+    ),
+    (
+        "C0003",
+        "A closing bracket is missing. The note shows where the bracket that
+needs closing was opened.
+
+```c
+int x = (1 + 2;   // error: expect Operation: ), found Semicolon: ;
+```",
+    ),
+    (
+        "C0004",
+        "The type specifiers of a declaration do not make up a type.
+
+```c
+char int c;   // error: invalid combination of type specifiers
+```
+
+Each of `void`, `_Bool`, `char`, `short`, `int` and `long` may be used
+once, except that `long` may be repeated, along with one of `signed` and
+`unsigned` for the integer types.",
+    ),
+    (
+        "C0005",
+        "A name is declared twice where it may only be declared once. Each block
+may declare a variable of a given name once; an inner block may declare it
+again, hiding the outer one.
+
+```c
+int a;
+int a;   // error: redefinition of 'a'
+{ int a; }   // fine, a new variable
+```
+
+A function may be declared any number of times but defined only once. The
+parameters of a function share the outermost block of its body, and a
+variable in that block cannot have the name of a function. Statements outside
+of any function make up an implicit `main`, so a program that has them cannot
+define `main` as well.",
+    ),
+    (
+        "C0006",
+        "An expression that does not designate an object is used where one is
+required, such as on the left of `=` or as the operand of `&`.
+
+```c
+int f() { return 1; }
+f = 2;   // error: lvalue required as left operand of assignment
+```",
+    ),
+    (
+        "C0007",
+        "The operand of unary `*` is not a pointer.
+
+```c
+int a = 1;
+*a;   // error: cannot dereference a value of type 'int'
+```",
+    ),
+    (
+        "C0008",
+        "A variable is declared with type `void`, which has no values.
+
+```c
+void v;   // error: variable 'v' declared void
+```
+
+Use `void *` for a pointer to anything.",
+    ),
+    (
+        "C0009",
+        "Something that is not a function or a pointer to one is called.
+
+```c
+int a = 1;
+a(2);   // error: called object of type 'int' is not a function or function pointer
+```",
+    ),
+    (
+        "C0010",
+        "A function is called with a different number of arguments than its
+prototype has parameters.
+
+```c
+int f(int a) { return a; }
+f(1, 2);   // error: too many arguments to function of type 'int (int)'
+```
+
+Functions declared with empty parentheses, like `int f()`, accept any
+number of arguments.",
+    ),
+    (
+        "C0011",
+        "A call passes more arguments than fit in registers. Only six
+arguments are supported, as there is no support for passing them on the
+stack.
+
+```c
+f(1, 2, 3, 4, 5, 6, 7);   // error: functions with more than 6 arguments are not supported
+```",
+    ),
+    (
+        "C0012",
+        "An object qualified with `const` is assigned to.
+
+```c
+const int a = 1;
+a = 2;   // error: assignment of read-only variable 'a'
+```
+
+The qualifier applies to what it follows in the declarator: through
+`const int *p` the pointee cannot be changed, while with `int *const p`
+the pointer itself cannot.",
+    ),
+    (
+        "C0013",
+        "A binary operator is applied to operands it does not accept, such as
+adding two pointers.
+
+```c
+int a = 1;
+&a + &a;   // error: invalid operands to binary + ('int *' and 'int *')
+```",
+    ),
+    (
+        "C0014",
+        "An operand of an `asm` statement has a constraint that is not
+supported. Supported constraints are `r`, `a`, `b`, `c`, `d`, `S`, `D`,
+`i` and `n` for constant inputs, and a digit for an input sharing the
+register of an output. Outputs start with `=` or `+`.
+
+```c
+int x;
+asm(\"\" : \"m\"(x));   // error: invalid constraint 'm' in asm
+```",
+    ),
+    (
+        "C0015",
+        "A clobber of an `asm` statement is not the name of a general
+purpose register, `cc` or `memory`.
+
+```c
+asm(\"\" : : : \"xmm0\");   // error: unknown register name 'xmm0' in asm clobbers
+```",
+    ),
+    (
+        "C0016",
+        "The operands of an `asm` statement need more registers, or the same
+register more than once, than is possible.
+
+```c
+int x;
+asm(\"\" : \"=a\"(x), \"=a\"(x));   // error: impossible register constraints in asm
+```",
+    ),
+    (
+        "C0017",
+        "The template of an `asm` statement refers to an operand that does not
+exist, or uses a modifier that is not supported.
+
+```c
+int x;
+asm(\"mov %1, 1\" : \"=r\"(x));   // error: invalid operand reference '%1' in asm template
+```",
+    ),
+    (
+        "C0018",
+        "So many syntax errors were found that the compiler stopped looking
+for more. Fix the first ones, which often cause the rest, or raise the
+limit with `-fmax-errors=N`, or remove it with `-fmax-errors=0`.",
+    ),
+    (
+        "C0019",
+        "A preprocessing directive or a macro invocation is invalid, or a file
+could not be included. The message says what is wrong.
+
+```c
+#include \"missing.h\"   // error: 'missing.h' file not found
+#define ADD(a, b) a + b
+ADD(1);   // error: macro 'ADD' requires 2 arguments, but 1 given
+```",
+    ),
+    (
+        "C0020",
+        "The source contains something that is not a token, such as a
+character outside of a string that is not part of C, or a malformed
+number or string.
+
+```c
+int a = 1 @ 2;   // error: stray '@' in program
+int b = 12abc;   // error: invalid suffix 'abc' on integer constant
+```",
+    ),
+    (
+        "C0021",
+        "A block comment is not closed before the end of the file.
+
+```c
+int a = 1; /* the rest of the file is a comment
+```",
+    ),
+    (
+        "C0022",
+        "A function definition has no name.
+
+```c
+int (int a) { return a; }   // error: function definition without a name
+```",
+    ),
+    (
+        "C0023",
+        "A parameter of a function definition has no name. Only declarations
+may leave parameters unnamed.
+
+```c
+int f(int) { return 1; }   // error: parameter name omitted in function definition
+```",
+    ),
+    (
+        "C0024",
+        "The source ended where a statement is required, such as after the
+condition of an `if`.
+
+```c
+if (1)   // error: expected statement
+```",
+    ),
+    (
+        "C0025",
+        "The source contains no tokens at all, so there is nothing to compile.
+A program needs at least one statement or function.",
+    ),
+    (
+        "C0026",
+        "The compiler reached a state that it should never be in. This is a
+bug in the compiler rather than in the program; please report it along
+with the source that causes it.",
+    ),
+    (
+        "C0027",
+        "A function declared to return `void` returns a value.
+
+```c
+void f() { return 1; }   // error: 'return' with a value, in function returning void
+```
+
+Either drop the value, writing `return;`, or declare the function with the
+type of the value it returns.",
+    ),
+    (
+        "C0028",
+        "The result of an expression of type `void`, such as a call to a function
+returning `void`, is used as a value.
+
+```c
+void f() {}
+int x = f();   // error: void value not ignored as it ought to be
+```
+
+Such an expression can only be a statement of its own, or be cast to
+`void`.",
+    ),
+    (
+        "C0029",
+        "A function is declared again with a different return type or different
+parameters.
+
+```c
+int f(int);
+long f(int a, int b) { return a; }   // error: conflicting types for 'f'
+```
+
+A declaration that leaves the parameters out, as `int f();` does, agrees
+with any parameters. A function called before it is declared is taken to be
+`int f()`, so its later declaration has to return `int`.",
+    ),
+];
+
+/// The explanation of `code`, which may be written in lower case.
+pub fn explain(code: &str) -> Option<&'static str> {
+    EXPLANATIONS
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(code))
+        .map(|(_, explanation)| *explanation)
+}
+
+#[test]
+fn test_explanations() {
+    use crate::gen::compile_from_source;
+    use crate::result::CompileError;
+    use crate::token::split_lines;
+
+    for (i, (code, _)) in EXPLANATIONS.iter().enumerate() {
+        assert_eq!(*code, format!("C{:04}", i + 1));
+    }
+    assert!(explain("c0012").unwrap().contains("const int a = 1;"));
+    assert_eq!(explain("C9999"), None);
+
+    // The examples cause the errors they explain.
+    let code = |source: &str| {
+        let error = compile_from_source(split_lines(source)).unwrap_err();
+        error.downcast_ref::<CompileError>().unwrap().code()
+    };
+    assert_eq!(code("int a = 1\nreturn a;"), "C0001");
+    assert_eq!(code("int a = 1"), "C0002");
+    assert_eq!(code("int x = (1 + 2;"), "C0003");
+    assert_eq!(code("char int c;"), "C0004");
+    assert_eq!(code("int a;\nint a;"), "C0005");
+    assert_eq!(code("int main() { return 0; } 1;"), "C0005");
+    assert_eq!(code("const int a = 1; a = 2;"), "C0012");
+    assert_eq!(code("int a = 1 @ 2;"), "C0020");
+    assert_eq!(code("int f(int) { return 1; }"), "C0023");
+    assert_eq!(code("if (1)"), "C0024");
+    assert_eq!(code(""), "C0025");
+    assert_eq!(code("void f() { return 1; }"), "C0027");
+    assert_eq!(code("void f() {}\nint x = f();"), "C0028");
+    assert_eq!(
+        code("int f(int);\nlong f(int a, int b) { return a; }"),
+        "C0029"
+    );
+}
@@ -0,0 +1,595 @@
+use crate::result::CompileError;
+use crate::token::{lex_with_trivia, Token, TokenKind, Trivia};
+use anyhow::Result;
+
+/// How `format_source` lays out a file.
+#[derive(Debug, Clone)]
+pub struct FormatOptions {
+    /// The column that lines are wrapped at where they can be.
+    pub width: usize,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self { width: 80 }
+    }
+}
+
+/// Lays out a C source file the way `print_program` would: four spaces of
+/// indentation per block, the opening brace on the line it belongs to, and
+/// spaces around binary operators and after commas. Lines longer than the
+/// width are broken after commas and `=`, or before binary operators, the
+/// outermost first, and go on eight spaces further in. Comments and
+/// directives are kept, as is one blank line wherever there were some.
+///
+/// Only the trivia between tokens changes, unlike with `print_program`,
+/// which works on the syntax tree. The source is expected to parse, which
+/// `ccompiler fmt` checks before formatting it.
+pub fn format_source(file: &str, text: &str, options: &FormatOptions) -> Result<String> {
+    let (tokens, trailing) = lex_with_trivia(file, text)?;
+    let mut formatter = Formatter {
+        text,
+        tokens: &tokens,
+        pos: 0,
+        width: options.width,
+        lines: Vec::new(),
+        line: Vec::new(),
+        depth: 0,
+        parens: Vec::new(),
+        last: None,
+        break_next: false,
+        rank_next: None,
+        trivia_done: None,
+        pending_blank: false,
+        block_start: false,
+        after_code: false,
+    };
+    while formatter.pos < tokens.len() {
+        formatter.stmt();
+    }
+    formatter.trivia(&trailing, true);
+    formatter.end_line();
+
+    let mut output = formatter.lines.join("\n");
+    if !output.is_empty() {
+        output.push('\n');
+    }
+    if significant(file, text)? != significant(file, &output)? {
+        Err(CompileError::Internal("formatted source"))?;
+    }
+    Ok(output)
+}
+
+/// The tokens as written, and the comments and directives between them,
+/// which formatting must leave as they are apart from indentation.
+fn significant(file: &str, text: &str) -> Result<Vec<String>> {
+    let trimmed = |text: &str| text.lines().map(str::trim).collect::<Vec<_>>().join("\n");
+    let (tokens, trailing) = lex_with_trivia(file, text)?;
+    let mut items = Vec::new();
+    let add_trivia = |trivia: &[Trivia], items: &mut Vec<String>| {
+        for trivia in trivia {
+            if let Trivia::Comment(text) | Trivia::Directive(text) = trivia {
+                items.push(trimmed(text));
+            }
+        }
+    };
+    for token in &tokens {
+        add_trivia(token.leading(), &mut items);
+        items.push(spelling(text, token));
+    }
+    add_trivia(&trailing, &mut items);
+    Ok(items)
+}
+
+/// A token as written, but joined where a spliced line splits it.
+fn spelling(text: &str, token: &Token) -> String {
+    text[token.span().start..token.span().end]
+        .replace("\\\r\n", "")
+        .replace("\\\n", "")
+}
+
+/// What a token does on its line, which decides the spacing around it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Role {
+    /// A name, number or string, or a `)` closing an expression.
+    Operand,
+    /// A `)` closing a type name, as in a cast or a parameter list.
+    TypeClose,
+    /// A unary operator, or the `*` of a pointer declarator.
+    Prefix,
+    Binary,
+    Other,
+}
+
+/// A token or comment on the line being put together.
+struct Piece {
+    text: String,
+    /// Whether a space separates it from the piece before.
+    space: bool,
+    /// Whether the line has to be broken before it, as after a `//` comment.
+    hard_break: bool,
+    /// A directive, which goes on a line of its own at the first column.
+    directive: bool,
+    /// How good a place it is to break the line before it, lower being
+    /// better: the depth of parentheses it is in, then the kind of break.
+    rank: Option<(usize, u8)>,
+}
+
+struct Formatter<'a> {
+    text: &'a str,
+    tokens: &'a [Token],
+    pos: usize,
+    width: usize,
+    lines: Vec<String>,
+    /// The line being put together, which is wrapped once it is complete.
+    line: Vec<Piece>,
+    depth: usize,
+    /// The open parentheses on the line, and whether each is around a type
+    /// name.
+    parens: Vec<bool>,
+    /// The last token on the line.
+    last: Option<(TokenKind, Role)>,
+    /// Whether the next piece has to start a new line.
+    break_next: bool,
+    /// How good a place the end of the last piece is to break at.
+    rank_next: Option<(usize, u8)>,
+    /// The token whose leading trivia has already been laid out.
+    trivia_done: Option<usize>,
+    /// Whether a blank line goes before the next line.
+    pending_blank: bool,
+    /// Whether the last line opened a block, so that no blank line follows.
+    block_start: bool,
+    /// Whether the last line is code rather than a comment or directive.
+    after_code: bool,
+}
+
+impl Formatter<'_> {
+    fn peek(&self) -> Option<TokenKind> {
+        self.tokens.get(self.pos).map(Token::kind)
+    }
+
+    fn stmt(&mut self) {
+        match self.peek() {
+            Some(TokenKind::If) => self.if_stmt(),
+            Some(TokenKind::LCurlyBracket) => {
+                self.block();
+                self.end_line();
+            }
+            // A `}` without a block, which does not parse anyway.
+            Some(TokenKind::RCurlyBracket) => {
+                self.push();
+                self.end_line();
+            }
+            _ => self.simple(),
+        }
+    }
+
+    /// A statement up to its `;`, or a function definition.
+    fn simple(&mut self) {
+        while let Some(kind) = self.peek() {
+            match kind {
+                TokenKind::Semicolon if self.parens.is_empty() => {
+                    self.push();
+                    break;
+                }
+                TokenKind::LCurlyBracket if self.parens.is_empty() => {
+                    // Function definitions are set apart by blank lines,
+                    // though not from the comments before them.
+                    let function = self.depth == 0;
+                    if function && self.after_code {
+                        self.pending_blank = true;
+                    }
+                    self.block();
+                    if function {
+                        self.end_line();
+                        self.pending_blank = true;
+                    }
+                    break;
+                }
+                TokenKind::RCurlyBracket if self.parens.is_empty() => break,
+                _ => self.push(),
+            }
+        }
+        self.end_line();
+    }
+
+    fn if_stmt(&mut self) {
+        self.push();
+        if self.peek() == Some(TokenKind::LRoundBracket) {
+            self.push();
+            while !self.parens.is_empty() && self.pos < self.tokens.len() {
+                self.push();
+            }
+        }
+        let open = self.branch();
+        if self.peek() != Some(TokenKind::Else) {
+            if open {
+                self.end_line();
+            }
+            return;
+        }
+        if has_comment(self.tokens[self.pos].leading()) {
+            self.end_line();
+        }
+        self.push();
+        if self.peek() == Some(TokenKind::If) {
+            self.if_stmt();
+        } else if self.branch() {
+            self.end_line();
+        }
+    }
+
+    /// The statement after `if (...)` or `else`. Returns whether it is a
+    /// block, whose `}` is left on the line for an `else` to follow.
+    fn branch(&mut self) -> bool {
+        if self.peek() == Some(TokenKind::LCurlyBracket) {
+            self.block();
+            return true;
+        }
+        self.end_line();
+        self.depth += 1;
+        if self.pos < self.tokens.len() {
+            self.stmt();
+        }
+        self.depth -= 1;
+        false
+    }
+
+    /// `{ ... }`, leaving the `}` on the line. An empty block stays `{}`.
+    fn block(&mut self) {
+        self.push();
+        if self.peek() == Some(TokenKind::RCurlyBracket)
+            && !has_comment(self.tokens[self.pos].leading())
+        {
+            self.push();
+            return;
+        }
+        self.end_line();
+        self.block_start = true;
+        self.depth += 1;
+        while !matches!(self.peek(), Some(TokenKind::RCurlyBracket) | None) {
+            self.stmt();
+        }
+        // Comments before the `}` are still inside the block.
+        if let Some(token) = self.tokens.get(self.pos) {
+            self.trivia(token.leading(), true);
+            self.trivia_done = Some(self.pos);
+        }
+        self.depth -= 1;
+        if self.pos < self.tokens.len() {
+            self.push();
+        }
+    }
+
+    /// Adds the next token to the line, after its leading trivia.
+    fn push(&mut self) {
+        let token = &self.tokens[self.pos];
+        if self.trivia_done != Some(self.pos) {
+            self.trivia(token.leading(), false);
+        }
+        let kind = token.kind();
+        let text = spelling(self.text, token);
+
+        let role = match &kind {
+            TokenKind::Ident(_) | TokenKind::Num(_) | TokenKind::Str(_) => Role::Operand,
+            TokenKind::RRoundBracket => match self.parens.pop() {
+                Some(true) => Role::TypeClose,
+                _ => Role::Operand,
+            },
+            TokenKind::Add | TokenKind::Sub | TokenKind::Mul | TokenKind::Addr => match self.last {
+                Some((_, Role::Operand)) => Role::Binary,
+                _ => Role::Prefix,
+            },
+            TokenKind::Not => Role::Prefix,
+            _ if binary_rank(&kind).is_some() => Role::Binary,
+            _ => Role::Other,
+        };
+        let space = self.space_before(&kind, role);
+        let rank = match role {
+            Role::Binary if kind != TokenKind::Assign => {
+                binary_rank(&kind).map(|rank| (self.parens.len(), rank))
+            }
+            _ => self.rank_next.take(),
+        };
+        self.line.push(Piece {
+            text,
+            space,
+            hard_break: std::mem::take(&mut self.break_next),
+            directive: false,
+            rank,
+        });
+
+        self.rank_next = match kind {
+            TokenKind::Assign => Some((self.parens.len(), 0)),
+            TokenKind::Comma => Some((self.parens.len(), 1)),
+            _ => None,
+        };
+        if kind == TokenKind::LRoundBracket {
+            let type_name = self
+                .tokens
+                .get(self.pos + 1)
+                .is_some_and(|next| next.kind().is_type_name());
+            self.parens.push(type_name);
+        }
+        self.last = Some((kind, role));
+        self.pos += 1;
+    }
+
+    fn space_before(&self, kind: &TokenKind, role: Role) -> bool {
+        let Some((last, last_role)) = &self.last else {
+            return false;
+        };
+        match (last, kind) {
+            (_, TokenKind::RRoundBracket | TokenKind::Comma | TokenKind::Semicolon) => false,
+            (TokenKind::LRoundBracket, _) => false,
+            (TokenKind::LCurlyBracket, TokenKind::RCurlyBracket) => false,
+            _ if *last_role == Role::Prefix => false,
+            // Calls, declarators and `asm volatile(...)`.
+            (
+                TokenKind::Ident(_)
+                | TokenKind::RRoundBracket
+                | TokenKind::Str(_)
+                | TokenKind::Asm
+                | TokenKind::Volatile,
+                TokenKind::LRoundBracket,
+            ) => false,
+            // A cast is followed by its operand, a parameter list by `=` or
+            // the body.
+            _ if *last_role == Role::TypeClose => {
+                role == Role::Binary || *kind == TokenKind::LCurlyBracket
+            }
+            _ => true,
+        }
+    }
+
+    /// Lays out the trivia before a token, or at the end of the file: a
+    /// comment on the same line as the token before stays there, other
+    /// comments and directives get lines of their own, and blank lines are
+    /// kept as one, except before a closing `}`.
+    fn trivia(&mut self, trivia: &[Trivia], closing: bool) {
+        let mut newlines = 0;
+        let mut comment_in_line = false;
+        for item in trivia {
+            let (text, directive) = match item {
+                Trivia::Space(_) => continue,
+                Trivia::Newline => {
+                    newlines += 1;
+                    continue;
+                }
+                Trivia::Comment(text) => (text.as_str(), false),
+                Trivia::Directive(text) => (text.trim_start(), true),
+            };
+            let after_token = newlines == 0 && !directive && self.pos > 0;
+            if !self.line.is_empty() {
+                let hard_break = std::mem::take(&mut self.break_next) || !after_token;
+                self.line.push(Piece {
+                    text: text.to_string(),
+                    space: true,
+                    hard_break,
+                    directive,
+                    rank: None,
+                });
+                self.break_next = directive || text.starts_with("//");
+                comment_in_line = !directive;
+            } else if let Some(last) = self.lines.last_mut().filter(|_| after_token) {
+                last.push(' ');
+                last.push_str(text);
+            } else {
+                if newlines >= 2 {
+                    self.pending_blank = true;
+                }
+                let indent = if directive { 0 } else { self.depth * 4 };
+                self.emit(format!("{}{}", " ".repeat(indent), text), false);
+            }
+            newlines = 0;
+        }
+        // Code after a comment in the middle of a line starts on the next
+        // line if it did in the source.
+        if comment_in_line && newlines > 0 {
+            self.break_next = true;
+        }
+        if newlines >= 2 && self.line.is_empty() && !closing {
+            self.pending_blank = true;
+        }
+    }
+
+    /// Adds a finished line, with a blank line before it if one is due.
+    fn emit(&mut self, line: String, code: bool) {
+        if self.pending_blank && !self.block_start && !self.lines.is_empty() {
+            self.lines.push(String::new());
+        }
+        self.pending_blank = false;
+        self.block_start = false;
+        self.after_code = code;
+        for line in line.split('\n') {
+            self.lines.push(line.trim_end().to_string());
+        }
+    }
+
+    /// Wraps up the line being put together.
+    fn end_line(&mut self) {
+        let pieces = std::mem::take(&mut self.line);
+        self.parens.clear();
+        self.last = None;
+        self.break_next = false;
+        self.rank_next = None;
+
+        let indent = self.depth * 4;
+        let mut start = 0;
+        for end in 1..=pieces.len() {
+            if end < pieces.len() && !pieces[end].hard_break {
+                continue;
+            }
+            let chunk = &pieces[start..end];
+            if chunk[0].directive {
+                self.emit(chunk[0].text.clone(), false);
+            } else {
+                let first = if start == 0 { indent } else { indent + 8 };
+                let mut lines = vec![" ".repeat(first)];
+                self.wrap(chunk, indent + 8, &mut lines);
+                for line in lines {
+                    self.emit(line, true);
+                }
+            }
+            start = end;
+        }
+    }
+
+    /// Adds `pieces` to `lines`, the last of which is still open. They are
+    /// split where it is best to break them, and each part goes on the open
+    /// line if it fits there, else on a line of its own at column `cont`,
+    /// and is split further if it fits on neither.
+    fn wrap(&self, pieces: &[Piece], cont: usize, lines: &mut Vec<String>) {
+        let best = pieces.iter().skip(1).filter_map(|piece| piece.rank).min();
+        let mut starts = vec![0];
+        if let Some(best) = best {
+            starts.extend((1..pieces.len()).filter(|&i| pieces[i].rank == Some(best)));
+        }
+        starts.push(pieces.len());
+
+        for window in starts.windows(2) {
+            let part = &pieces[window[0]..window[1]];
+            let line = lines.last_mut().expect("an open line");
+            let empty = line.trim_start().is_empty();
+            let space = usize::from(!empty && part[0].space);
+            if line.chars().count() + space + width(part) <= self.width {
+                if space == 1 {
+                    line.push(' ');
+                }
+                line.push_str(&join(part));
+            } else if best.is_some() && cont + width(part) > self.width {
+                self.wrap(part, cont, lines);
+            } else if empty {
+                line.push_str(&join(part));
+            } else {
+                lines.push(format!("{}{}", " ".repeat(cont), join(part)));
+            }
+        }
+    }
+}
+
+/// The pieces on one line.
+fn join(pieces: &[Piece]) -> String {
+    let mut text = String::new();
+    for (i, piece) in pieces.iter().enumerate() {
+        if i > 0 && piece.space {
+            text.push(' ');
+        }
+        text.push_str(&piece.text);
+    }
+    text
+}
+
+fn width(pieces: &[Piece]) -> usize {
+    join(pieces).chars().count()
+}
+
+/// How good a place the line is to break before a binary operator, lower
+/// being better, or `None` if the token is not one.
+fn binary_rank(kind: &TokenKind) -> Option<u8> {
+    match kind {
+        TokenKind::Assign => Some(0),
+        TokenKind::Question | TokenKind::Colon => Some(2),
+        TokenKind::LogicalOr => Some(3),
+        TokenKind::LogicalAnd => Some(4),
+        TokenKind::Equal | TokenKind::NEqual => Some(5),
+        TokenKind::Less
+        | TokenKind::Greater
+        | TokenKind::LessOrEqual
+        | TokenKind::GreaterOrEqual => Some(6),
+        TokenKind::Add | TokenKind::Sub => Some(7),
+        TokenKind::Mul | TokenKind::Div | TokenKind::Mod | TokenKind::Addr => Some(8),
+        _ => None,
+    }
+}
+
+fn has_comment(trivia: &[Trivia]) -> bool {
+    trivia
+        .iter()
+        .any(|trivia| matches!(trivia, Trivia::Comment(_)))
+}
+
+#[test]
+fn test_format_source() {
+    let format =
+        |source: &str, width: usize| format_source("", source, &FormatOptions { width }).unwrap();
+
+    let source = r#"#define SQUARE(x) ((x) * (x))
+/* Adds
+   things */
+int add(int a,int b){return a+b;} // trailing
+int (*op)(int,int)=add;
+int main(){
+  int x=-1;   // minus one
+
+
+  int *p=&x;
+  if(x>0)x=2;else if(x<0){x=(int)-x;}else x=*p;
+  if (x) if (x) x = 1; else { }
+  asm volatile("cpuid":"=a"(x):"a"(0):"rcx");
+  {
+
+  }
+  return op(x,SQUARE(2));
+  // the end
+}
+"#;
+    let formatted = r#"#define SQUARE(x) ((x) * (x))
+/* Adds
+   things */
+int add(int a, int b) {
+    return a + b;
+} // trailing
+
+int (*op)(int, int) = add;
+
+int main() {
+    int x = -1; // minus one
+
+    int *p = &x;
+    if (x > 0)
+        x = 2;
+    else if (x < 0) {
+        x = (int)-x;
+    } else
+        x = *p;
+    if (x)
+        if (x)
+            x = 1;
+        else {}
+    asm volatile("cpuid" : "=a"(x) : "a"(0) : "rcx");
+    {}
+    return op(x, SQUARE(2));
+    // the end
+}
+"#;
+    assert_eq!(format(source, 80), formatted);
+    assert_eq!(format(formatted, 80), formatted);
+
+    // Lines are filled as far as they go, breaking at the outermost places
+    // first, and comments force breaks.
+    assert_eq!(
+        format(
+            "long total = add(first_argument, second_argument) * 2 + add(third, fourth);",
+            40
+        ),
+        concat!(
+            "long total = add(first_argument,\n",
+            "        second_argument) * 2\n",
+            "        + add(third, fourth);\n",
+        )
+    );
+    assert_eq!(
+        format("f(a, // first\n  b);", 80),
+        "f(a, // first\n        b);\n"
+    );
+    // Spliced lines are joined, as the compiler reads them.
+    assert_eq!(
+        format("int main(){ ret\\\nurn 6; }\nint x = 1 +\\\r\n 2;", 80),
+        "int main() {\n    return 6;\n}\n\nint x = 1 + 2;\n"
+    );
+    assert_eq!(
+        format("int y = 1; /* a \\\n b */ int z = 2;", 80),
+        "int y = 1; /* a \\\n b */\nint z = 2;\n"
+    );
+}
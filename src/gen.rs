@@ -1,184 +1,841 @@
-use crate::node::program;
-use crate::node::Node;
-use crate::result::CompileError;
-use crate::token::{tokenize, TokenKind};
+use crate::ast::{BinaryOp, Decl, Expr, ExprKind, InlineAsm, Item, Stmt, StmtKind};
+use crate::diagnostic::{Diagnostic, Diagnostics};
+use crate::node::program_with_recovery;
+use crate::preprocess::Preprocessor;
+use crate::result::{CompileError, CompileWarning};
+use crate::sema::analyze;
+use crate::token::{classify, Span, TokenStream};
+use crate::types::{const_value, Type, ARG_REGISTERS};
+use crate::visit::{walk_decl, walk_expr, Visitor};
+use crate::warning::check_program;
 use anyhow::Result;
 use std::fmt::Write;
 
-pub fn gen_lval(node: &Box<Node>, output: &mut String) -> Result<()> {
-    if let TokenKind::LocalVar { offset, .. } = node.kind() {
-        writeln!(output, "  mov rax, rbp")?;
-        writeln!(output, "  sub rax, {}", offset)?;
-        writeln!(output, "  push rax")?;
-    } else {
-        Err(CompileError::ParseError(None))?;
+pub fn gen_lval(expr: &Expr, output: &mut String, labels: &mut u32) -> Result<()> {
+    match &expr.kind {
+        ExprKind::Var { name, .. } if expr.ty.as_ref().is_some_and(Type::is_func) => {
+            writeln!(output, "  mov rax, [rip + {}@GOTPCREL]", name)?;
+            writeln!(output, "  push rax")?;
+        }
+        ExprKind::Var { offset, .. } => {
+            writeln!(output, "  mov rax, rbp")?;
+            writeln!(output, "  sub rax, {}", offset)?;
+            writeln!(output, "  push rax")?;
+        }
+        ExprKind::Deref(operand) => gen_expr(operand, output, labels)?,
+        _ => Err(CompileError::Internal("lvalue"))?,
     }
     Ok(())
 }
 
-pub fn gen(node: &Box<Node>, output: &mut String) -> Result<()> {
-    match node.kind() {
-        TokenKind::If => {
-            gen(
-                node.lhs
-                    .as_ref()
-                    .ok_or_else(|| CompileError::ParseError(Some("If lhs")))?,
-                output,
-            )?;
+fn expr_type(expr: &Expr) -> Result<&Type> {
+    Ok(expr
+        .ty
+        .as_ref()
+        .ok_or(CompileError::Internal("untyped expression"))?)
+}
+
+/// Loads a value of type `ty` from the address in rax into rax.
+fn load(ty: &Type, output: &mut String) -> Result<()> {
+    load_register(ty, RAX, output)
+}
+
+/// Loads a value of type `ty` from the address in a register into itself.
+fn load_register(ty: &Type, reg: usize, output: &mut String) -> Result<()> {
+    let (r64, r32) = (REGS[3][reg], REGS[2][reg]);
+    match (ty.size(), ty.is_signed()) {
+        (1, true) => writeln!(output, "  movsx {}, byte ptr [{}]", r64, r64)?,
+        (1, false) => writeln!(output, "  movzx {}, byte ptr [{}]", r64, r64)?,
+        (2, true) => writeln!(output, "  movsx {}, word ptr [{}]", r64, r64)?,
+        (2, false) => writeln!(output, "  movzx {}, word ptr [{}]", r64, r64)?,
+        (4, true) => writeln!(output, "  movsxd {}, dword ptr [{}]", r64, r64)?,
+        (4, false) => writeln!(output, "  mov {}, dword ptr [{}]", r32, r64)?,
+        _ => writeln!(output, "  mov {}, [{}]", r64, r64)?,
+    }
+    Ok(())
+}
+
+/// Stores rdi to the address in rax as a value of type `ty`.
+fn store(ty: &Type, output: &mut String) -> Result<()> {
+    match ty.size() {
+        1 => writeln!(output, "  mov [rax], dil")?,
+        2 => writeln!(output, "  mov [rax], di")?,
+        4 => writeln!(output, "  mov [rax], edi")?,
+        _ => writeln!(output, "  mov [rax], rdi")?,
+    }
+    Ok(())
+}
+
+/// Truncates rax to `ty` and extends it back to 64 bits.
+fn convert(ty: &Type, output: &mut String) -> Result<()> {
+    if ty.is_bool() {
+        writeln!(output, "  cmp rax, 0")?;
+        writeln!(output, "  setne al")?;
+        writeln!(output, "  movzb rax, al")?;
+        return Ok(());
+    }
+
+    match (ty.size(), ty.is_signed()) {
+        (1, true) => writeln!(output, "  movsx rax, al")?,
+        (1, false) => writeln!(output, "  movzx rax, al")?,
+        (2, true) => writeln!(output, "  movsx rax, ax")?,
+        (2, false) => writeln!(output, "  movzx rax, ax")?,
+        (4, true) => writeln!(output, "  movsxd rax, eax")?,
+        (4, false) => writeln!(output, "  mov eax, eax")?,
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Emits a statement, which leaves one value on the stack: the value of an
+/// expression statement and 0 for most others.
+pub fn gen_stmt(stmt: &Stmt, output: &mut String, labels: &mut u32) -> Result<()> {
+    match &stmt.kind {
+        StmtKind::Expr(expr) => gen_expr(expr, output, labels)?,
+        StmtKind::Decl(decl) => match &decl.init {
+            Some(init) if !decl.ty.is_func() => {
+                writeln!(output, "  mov rax, rbp")?;
+                writeln!(output, "  sub rax, {}", decl.offset)?;
+                writeln!(output, "  push rax")?;
+                gen_expr(init, output, labels)?;
+                writeln!(output, "  pop rdi")?;
+                writeln!(output, "  pop rax")?;
+                store(&decl.ty, output)?;
+                writeln!(output, "  push rdi")?;
+            }
+            _ => writeln!(output, "  push 0")?,
+        },
+        StmtKind::Block(stmts) => {
+            for stmt in stmts {
+                gen_stmt(stmt, output, labels)?;
+                writeln!(output, "  pop rax")?;
+            }
+            if stmts.is_empty() {
+                writeln!(output, "  push 0")?;
+            } else {
+                writeln!(output, "  push rax")?;
+            }
+        }
+        StmtKind::If {
+            cond,
+            then,
+            otherwise,
+        } => {
+            gen_expr(cond, output, labels)?;
             writeln!(output, "  pop rax")?;
             writeln!(output, "  cmp rax, 0")?;
 
-            let rhs = node
-                .rhs
-                .as_ref()
-                .ok_or_else(|| CompileError::ParseError(Some("If rhs")))?;
-
-            if rhs.kind == TokenKind::Else {
-                writeln!(output, "  je .LelseA")?;
-                gen(
-                    rhs.lhs
-                        .as_ref()
-                        .ok_or_else(|| CompileError::ParseError(Some("")))?,
-                    output,
-                )?;
-                writeln!(output, "  jmp .LendA")?;
-                writeln!(output, ".LelseA:")?;
-                gen(
-                    rhs.rhs
-                        .as_ref()
-                        .ok_or_else(|| CompileError::ParseError(Some("")))?,
-                    output,
-                )?;
-                writeln!(output, ".LendA:")?;
-            } else {
-                writeln!(output, "  je .LendA")?;
-                writeln!(output, ".LendA:")?;
-                gen(rhs, output)?;
+            *labels += 1;
+            let label = *labels;
+            writeln!(output, "  je .Lelse{}", label)?;
+            gen_stmt(then, output, labels)?;
+            writeln!(output, "  jmp .Lend{}", label)?;
+            writeln!(output, ".Lelse{}:", label)?;
+            match otherwise {
+                Some(otherwise) => gen_stmt(otherwise, output, labels)?,
+                // Every statement leaves one value on the stack, even a skipped one.
+                None => writeln!(output, "  push 0")?,
             }
+            writeln!(output, ".Lend{}:", label)?;
+        }
+        StmtKind::Return(value) => {
+            if let Some(value) = value {
+                gen_expr(value, output, labels)?;
+                writeln!(output, "  pop rax")?;
+            }
+            writeln!(output, "  mov rsp, rbp")?;
+            writeln!(output, "  pop rbp")?;
+            writeln!(output, "  ret")?;
+        }
+        StmtKind::Asm(asm) => gen_asm(asm, &stmt.span, output, labels)?,
+    }
+    Ok(())
+}
 
+pub fn gen_expr(expr: &Expr, output: &mut String, labels: &mut u32) -> Result<()> {
+    let (op, lhs, rhs) = match &expr.kind {
+        ExprKind::Num(num) => {
+            writeln!(output, "  push {}", num)?;
             return Ok(());
         }
-        TokenKind::Num(num) => {
-            writeln!(output, "  push {}", num)?;
+        // The characters go to read-only data right where they are used,
+        // with a terminating zero.
+        ExprKind::Str(text) => {
+            *labels += 1;
+            let bytes: Vec<String> = text.bytes().chain([0]).map(|b| b.to_string()).collect();
+            writeln!(output, "  .pushsection .rodata")?;
+            writeln!(output, ".Lstr{}:", labels)?;
+            writeln!(output, "  .byte {}", bytes.join(", "))?;
+            writeln!(output, "  .popsection")?;
+            writeln!(output, "  lea rax, [rip + .Lstr{}]", labels)?;
+            writeln!(output, "  push rax")?;
             return Ok(());
         }
-        TokenKind::LocalVar { .. } => {
-            gen_lval(node, output)?;
+        ExprKind::Var { .. } | ExprKind::Deref(_) => {
+            gen_lval(expr, output, labels)?;
             writeln!(output, "  pop rax")?;
-            writeln!(output, "  mov rax, [rax]")?;
+            load(expr_type(expr)?, output)?;
             writeln!(output, "  push rax")?;
             return Ok(());
         }
-        TokenKind::Return => {
-            gen(
-                node.lhs
-                    .as_ref()
-                    .ok_or_else(|| CompileError::ParseError(None))?,
-                output,
-            )?;
+        ExprKind::Addr(operand) => return gen_lval(operand, output, labels),
+        ExprKind::Cast(operand) => {
+            gen_expr(operand, output, labels)?;
             writeln!(output, "  pop rax")?;
-            writeln!(output, "  mov rsp, rbp")?;
-            writeln!(output, "  pop rbp")?;
-            writeln!(output, "  ret")?;
+            convert(expr_type(expr)?, output)?;
+            writeln!(output, "  push rax")?;
             return Ok(());
         }
-        TokenKind::Assign => {
-            gen_lval(
-                node.lhs
-                    .as_ref()
-                    .ok_or_else(|| CompileError::ParseError(None))?,
-                output,
-            )?;
-            gen(
-                node.rhs
-                    .as_ref()
-                    .ok_or_else(|| CompileError::ParseError(None))?,
-                output,
-            )?;
+        ExprKind::Assign { lhs, rhs } => {
+            gen_lval(lhs, output, labels)?;
+            gen_expr(rhs, output, labels)?;
             writeln!(output, "  pop rdi")?;
             writeln!(output, "  pop rax")?;
-            writeln!(output, "  mov [rax], rdi")?;
+            store(expr_type(expr)?, output)?;
             writeln!(output, "  push rdi")?;
             return Ok(());
         }
-        _ => {}
-    }
+        ExprKind::Call { callee, args } => return gen_call(expr, callee, args, output, labels),
+        ExprKind::Binary { op, lhs, rhs } => (*op, lhs, rhs),
+    };
 
-    gen(
-        node.lhs
-            .as_ref()
-            .ok_or_else(|| CompileError::ParseError(None))?,
-        output,
-    )?;
-
-    gen(
-        node.rhs
-            .as_ref()
-            .ok_or_else(|| CompileError::ParseError(None))?,
-        output,
-    )?;
+    gen_expr(lhs, output, labels)?;
+    gen_expr(rhs, output, labels)?;
 
     writeln!(output, "  pop rdi")?;
     writeln!(output, "  pop rax")?;
 
-    match node.kind() {
-        TokenKind::Add => writeln!(output, "  add rax, rdi")?,
-        TokenKind::Sub => writeln!(output, "  sub rax, rdi")?,
-        TokenKind::Mul => writeln!(output, "  imul rax, rdi")?,
-        TokenKind::Div => {
+    // Pointers compare as unsigned, and are scaled by their pointee in arithmetic.
+    let operand = expr_type(lhs)?;
+    let signed = operand.is_signed();
+    let scale = operand.pointee().map(Type::size);
+
+    match op {
+        BinaryOp::Add | BinaryOp::Sub if expr_type(rhs)?.is_pointer() => {
+            writeln!(output, "  sub rax, rdi")?;
+            writeln!(output, "  mov rdi, {}", scale.unwrap_or(1))?;
+            writeln!(output, "  cqo")?;
+            writeln!(output, "  idiv rdi")?;
+        }
+        BinaryOp::Add | BinaryOp::Sub => {
+            if let Some(scale) = scale {
+                writeln!(output, "  imul rdi, {}", scale)?;
+            }
+            if op == BinaryOp::Add {
+                writeln!(output, "  add rax, rdi")?;
+            } else {
+                writeln!(output, "  sub rax, rdi")?;
+            }
+        }
+        BinaryOp::Mul => writeln!(output, "  imul rax, rdi")?,
+        BinaryOp::Div if signed => {
             writeln!(output, "  cqo")?;
             writeln!(output, "  idiv rdi")?;
         }
-        TokenKind::Equal => {
+        BinaryOp::Div => {
+            writeln!(output, "  xor edx, edx")?;
+            writeln!(output, "  div rdi")?;
+        }
+        BinaryOp::Equal => {
             writeln!(output, "  cmp rax, rdi")?;
             writeln!(output, "  sete al")?;
             writeln!(output, "  movzb rax, al")?;
         }
-        TokenKind::NEqual => {
+        BinaryOp::NEqual => {
             writeln!(output, "  cmp rax, rdi")?;
             writeln!(output, "  setne al")?;
             writeln!(output, "  movzb rax, al")?;
         }
-        TokenKind::Less => {
+        BinaryOp::Less => {
             writeln!(output, "  cmp rax, rdi")?;
-            writeln!(output, "  setl al")?;
+            if signed {
+                writeln!(output, "  setl al")?;
+            } else {
+                writeln!(output, "  setb al")?;
+            }
             writeln!(output, "  movzb rax, al")?;
         }
-        TokenKind::LessOrEqual => {
+        BinaryOp::LessOrEqual => {
             writeln!(output, "  cmp rax, rdi")?;
-            writeln!(output, "  setle al")?;
+            if signed {
+                writeln!(output, "  setle al")?;
+            } else {
+                writeln!(output, "  setbe al")?;
+            }
             writeln!(output, "  movzb rax, al")?;
         }
-        _ => (),
     }
 
+    // Keep the result wrapped to the width of its type.
+    convert(expr_type(expr)?, output)?;
     writeln!(output, "  push rax")?;
 
     Ok(())
 }
 
-pub fn compile_from_source(source: Vec<String>) -> Result<String> {
-    let mut output = String::new();
+const ARG_REGS: [[&str; ARG_REGISTERS]; 4] = [
+    ["dil", "sil", "dl", "cl", "r8b", "r9b"],
+    ["di", "si", "dx", "cx", "r8w", "r9w"],
+    ["edi", "esi", "edx", "ecx", "r8d", "r9d"],
+    ["rdi", "rsi", "rdx", "rcx", "r8", "r9"],
+];
 
-    let (mut ts, variable_len) = tokenize(source)?;
-    let program = program(&mut ts)?;
+/// Row of `ARG_REGS` or `REGS` holding the registers as wide as `ty`.
+fn width(ty: &Type) -> usize {
+    match ty.size() {
+        1 => 0,
+        2 => 1,
+        4 => 2,
+        _ => 3,
+    }
+}
 
-    writeln!(output, ".intel_syntax noprefix")?;
-    writeln!(output, ".globl main")?;
-    writeln!(output, "main:")?;
+fn arg_register(ty: &Type, index: usize) -> &'static str {
+    ARG_REGS[width(ty)][index]
+}
+
+/// Calls `callee`, directly by name when it is a function and through
+/// `call rax` for function pointers. `call` is the call expression itself.
+pub fn gen_call(
+    call: &Expr,
+    callee: &Expr,
+    args: &[Expr],
+    output: &mut String,
+    labels: &mut u32,
+) -> Result<()> {
+    for arg in args {
+        gen_expr(arg, output, labels)?;
+    }
+    let direct = match &callee.kind {
+        ExprKind::Addr(function) => match &function.kind {
+            ExprKind::Var { name, .. } if function.ty.as_ref().is_some_and(Type::is_func) => {
+                Some(name)
+            }
+            _ => None,
+        },
+        _ => None,
+    };
+    if direct.is_none() {
+        gen_expr(callee, output, labels)?;
+        writeln!(output, "  pop rax")?;
+    }
+    for i in (0..args.len()).rev() {
+        writeln!(output, "  pop {}", ARG_REGS[3][i])?;
+    }
+
+    // The ABI wants rsp 16-byte aligned at the call, but the depth of our
+    // expression stack is only known at runtime.
+    writeln!(output, "  mov r10, rsp")?;
+    writeln!(output, "  and rsp, -16")?;
+    writeln!(output, "  sub rsp, 8")?;
+    writeln!(output, "  push r10")?;
+    match direct {
+        Some(symbol) => {
+            writeln!(output, "  mov eax, 0")?;
+            writeln!(output, "  call {}", symbol)?;
+        }
+        None => writeln!(output, "  call rax")?,
+    }
+    writeln!(output, "  pop rsp")?;
+
+    // Only the bits of the return type are defined in rax.
+    convert(expr_type(call)?, output)?;
+    writeln!(output, "  push rax")?;
+    Ok(())
+}
+
+/// General purpose registers other than rsp and rbp, by width.
+const REGS: [[&str; 14]; 4] = [
+    [
+        "al", "bl", "cl", "dl", "sil", "dil", "r8b", "r9b", "r10b", "r11b", "r12b", "r13b", "r14b",
+        "r15b",
+    ],
+    [
+        "ax", "bx", "cx", "dx", "si", "di", "r8w", "r9w", "r10w", "r11w", "r12w", "r13w", "r14w",
+        "r15w",
+    ],
+    [
+        "eax", "ebx", "ecx", "edx", "esi", "edi", "r8d", "r9d", "r10d", "r11d", "r12d", "r13d",
+        "r14d", "r15d",
+    ],
+    [
+        "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13", "r14",
+        "r15",
+    ],
+];
+
+const RAX: usize = 0;
+
+/// Registers our caller expects to survive, i.e. rbx and r12 to r15.
+const CALLEE_SAVED: [usize; 5] = [1, 10, 11, 12, 13];
+
+/// The order in which `r` operands get registers, caller-saved ones first.
+const ALLOCATION_ORDER: [usize; 14] = [6, 7, 8, 9, 4, 5, 2, 3, 0, 1, 10, 11, 12, 13];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum AsmOperand {
+    Register(usize),
+    Immediate(i64),
+}
+
+fn register_index(name: &str) -> Option<usize> {
+    REGS.iter()
+        .find_map(|row| row.iter().position(|reg| *reg == name))
+}
+
+/// Marks `reg` as used by an output (side 0) or input (side 1) operand.
+fn claim(taken: &mut [[bool; 14]; 2], side: usize, reg: usize, span: &Span) -> Result<()> {
+    if taken[side][reg] {
+        Err(CompileError::AsmRegisters { span: span.clone() })?;
+    }
+    taken[side][reg] = true;
+    Ok(())
+}
+
+/// Chooses where each operand of an extended `asm` lives. Registers named by
+/// a constraint letter or a clobber are never handed out for `r`, and an
+/// input may share a register with an output as the value is read first.
+/// `span` is the whole statement.
+fn asm_operands(asm: &InlineAsm, span: &Span, clobbered: &[bool; 14]) -> Result<Vec<AsmOperand>> {
+    let (constraints, outputs) = (&asm.constraints, asm.outputs);
+    let invalid = |i: usize| CompileError::InvalidAsmConstraint {
+        constraint: constraints[i].clone(),
+        span: asm.operands[i].span.clone(),
+    };
+    let no_registers = || CompileError::AsmRegisters { span: span.clone() };
+    let mut operands = vec![None; constraints.len()];
+    let mut taken = [[false; 14]; 2];
+    let mut general = Vec::new();
+    let mut matching = Vec::new();
+
+    for (i, constraint) in constraints.iter().enumerate() {
+        let is_output = i < outputs;
+        let letters = constraint.trim_start_matches(['=', '+', '&']);
+        if is_output != (constraint.starts_with('=') || constraint.starts_with('+')) {
+            Err(invalid(i))?;
+        }
+        let fixed = match letters {
+            "a" => 0,
+            "b" => 1,
+            "c" => 2,
+            "d" => 3,
+            "S" => 4,
+            "D" => 5,
+            "r" => {
+                general.push(i);
+                continue;
+            }
+            "i" | "n" if !is_output => {
+                let value = const_value(&asm.operands[i]).ok_or_else(|| invalid(i))?;
+                operands[i] = Some(AsmOperand::Immediate(value));
+                continue;
+            }
+            _ if !is_output => {
+                let target = letters.parse::<usize>().map_err(|_| invalid(i))?;
+                if target >= outputs {
+                    Err(invalid(i))?;
+                }
+                matching.push((i, target));
+                continue;
+            }
+            _ => Err(invalid(i))?,
+        };
+        if clobbered[fixed] {
+            Err(no_registers())?;
+        }
+        claim(&mut taken, if is_output { 0 } else { 1 }, fixed, span)?;
+        // A read-write output is also read before the template runs.
+        if constraint.starts_with('+') {
+            claim(&mut taken, 1, fixed, span)?;
+        }
+        operands[i] = Some(AsmOperand::Register(fixed));
+    }
+
+    for i in general {
+        let reg = *ALLOCATION_ORDER
+            .iter()
+            .find(|&&reg| !clobbered[reg] && !taken[0][reg] && !taken[1][reg])
+            .ok_or_else(no_registers)?;
+        taken[if i < outputs { 0 } else { 1 }][reg] = true;
+        if constraints[i].starts_with('+') {
+            taken[1][reg] = true;
+        }
+        operands[i] = Some(AsmOperand::Register(reg));
+    }
+
+    for (i, target) in matching {
+        let Some(AsmOperand::Register(reg)) = operands[target] else {
+            return Err(invalid(i).into());
+        };
+        if constraints[target].starts_with('+') {
+            Err(no_registers())?;
+        }
+        claim(&mut taken, 1, reg, span)?;
+        operands[i] = Some(AsmOperand::Register(reg));
+    }
+
+    Ok(operands.into_iter().flatten().collect())
+}
+
+/// Replaces `%0`, `%1`, ... in the template of an extended `asm` with its
+/// operands. Registers are as wide as the operand unless a `b`, `w`, `k` or
+/// `q` modifier asks otherwise; `%%` is a percent sign and `%=` a number
+/// unique to this statement, which `span` is.
+fn asm_template(
+    template: &str,
+    operands: &[AsmOperand],
+    widths: &[usize],
+    id: u32,
+    span: &Span,
+) -> Result<String> {
+    let mut result = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            result.push(c);
+            continue;
+        }
+        let modifier = match chars.peek() {
+            Some('%') => {
+                chars.next();
+                result.push('%');
+                continue;
+            }
+            Some('=') => {
+                chars.next();
+                write!(result, "{}", id)?;
+                continue;
+            }
+            Some('b') => Some(0),
+            Some('w') => Some(1),
+            Some('k') => Some(2),
+            Some('q') => Some(3),
+            _ => None,
+        };
+        if modifier.is_some() {
+            chars.next();
+        }
+        let mut digits = String::new();
+        while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+            digits.push(digit);
+        }
+        let index = digits
+            .parse::<usize>()
+            .ok()
+            .filter(|&index| index < operands.len())
+            .ok_or_else(|| CompileError::InvalidAsmOperand {
+                operand: format!("%{}", digits),
+                span: span.clone(),
+            })?;
+        match operands[index] {
+            AsmOperand::Register(reg) => {
+                result.push_str(REGS[modifier.unwrap_or(widths[index])][reg]);
+            }
+            AsmOperand::Immediate(value) => write!(result, "{}", value)?,
+        }
+    }
+    Ok(result)
+}
+
+/// Emits an `asm` statement. Output operands are stored back through their
+/// addresses, which stay on the stack while the template runs. `span` is the
+/// statement, for errors.
+pub fn gen_asm(asm: &InlineAsm, span: &Span, output: &mut String, labels: &mut u32) -> Result<()> {
+    let (constraints, outputs) = (&asm.constraints, asm.outputs);
+
+    // Basic asm is copied verbatim, without looking at `%`.
+    if !asm.extended {
+        write_asm(&asm.template, output)?;
+        writeln!(output, "  push 0")?;
+        return Ok(());
+    }
+
+    let mut clobbered = [false; 14];
+    for clobber in asm.clobbers.iter() {
+        match register_index(clobber) {
+            Some(reg) => clobbered[reg] = true,
+            None if clobber == "memory" || clobber == "cc" => {}
+            None => Err(CompileError::InvalidAsmClobber {
+                clobber: clobber.clone(),
+                span: span.clone(),
+            })?,
+        }
+    }
+    let operands = asm_operands(asm, span, &clobbered)?;
+    let widths = asm
+        .operands
+        .iter()
+        .map(|child| expr_type(child).map(width))
+        .collect::<Result<Vec<_>>>()?;
+    *labels += 1;
+    let template = asm_template(&asm.template, &operands, &widths, *labels, span)?;
+
+    let saved: Vec<usize> = CALLEE_SAVED
+        .into_iter()
+        .filter(|&reg| clobbered[reg] || operands.contains(&AsmOperand::Register(reg)))
+        .collect();
+    for &reg in saved.iter() {
+        writeln!(output, "  push {}", REGS[3][reg])?;
+    }
+
+    for expr in asm.operands.iter().take(outputs) {
+        gen_lval(expr, output, labels)?;
+    }
+    let inputs = asm.operands.iter().zip(operands.iter()).skip(outputs);
+    for (expr, operand) in inputs.clone() {
+        if let AsmOperand::Register(_) = operand {
+            gen_expr(expr, output, labels)?;
+        }
+    }
+    for (_, operand) in inputs.rev() {
+        if let AsmOperand::Register(reg) = operand {
+            writeln!(output, "  pop {}", REGS[3][*reg])?;
+        }
+    }
+    for (i, operand) in operands.iter().enumerate().take(outputs) {
+        if let (true, AsmOperand::Register(reg)) = (constraints[i].starts_with('+'), operand) {
+            writeln!(
+                output,
+                "  mov {}, [rsp + {}]",
+                REGS[3][*reg],
+                (outputs - 1 - i) * 8
+            )?;
+            load_register(expr_type(&asm.operands[i])?, *reg, output)?;
+        }
+    }
+
+    write_asm(&template, output)?;
+
+    for operand in operands.iter().take(outputs) {
+        if let AsmOperand::Register(reg) = operand {
+            writeln!(output, "  push {}", REGS[3][*reg])?;
+        }
+    }
+    for i in (0..outputs).rev() {
+        writeln!(output, "  pop rdi")?;
+        writeln!(output, "  mov rax, [rsp + {}]", (outputs - 1) * 8)?;
+        store(expr_type(&asm.operands[i])?, output)?;
+    }
+    if outputs > 0 {
+        writeln!(output, "  add rsp, {}", outputs * 8)?;
+    }
+    for &reg in saved.iter().rev() {
+        writeln!(output, "  pop {}", REGS[3][reg])?;
+    }
+
+    writeln!(output, "  push 0")?;
+    Ok(())
+}
+
+fn write_asm(template: &str, output: &mut String) -> Result<()> {
+    for line in template
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+    {
+        writeln!(output, "  {}", line)?;
+    }
+    Ok(())
+}
+
+/// Emits a function whose frame holds `frame_size` bytes of local variables.
+pub fn gen_function(
+    symbol: &str,
+    params: &[Decl],
+    body: &[&Stmt],
+    frame_size: u32,
+    output: &mut String,
+    labels: &mut u32,
+) -> Result<()> {
+    writeln!(output, ".globl {}", symbol)?;
+    writeln!(output, "{}:", symbol)?;
 
     writeln!(output, "  push rbp")?;
     writeln!(output, "  mov rbp, rsp")?;
-    writeln!(output, "  sub rsp, {}", variable_len * 8)?;
+    writeln!(output, "  sub rsp, {}", frame_size.next_multiple_of(16))?;
 
-    for node in program {
-        gen(&node, &mut output)?;
+    for (i, param) in params.iter().enumerate() {
+        let register = arg_register(&param.ty, i);
+        writeln!(output, "  mov [rbp - {}], {}", param.offset, register)?;
+    }
+
+    for stmt in body {
+        gen_stmt(stmt, output, labels)?;
         writeln!(output, "  pop rax")?;
     }
 
     writeln!(output, "  mov rsp, rbp")?;
     writeln!(output, "  pop rbp")?;
     writeln!(output, "  ret")?;
+    Ok(())
+}
+
+pub fn compile_from_source(source: Vec<String>) -> Result<String> {
+    Ok(compile_with_warnings(source)?.0)
+}
+
+/// Compiles `source`, failing with the first error found.
+pub fn compile_with_warnings(source: Vec<String>) -> Result<(String, Vec<CompileWarning>)> {
+    compile(&mut Preprocessor::new(), "", source, 1).map_err(|mut errors| errors.remove(0))
+}
+
+/// Compiles the program in `file`. Statements outside of any function make
+/// up the body of an implicit `main`. Parsing goes on after a syntax error,
+/// so that up to `max_errors` of them are reported together.
+pub fn compile_with_preprocessor(
+    preprocessor: &mut Preprocessor,
+    file: &str,
+    source: Vec<String>,
+    max_errors: usize,
+) -> Result<(String, Vec<CompileWarning>), Diagnostics> {
+    compile(preprocessor, file, source, max_errors)
+        .map_err(|errors| errors.iter().map(Diagnostic::from).collect())
+}
+
+fn compile(
+    preprocessor: &mut Preprocessor,
+    file: &str,
+    source: Vec<String>,
+    max_errors: usize,
+) -> Result<(String, Vec<CompileWarning>), Vec<anyhow::Error>> {
+    let mut ts = lex(preprocessor, file, source).map_err(|e| vec![e])?;
+    let mut program = parse(&mut ts, max_errors)?;
+    let warnings = check(&mut program).map_err(|e| vec![e])?;
+    let output = gen_program(&program).map_err(|e| vec![e])?;
+
+    Ok((output, warnings))
+}
+
+/// The first phase: preprocesses `source` and picks out its keywords.
+pub fn lex(
+    preprocessor: &mut Preprocessor,
+    file: &str,
+    source: Vec<String>,
+) -> Result<TokenStream> {
+    classify(preprocessor.preprocess(file, source)?)
+}
+
+/// The second phase: parses the tokens, reporting up to `max_errors`
+/// syntax errors.
+pub fn parse(ts: &mut TokenStream, max_errors: usize) -> Result<Vec<Item>, Vec<anyhow::Error>> {
+    ts.set_max_errors(max_errors);
+    let (program, errors) = program_with_recovery(ts);
+    if errors.is_empty() {
+        Ok(program)
+    } else {
+        Err(errors)
+    }
+}
+
+/// The third phase: resolves names, adds types and looks for likely
+/// mistakes. There is no intermediate representation; the program is
+/// ready for `gen_program` afterwards.
+pub fn check(program: &mut [Item]) -> Result<Vec<CompileWarning>> {
+    let mut warnings = Vec::new();
+    analyze(program, &mut warnings)?;
+    check_program(program, &mut warnings);
+    Ok(warnings)
+}
+
+/// The last phase: generates the assembly for a program that has been
+/// checked.
+pub fn gen_program(program: &[Item]) -> Result<String> {
+    let mut output = String::new();
+    let mut labels = 0;
+
+    writeln!(output, ".intel_syntax noprefix")?;
+
+    let mut main = Vec::new();
+    for item in program {
+        match item {
+            Item::Function(function) => {
+                let body: Vec<&Stmt> = function.body.iter().collect();
+                let frame_size = frame_size(&function.params, &body);
+                gen_function(
+                    &function.name,
+                    &function.params,
+                    &body,
+                    frame_size,
+                    &mut output,
+                    &mut labels,
+                )?;
+            }
+            Item::Stmt(Stmt {
+                kind: StmtKind::Decl(decl),
+                ..
+            }) if decl.ty.is_func() => {}
+            Item::Stmt(stmt) => main.push(stmt),
+        }
+    }
+
+    if !main.is_empty() {
+        let defined_main = program.iter().find_map(|item| match item {
+            Item::Function(function) if function.name == "main" => Some(function),
+            _ => None,
+        });
+        if let Some(function) = defined_main {
+            Err(CompileError::Redefinition {
+                name: "main".to_string(),
+                span: function.span.clone(),
+            })?;
+        }
+        let frame_size = frame_size(&[], &main);
+        gen_function("main", &[], &main, frame_size, &mut output, &mut labels)?;
+    }
+
+    // Nothing needs an executable stack, which the linker would otherwise
+    // assume and warn about.
+    writeln!(output, ".section .note.GNU-stack,\"\",@progbits")?;
+    Ok(output)
+}
+
+/// The bytes of stack a function needs for the variables `sema` gave slots
+/// in its parameters and body.
+fn frame_size(params: &[Decl], body: &[&Stmt]) -> u32 {
+    let mut deepest = DeepestSlot(0);
+    for param in params {
+        deepest.visit_decl(param);
+    }
+    for stmt in body {
+        deepest.visit_stmt(stmt);
+    }
+    deepest.0
+}
+
+struct DeepestSlot(u32);
+
+impl Visitor for DeepestSlot {
+    fn visit_decl(&mut self, decl: &Decl) {
+        self.0 = self.0.max(decl.offset);
+        walk_decl(self, decl);
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        if let ExprKind::Var { offset, .. } = expr.kind {
+            self.0 = self.0.max(offset);
+        }
+        walk_expr(self, expr);
+    }
+}
+
+#[test]
+fn test_volatile_access() {
+    let output =
+        compile_from_source(vec!["volatile int v = 1; v = v + v; v;".to_string()]).unwrap();
+    assert_eq!(output.matches("movsxd rax, dword ptr [rax]").count(), 3);
+    assert_eq!(output.matches("mov [rax], edi").count(), 2);
+}
+
+#[test]
+fn test_inline_asm() {
+    let output = compile_from_source(vec![
+        r#"int x; int y = 2; asm("lea %0, [%1 + %1]%%" : "=r"(x) : "r"(y) : "rbx");"#.to_string(),
+    ])
+    .unwrap();
+    assert!(output.contains("  lea r8d, [r9d + r9d]%\n"));
+    assert_eq!(output.matches("push rbx").count(), 1);
+    assert_eq!(output.matches("pop rbx").count(), 1);
 
-    return Ok(output);
+    let error = compile_from_source(vec![r#"int x; asm("" : "=a"(x), "=a"(x));"#.to_string()]);
+    assert!(error.is_err());
 }
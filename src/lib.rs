@@ -1,4 +1,15 @@
+pub mod ast;
+pub mod compiler;
+pub mod diagnostic;
+pub mod explain;
+pub mod format;
 pub mod gen;
 pub mod node;
+pub mod preprocess;
+pub mod print;
 pub mod result;
+pub mod sema;
 pub mod token;
+pub mod types;
+pub mod visit;
+pub mod warning;
@@ -1,287 +1,871 @@
+use crate::ast::{BinaryOp, Decl, Expr, ExprKind, FunctionDef, InlineAsm, Item, Stmt, StmtKind};
 use crate::result::CompileError;
-use crate::token::{TokenKind, TokenStream};
+use crate::token::{Span, TokenKind, TokenStream};
+use crate::types::{Type, TypeKind};
 use anyhow::Result;
 
-#[derive(Debug, PartialEq)]
-pub struct Node {
-    pub kind: TokenKind,
-    pub lhs: Option<Box<Node>>,
-    pub rhs: Option<Box<Node>>,
+/// The declarator of a declaration, e.g. `*op` or `(*op)(int, int)`.
+#[derive(Debug)]
+pub struct Declarator {
+    pub ty: Type,
+    pub name: Option<String>,
+    /// Named parameters, if the declarator declares a function.
+    pub params: Vec<Decl>,
 }
 
-impl Node {
-    pub fn kind(&self) -> TokenKind {
-        self.kind.clone()
+/// Parses the program, failing with its first syntax error.
+pub fn program(tokenstream: &mut TokenStream) -> Result<Vec<Item>> {
+    let (items, mut errors) = program_with_recovery(tokenstream);
+    if errors.is_empty() {
+        Ok(items)
+    } else {
+        Err(errors.remove(0))
     }
+}
 
-    pub fn lhs(&self) -> Option<&Box<Node>> {
-        self.lhs.as_ref()
+/// Parses the program, carrying on after each syntax error from the next
+/// statement, and returns every error found. Gives up after the stream's
+/// `max_errors`, with a `TooManyErrors` last.
+pub fn program_with_recovery(tokenstream: &mut TokenStream) -> (Vec<Item>, Vec<anyhow::Error>) {
+    let mut items = Vec::new();
+
+    while !tokenstream.is_empty() {
+        let item = if tokenstream
+            .peek_kind(0)
+            .is_some_and(|kind| kind.is_type_name())
+        {
+            external_declaration(tokenstream).map(Some)
+        } else {
+            stmt(tokenstream).map(|stmt| stmt.map(Item::Stmt))
+        };
+        match item {
+            Ok(item) => items.extend(item),
+            Err(error) => {
+                // Blocks recover by themselves and only give up once there
+                // are too many errors.
+                let gave_up = matches!(
+                    error.downcast_ref::<CompileError>(),
+                    Some(CompileError::TooManyErrors(_))
+                );
+                let result = if gave_up {
+                    Err(error)
+                } else {
+                    tokenstream.recover(error)
+                };
+                if let Err(error) = result {
+                    let mut errors = tokenstream.take_errors();
+                    errors.push(error);
+                    return (items, errors);
+                }
+                // A `}` without a block to close.
+                tokenstream.consume(TokenKind::RCurlyBracket);
+            }
+        }
     }
 
-    pub fn rhs(&self) -> Option<&Box<Node>> {
-        self.rhs.as_ref()
-    }
+    (items, tokenstream.take_errors())
+}
 
-    pub fn op_node(kind: TokenKind, lhs: Box<Node>, rhs: Box<Node>) -> Box<Node> {
-        Box::new(Self {
-            kind,
-            lhs: Some(lhs),
-            rhs: Some(rhs),
-        })
+/// A function definition, or a declaration like any other statement.
+pub fn external_declaration(tokenstream: &mut TokenStream) -> Result<Item> {
+    let start = tokenstream.checkpoint();
+    let span = tokenstream.peek_span();
+    let base = declspec(tokenstream)?;
+    let declarator = declarator(tokenstream, base)?;
+
+    if declarator.ty.is_func() && tokenstream.peek_kind(0) == Some(TokenKind::LCurlyBracket) {
+        return Ok(Item::Function(function_definition(
+            tokenstream,
+            span,
+            declarator,
+        )?));
     }
 
-    pub fn num_node(val: u32) -> Box<Node> {
-        Box::new(Self {
-            kind: TokenKind::Num(val),
-            lhs: None,
-            rhs: None,
-        })
-    }
+    tokenstream.rewind(start);
+    let decl = declaration(tokenstream)?;
+    Ok(Item::Stmt(Stmt::new(
+        StmtKind::Decl(decl),
+        tokenstream.span_from(&span),
+    )))
+}
 
-    pub fn variable_node(symbol: String, offset: u32) -> Box<Node> {
-        Box::new(Self {
-            kind: TokenKind::LocalVar { symbol, offset },
-            lhs: None,
-            rhs: None,
-        })
+/// The body of a function whose declarator, starting at `start`, has been
+/// parsed.
+pub fn function_definition(
+    tokenstream: &mut TokenStream,
+    start: Span,
+    declarator: Declarator,
+) -> Result<FunctionDef> {
+    let name = declarator
+        .name
+        .ok_or_else(|| CompileError::MissingFunctionName {
+            stop: tokenstream.token(),
+        })?;
+    if let TypeKind::Func {
+        params: Some(params),
+        ..
+    } = &declarator.ty.kind
+    {
+        if params.len() != declarator.params.len() {
+            Err(CompileError::ParameterNameOmitted {
+                stop: tokenstream.token(),
+            })?;
+        }
     }
+
+    let body = block_items(tokenstream)?;
+    Ok(FunctionDef {
+        name,
+        ty: declarator.ty,
+        params: declarator.params,
+        body,
+        span: tokenstream.span_from(&start),
+    })
 }
 
-pub fn program(tokenstream: &mut TokenStream) -> Result<Vec<Box<Node>>> {
-    let mut nodes = Vec::new();
+pub fn block(tokenstream: &mut TokenStream) -> Result<Stmt> {
+    let start = tokenstream.peek_span();
+    let stmts = block_items(tokenstream)?;
+    Ok(Stmt::new(
+        StmtKind::Block(stmts),
+        tokenstream.span_from(&start),
+    ))
+}
 
-    while let Some(node) = stmt(tokenstream)? {
-        nodes.push(node);
+/// `{ stmt* }`, recovering from errors in the statements.
+fn block_items(tokenstream: &mut TokenStream) -> Result<Vec<Stmt>> {
+    tokenstream.expect(TokenKind::LCurlyBracket)?;
+    let open = tokenstream.token();
+
+    let mut stmts = Vec::new();
+    while !tokenstream.consume(TokenKind::RCurlyBracket) {
+        match stmt(tokenstream) {
+            Ok(Some(stmt)) => stmts.push(stmt),
+            Ok(None) => {
+                tokenstream.expect_closing(TokenKind::RCurlyBracket, &open)?;
+            }
+            Err(error) => tokenstream.recover(error)?,
+        }
     }
 
-    Ok(nodes)
+    Ok(stmts)
 }
 
-pub fn stmt(tokenstream: &mut TokenStream) -> Result<Option<Box<Node>>> {
+pub fn stmt(tokenstream: &mut TokenStream) -> Result<Option<Stmt>> {
     if tokenstream.is_empty() {
         return Ok(None);
     }
+    let start = tokenstream.peek_span();
 
     if tokenstream.consume(TokenKind::If) {
-        if !tokenstream.consume(TokenKind::LRoundBracket) {
-            return Err(CompileError::ParseError(Some("stmt LRoundBracket")))?;
-        }
-
-        let lhs = Some(expr(tokenstream)?);
-
-        if !tokenstream.consume(TokenKind::RRoundBracket) {
-            return Err(CompileError::ParseError(Some("stmt RRoundBracket")))?;
-        }
+        tokenstream.expect(TokenKind::LRoundBracket)?;
+        let open = tokenstream.token();
+        let cond = expr(tokenstream)?;
+        tokenstream.expect_closing(TokenKind::RRoundBracket, &open)?;
+
+        let then = required_stmt(tokenstream)?;
+        let otherwise = if tokenstream.consume(TokenKind::Else) {
+            Some(Box::new(required_stmt(tokenstream)?))
+        } else {
+            None
+        };
 
-        let then = match stmt(tokenstream)? {
-            Some(node) => Some(node),
-            None => Err(CompileError::ParseError(Some("expect rhs")))?,
+        let kind = StmtKind::If {
+            cond,
+            then: Box::new(then),
+            otherwise,
         };
+        return Ok(Some(Stmt::new(kind, tokenstream.span_from(&start))));
+    }
 
-        let rhs = if tokenstream.consume(TokenKind::Else) {
-            Some(Box::new(Node {
-                kind: TokenKind::Else,
-                lhs: then,
-                rhs: match stmt(tokenstream)? {
-                    Some(node) => Some(node),
-                    None => Err(CompileError::ParseError(Some("expect rhs")))?,
-                },
-            }))
+    if tokenstream.peek_kind(0) == Some(TokenKind::LCurlyBracket) {
+        return Ok(Some(block(tokenstream)?));
+    }
+
+    let kind = if tokenstream.consume(TokenKind::Return) {
+        let value = if tokenstream.consume(TokenKind::Semicolon) {
+            None
         } else {
-            then
+            let value = expr(tokenstream)?;
+            tokenstream.expect(TokenKind::Semicolon)?;
+            Some(value)
         };
+        StmtKind::Return(value)
+    } else if tokenstream.consume(TokenKind::Asm) {
+        StmtKind::Asm(asm_stmt(tokenstream)?)
+    } else if tokenstream
+        .peek_kind(0)
+        .is_some_and(|kind| kind.is_type_name())
+    {
+        StmtKind::Decl(declaration(tokenstream)?)
+    } else {
+        let value = expr(tokenstream)?;
+        tokenstream.expect(TokenKind::Semicolon)?;
+        StmtKind::Expr(value)
+    };
 
-        let node = Box::new(Node {
-            kind: TokenKind::If,
-            lhs,
-            rhs,
-        });
+    Ok(Some(Stmt::new(kind, tokenstream.span_from(&start))))
+}
 
-        return Ok(Some(node));
+/// A statement that has to be there, such as the body of an `if`.
+fn required_stmt(tokenstream: &mut TokenStream) -> Result<Stmt> {
+    match stmt(tokenstream)? {
+        Some(stmt) => Ok(stmt),
+        None => Err(CompileError::ExpectedStatement {
+            stop: tokenstream.token(),
+        })?,
     }
+}
 
-    if tokenstream.consume(TokenKind::Return) {
-        let mut node = Box::new(Node {
-            kind: TokenKind::Return,
-            lhs: None,
-            rhs: None,
-        });
-        node.lhs = Some(match expr(tokenstream) {
-            Ok(node) => node,
-            Err(err) => return Err(err)?,
-        });
-
-        if let Err(err) = tokenstream.expect(TokenKind::Semicolon) {
-            return Err(err)?;
+/// The rest of an `asm` statement after the keyword. A `volatile` is accepted
+/// and ignored, since inline assembly is never moved or removed anyway.
+pub fn asm_stmt(tokenstream: &mut TokenStream) -> Result<InlineAsm> {
+    tokenstream.consume(TokenKind::Volatile);
+    tokenstream.expect(TokenKind::LRoundBracket)?;
+    let open = tokenstream.token();
+    let template = string_literal(tokenstream)?;
+
+    let mut constraints = Vec::new();
+    let mut operands = Vec::new();
+    let mut outputs = 0;
+    let mut clobbers = Vec::new();
+    let extended = tokenstream.consume(TokenKind::Colon);
+    if extended {
+        (constraints, operands) = asm_operands(tokenstream)?.into_iter().unzip();
+        outputs = constraints.len();
+        if tokenstream.consume(TokenKind::Colon) {
+            let (inputs, exprs): (Vec<_>, Vec<_>) = asm_operands(tokenstream)?.into_iter().unzip();
+            constraints.extend(inputs);
+            operands.extend(exprs);
+            if tokenstream.consume(TokenKind::Colon)
+                && tokenstream.peek_kind(0) != Some(TokenKind::RRoundBracket)
+            {
+                clobbers.push(tokenstream.expect_string()?);
+                while tokenstream.consume(TokenKind::Comma) {
+                    clobbers.push(tokenstream.expect_string()?);
+                }
+            }
         }
-
-        return Ok(Some(node));
     }
+    tokenstream.expect_closing(TokenKind::RRoundBracket, &open)?;
+    tokenstream.expect(TokenKind::Semicolon)?;
+
+    Ok(InlineAsm {
+        template,
+        constraints,
+        operands,
+        outputs,
+        clobbers,
+        extended,
+    })
+}
 
-    let node = match expr(tokenstream) {
-        Ok(node) => node,
-        Err(err) => return Err(err)?,
-    };
-
-    if let Err(err) = tokenstream.expect(TokenKind::Semicolon) {
-        return Err(err)?;
+/// A possibly empty list of `"constraint" (expr)` operands.
+fn asm_operands(tokenstream: &mut TokenStream) -> Result<Vec<(String, Expr)>> {
+    let mut operands = Vec::new();
+    if !matches!(tokenstream.peek_kind(0), Some(TokenKind::Str(_))) {
+        return Ok(operands);
+    }
+    loop {
+        let constraint = tokenstream.expect_string()?;
+        tokenstream.expect(TokenKind::LRoundBracket)?;
+        operands.push((constraint, expr(tokenstream)?));
+        tokenstream.expect(TokenKind::RRoundBracket)?;
+        if !tokenstream.consume(TokenKind::Comma) {
+            return Ok(operands);
+        }
     }
+}
 
-    Ok(Some(node))
+/// Adjacent string literals are concatenated, e.g. `"rdtsc\n" "nop"`.
+fn string_literal(tokenstream: &mut TokenStream) -> Result<String> {
+    let mut string = tokenstream.expect_string()?;
+    while let Some(TokenKind::Str(next)) = tokenstream.peek_kind(0) {
+        tokenstream.expect_string()?;
+        string.push_str(&next);
+    }
+    Ok(string)
 }
 
-pub fn expr(tokenstream: &mut TokenStream) -> Result<Box<Node>> {
-    Ok(assign(tokenstream)?)
+pub fn expr(tokenstream: &mut TokenStream) -> Result<Expr> {
+    assign(tokenstream)
 }
 
-pub fn assign(tokenstream: &mut TokenStream) -> Result<Box<Node>> {
-    let mut node = equality(tokenstream)?;
-    if tokenstream.consume(TokenKind::Assign) {
-        node = Node::op_node(TokenKind::Assign, node, assign(tokenstream)?);
+pub fn assign(tokenstream: &mut TokenStream) -> Result<Expr> {
+    let lhs = equality(tokenstream)?;
+    if !tokenstream.consume(TokenKind::Assign) {
+        return Ok(lhs);
     }
-    Ok(node)
+    let rhs = assign(tokenstream)?;
+    let span = lhs.span.to(&rhs.span);
+    Ok(Expr::new(
+        ExprKind::Assign {
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        },
+        span,
+    ))
 }
-pub fn equality(tokenstream: &mut TokenStream) -> Result<Box<Node>> {
+
+pub fn equality(tokenstream: &mut TokenStream) -> Result<Expr> {
     let mut node = relational(tokenstream)?;
 
     loop {
         if tokenstream.consume(TokenKind::Equal) {
-            node = Node::op_node(TokenKind::Equal, node, relational(tokenstream)?);
+            node = Expr::binary(BinaryOp::Equal, node, relational(tokenstream)?);
         } else if tokenstream.consume(TokenKind::NEqual) {
-            node = Node::op_node(TokenKind::NEqual, node, relational(tokenstream)?);
+            node = Expr::binary(BinaryOp::NEqual, node, relational(tokenstream)?);
         } else {
             return Ok(node);
         }
     }
 }
 
-pub fn relational(tokenstream: &mut TokenStream) -> Result<Box<Node>> {
+pub fn relational(tokenstream: &mut TokenStream) -> Result<Expr> {
     let mut node = add(tokenstream)?;
     loop {
         if tokenstream.consume(TokenKind::Less) {
-            node = Node::op_node(TokenKind::Less, node, add(tokenstream)?);
+            node = Expr::binary(BinaryOp::Less, node, add(tokenstream)?);
         } else if tokenstream.consume(TokenKind::Greater) {
-            node = Node::op_node(TokenKind::Less, add(tokenstream)?, node);
+            node = swapped(BinaryOp::Less, node, add(tokenstream)?);
         } else if tokenstream.consume(TokenKind::LessOrEqual) {
-            node = Node::op_node(TokenKind::LessOrEqual, node, add(tokenstream)?);
+            node = Expr::binary(BinaryOp::LessOrEqual, node, add(tokenstream)?);
         } else if tokenstream.consume(TokenKind::GreaterOrEqual) {
-            node = Node::op_node(TokenKind::LessOrEqual, add(tokenstream)?, node);
+            node = swapped(BinaryOp::LessOrEqual, node, add(tokenstream)?);
         } else {
             return Ok(node);
         }
     }
 }
 
-pub fn add(tokenstream: &mut TokenStream) -> Result<Box<Node>> {
+/// `lhs > rhs` as `rhs < lhs`, spanning both operands as written.
+fn swapped(op: BinaryOp, lhs: Expr, rhs: Expr) -> Expr {
+    let span = lhs.span.to(&rhs.span);
+    Expr {
+        span,
+        ..Expr::binary(op, rhs, lhs)
+    }
+}
+
+pub fn add(tokenstream: &mut TokenStream) -> Result<Expr> {
     let mut node = mul(tokenstream)?;
     loop {
         if tokenstream.consume(TokenKind::Add) {
-            node = Node::op_node(TokenKind::Add, node, mul(tokenstream)?);
+            node = Expr::binary(BinaryOp::Add, node, mul(tokenstream)?);
         } else if tokenstream.consume(TokenKind::Sub) {
-            node = Node::op_node(TokenKind::Sub, node, mul(tokenstream)?);
+            node = Expr::binary(BinaryOp::Sub, node, mul(tokenstream)?);
         } else {
             return Ok(node);
         }
     }
 }
 
-pub fn mul(tokenstream: &mut TokenStream) -> Result<Box<Node>> {
+pub fn mul(tokenstream: &mut TokenStream) -> Result<Expr> {
     let mut node = unary(tokenstream)?;
     loop {
         if tokenstream.consume(TokenKind::Mul) {
-            node = Node::op_node(TokenKind::Mul, node, unary(tokenstream)?);
+            node = Expr::binary(BinaryOp::Mul, node, unary(tokenstream)?);
         } else if tokenstream.consume(TokenKind::Div) {
-            node = Node::op_node(TokenKind::Div, node, unary(tokenstream)?);
+            node = Expr::binary(BinaryOp::Div, node, unary(tokenstream)?);
         } else {
             return Ok(node);
         }
     }
 }
 
-pub fn unary(tokenstream: &mut TokenStream) -> Result<Box<Node>> {
+pub fn unary(tokenstream: &mut TokenStream) -> Result<Expr> {
+    let start = tokenstream.peek_span();
     if tokenstream.consume(TokenKind::Add) {
-        return primary(tokenstream);
+        return unary(tokenstream);
     }
     if tokenstream.consume(TokenKind::Sub) {
-        return Ok(Node::op_node(
-            TokenKind::Sub,
-            Node::num_node(0),
-            primary(tokenstream)?,
+        let operand = unary(tokenstream)?;
+        let span = start.to(&operand.span);
+        return Ok(Expr {
+            span,
+            ..Expr::binary(BinaryOp::Sub, Expr::num(0, start), operand)
+        });
+    }
+    if tokenstream.consume(TokenKind::Mul) {
+        let operand = Box::new(unary(tokenstream)?);
+        return Ok(Expr::new(
+            ExprKind::Deref(operand),
+            tokenstream.span_from(&start),
+        ));
+    }
+    if tokenstream.consume(TokenKind::Addr) {
+        let operand = Box::new(unary(tokenstream)?);
+        return Ok(Expr::new(
+            ExprKind::Addr(operand),
+            tokenstream.span_from(&start),
         ));
     }
-    primary(tokenstream)
+
+    // "(" followed by a type name is a cast rather than a parenthesized expression.
+    if tokenstream.peek_kind(0) == Some(TokenKind::LRoundBracket)
+        && tokenstream
+            .peek_kind(1)
+            .is_some_and(|kind| kind.is_type_name())
+    {
+        tokenstream.expect(TokenKind::LRoundBracket)?;
+        let ty = type_name(tokenstream)?;
+        tokenstream.expect(TokenKind::RRoundBracket)?;
+
+        let operand = Box::new(unary(tokenstream)?);
+        return Ok(Expr {
+            ty: Some(ty),
+            ..Expr::new(ExprKind::Cast(operand), tokenstream.span_from(&start))
+        });
+    }
+
+    postfix(tokenstream)
 }
 
-pub fn primary(tokenstream: &mut TokenStream) -> Result<Box<Node>> {
+pub fn postfix(tokenstream: &mut TokenStream) -> Result<Expr> {
+    let start = tokenstream.peek_span();
+    let mut node = primary(tokenstream)?;
+
+    while tokenstream.consume(TokenKind::LRoundBracket) {
+        let open = tokenstream.token();
+        let mut args = Vec::new();
+        if !tokenstream.consume(TokenKind::RRoundBracket) {
+            loop {
+                args.push(assign(tokenstream)?);
+                if !tokenstream.consume(TokenKind::Comma) {
+                    break;
+                }
+            }
+            tokenstream.expect_closing(TokenKind::RRoundBracket, &open)?;
+        }
+
+        let callee = Box::new(node);
+        node = Expr::new(
+            ExprKind::Call { callee, args },
+            tokenstream.span_from(&start),
+        );
+    }
+
+    Ok(node)
+}
+
+pub fn primary(tokenstream: &mut TokenStream) -> Result<Expr> {
     if tokenstream.consume(TokenKind::LRoundBracket) {
+        let open = tokenstream.token();
         let node = expr(tokenstream)?;
-        tokenstream.expect(TokenKind::RRoundBracket)?;
+        tokenstream.expect_closing(TokenKind::RRoundBracket, &open)?;
         return Ok(node);
     }
 
-    if let Ok(TokenKind::LocalVar { symbol, offset }) = tokenstream.expect_local_variable() {
-        return Ok(Node::variable_node(symbol, offset));
+    let start = tokenstream.peek_span();
+    if let Some(TokenKind::Str(_)) = tokenstream.peek_kind(0) {
+        let text = string_literal(tokenstream)?;
+        return Ok(Expr::new(
+            ExprKind::Str(text),
+            tokenstream.span_from(&start),
+        ));
+    }
+    if let Ok(name) = tokenstream.expect_identifier() {
+        Ok(Expr::var(name, start))
+    } else {
+        Ok(Expr::num(tokenstream.expect_number()?, start))
+    }
+}
+
+/// `type declarator ("=" assign)? ";"`
+pub fn declaration(tokenstream: &mut TokenStream) -> Result<Decl> {
+    let start = tokenstream.peek_span();
+    let base = declspec(tokenstream)?;
+    let declarator = declarator(tokenstream, base)?;
+    let Some(name) = declarator.name else {
+        Err(CompileError::Expected {
+            stop: tokenstream.token(),
+            expect: TokenKind::Ident(String::new()),
+        })?
+    };
+    if declarator.ty.is_void() {
+        Err(CompileError::VoidVariable {
+            name: name.clone(),
+            span: tokenstream.span_from(&start),
+        })?;
+    }
+
+    let init = if tokenstream.consume(TokenKind::Assign) {
+        Some(assign(tokenstream)?)
+    } else {
+        None
+    };
+    tokenstream.expect(TokenKind::Semicolon)?;
+
+    Ok(Decl {
+        name,
+        ty: declarator.ty,
+        offset: 0,
+        init,
+        span: tokenstream.span_from(&start),
+    })
+}
+
+/// A type name as written in a cast, e.g. `int (*)(int)`.
+pub fn type_name(tokenstream: &mut TokenStream) -> Result<Type> {
+    let base = declspec(tokenstream)?;
+    let declarator = declarator(tokenstream, base)?;
+    if declarator.name.is_some() {
+        Err(CompileError::Unexpected {
+            stop: tokenstream.token(),
+            expect: TokenKind::RRoundBracket,
+            result: tokenstream.token().kind(),
+        })?;
+    }
+    Ok(declarator.ty)
+}
+
+/// declarator := pointers ("(" declarator ")" | ident)? type-suffix
+///
+/// The name is optional so that the same function parses abstract
+/// declarators in casts and parameter lists.
+pub fn declarator(tokenstream: &mut TokenStream, ty: Type) -> Result<Declarator> {
+    let ty = pointers(tokenstream, ty);
+
+    let nested = tokenstream.peek_kind(0) == Some(TokenKind::LRoundBracket)
+        && matches!(
+            tokenstream.peek_kind(1),
+            Some(TokenKind::Mul | TokenKind::LRoundBracket | TokenKind::Ident(_))
+        );
+    if nested {
+        // The suffix after the parentheses applies before the nested
+        // declarator, so parse it first and then come back.
+        let start = tokenstream.checkpoint();
+        tokenstream.expect(TokenKind::LRoundBracket)?;
+        let mut depth = 1;
+        while depth > 0 {
+            let Some(kind) = tokenstream.peek_kind(0) else {
+                return Err(CompileError::Expected {
+                    stop: tokenstream.token(),
+                    expect: TokenKind::RRoundBracket,
+                })?;
+            };
+            match kind {
+                TokenKind::LRoundBracket => depth += 1,
+                TokenKind::RRoundBracket => depth -= 1,
+                _ => {}
+            }
+            tokenstream.expect(kind)?;
+        }
+        let (ty, _) = type_suffix(tokenstream, ty)?;
+        let end = tokenstream.checkpoint();
+
+        tokenstream.rewind(start + 1);
+        let declarator = declarator(tokenstream, ty)?;
+        tokenstream.expect(TokenKind::RRoundBracket)?;
+        tokenstream.rewind(end);
+        return Ok(declarator);
+    }
+
+    let name = match tokenstream.peek_kind(0) {
+        Some(TokenKind::Ident(_)) => Some(tokenstream.expect_identifier()?),
+        _ => None,
+    };
+    let (ty, params) = type_suffix(tokenstream, ty)?;
+
+    Ok(Declarator { ty, name, params })
+}
+
+/// type-suffix := ("(" ("void" | param ("," param)*)? ")")?
+pub fn type_suffix(tokenstream: &mut TokenStream, ty: Type) -> Result<(Type, Vec<Decl>)> {
+    if !tokenstream.consume(TokenKind::LRoundBracket) {
+        return Ok((ty, Vec::new()));
+    }
+    if tokenstream.consume(TokenKind::RRoundBracket) {
+        return Ok((Type::func(ty, None), Vec::new()));
+    }
+    if tokenstream.peek_kind(0) == Some(TokenKind::Void)
+        && tokenstream.peek_kind(1) == Some(TokenKind::RRoundBracket)
+    {
+        tokenstream.expect(TokenKind::Void)?;
+        tokenstream.expect(TokenKind::RRoundBracket)?;
+        return Ok((Type::func(ty, Some(Vec::new())), Vec::new()));
+    }
+
+    let mut types = Vec::new();
+    let mut params = Vec::new();
+    loop {
+        let start = tokenstream.peek_span();
+        let base = declspec(tokenstream)?;
+        let declarator = declarator(tokenstream, base)?;
+        // A parameter of function type is adjusted to a function pointer.
+        let param_ty = if declarator.ty.is_func() {
+            Type::pointer_to(declarator.ty)
+        } else {
+            declarator.ty
+        };
+
+        if let Some(name) = declarator.name {
+            params.push(Decl {
+                name,
+                ty: param_ty.clone(),
+                offset: 0,
+                init: None,
+                span: tokenstream.span_from(&start),
+            });
+        }
+        types.push(param_ty);
+
+        if !tokenstream.consume(TokenKind::Comma) {
+            break;
+        }
+    }
+    tokenstream.expect(TokenKind::RRoundBracket)?;
+
+    Ok((Type::func(ty, Some(types)), params))
+}
+
+/// Parses a sequence of integer type specifiers and qualifiers such as
+/// `const unsigned long int`.
+pub fn declspec(tokenstream: &mut TokenStream) -> Result<Type> {
+    let mut signed = None;
+    let mut size = None;
+    let mut int = false;
+    let mut is_const = false;
+    let mut is_volatile = false;
+    let mut boolean = false;
+    let mut void = false;
+
+    while let Some(kind) = tokenstream.peek_kind(0) {
+        if !kind.is_type_name() {
+            break;
+        }
+        tokenstream.expect(kind.clone())?;
+
+        let valid = match kind {
+            // Repeated qualifiers are allowed and behave as if they appeared once.
+            TokenKind::Const => {
+                is_const = true;
+                true
+            }
+            TokenKind::Volatile => {
+                is_volatile = true;
+                true
+            }
+            // `_Bool` cannot be combined with any other type specifier.
+            TokenKind::Bool if !boolean && !void && signed.is_none() && size.is_none() && !int => {
+                boolean = true;
+                true
+            }
+            TokenKind::Void if !void && !boolean && signed.is_none() && size.is_none() && !int => {
+                void = true;
+                true
+            }
+            _ if boolean || void => false,
+            TokenKind::Signed | TokenKind::Unsigned if signed.is_none() => {
+                signed = Some(kind == TokenKind::Signed);
+                true
+            }
+            TokenKind::Int if !int && size != Some(1) => {
+                int = true;
+                true
+            }
+            TokenKind::Char if size.is_none() && !int => {
+                size = Some(1);
+                true
+            }
+            TokenKind::Short if size.is_none() => {
+                size = Some(2);
+                true
+            }
+            // `long long` is the same as `long`.
+            TokenKind::Long if matches!(size, None | Some(8)) => {
+                size = Some(8);
+                true
+            }
+            _ => false,
+        };
+        if !valid {
+            Err(CompileError::InvalidTypeSpecifier {
+                stop: tokenstream.token(),
+            })?;
+        }
+    }
+
+    let mut ty = if boolean {
+        Type::BOOL
+    } else if void {
+        Type::VOID
     } else {
-        return Ok(Node::num_node(tokenstream.expect_number()?));
+        Type::integer(size.unwrap_or(4), signed.unwrap_or(true))
+    };
+    ty.is_const = is_const;
+    ty.is_volatile = is_volatile;
+    Ok(ty)
+}
+
+/// Parses `"*" ("const" | "volatile")*` repeatedly, e.g. `* const *`.
+pub fn pointers(tokenstream: &mut TokenStream, mut ty: Type) -> Type {
+    while tokenstream.consume(TokenKind::Mul) {
+        ty = Type::pointer_to(ty);
+        loop {
+            if tokenstream.consume(TokenKind::Const) {
+                ty.is_const = true;
+            } else if tokenstream.consume(TokenKind::Volatile) {
+                ty.is_volatile = true;
+            } else {
+                break;
+            }
+        }
     }
+    ty
 }
 
 #[test]
 fn testrunner_node() -> Result<()> {
     use crate::token::tokenize;
-    let test_node = |source: &str, expect: Box<Node>| {
-        let (mut tokenstream, _) = tokenize(vec![source.to_string()]).unwrap();
+    let test_node = |source: &str, expect: StmtKind| {
+        let mut tokenstream = tokenize(vec![source.to_string()]).unwrap();
         let stmt = stmt(&mut tokenstream).unwrap().unwrap();
 
-        assert_eq!(stmt, expect, "{}", source);
+        assert_eq!(stmt.kind, expect, "{}", source);
+        assert_eq!(stmt.span.end, source.len(), "{}", source);
+    };
+    let at = |start, end| Span {
+        file: "".into(),
+        start,
+        end,
     };
 
-    let expect = Box::new(Node {
-        kind: TokenKind::Add,
-        lhs: Some(Box::new(Node {
-            kind: TokenKind::Num(1),
-            lhs: None,
-            rhs: None,
-        })),
-        rhs: Some(Box::new(Node {
-            kind: TokenKind::Num(2),
-            lhs: None,
-            rhs: None,
-        })),
-    });
+    let expect = StmtKind::Expr(Expr::binary(
+        BinaryOp::Add,
+        Expr::num(1, at(0, 1)),
+        Expr::num(2, at(2, 3)),
+    ));
     test_node("1+2;", expect);
 
-    let expect = Box::new(Node {
-        kind: TokenKind::Assign,
-        lhs: Some(Box::new(Node {
-            kind: TokenKind::LocalVar {
-                symbol: "a".to_string(),
-                offset: 8,
-            },
-            lhs: None,
-            rhs: None,
-        })),
-        rhs: Some(Box::new(Node {
-            kind: TokenKind::Num(3),
-            lhs: None,
-            rhs: None,
-        })),
-    });
+    let expect = StmtKind::Expr(Expr::new(
+        ExprKind::Assign {
+            lhs: Box::new(Expr::var("a".to_string(), at(0, 1))),
+            rhs: Box::new(Expr::num(3, at(4, 5))),
+        },
+        at(0, 5),
+    ));
     test_node("a = 3;", expect);
 
-    let expect = Box::new(Node {
-        kind: TokenKind::If,
-        lhs: Some(Box::new(Node {
-            kind: TokenKind::Num(1),
-            lhs: None,
-            rhs: None,
-        })),
-        rhs: Some(Box::new(Node {
-            kind: TokenKind::Num(4),
-            lhs: None,
-            rhs: None,
-        })),
-    });
+    let expect = StmtKind::If {
+        cond: Expr::num(1, at(4, 5)),
+        then: Box::new(Stmt::new(StmtKind::Expr(Expr::num(4, at(7, 8))), at(7, 9))),
+        otherwise: None,
+    };
     test_node("if (1) 4;", expect);
+
+    let expect = StmtKind::Expr(Expr {
+        ty: Some(Type::pointer_to(Type::integer(1, false))),
+        ..Expr::new(
+            ExprKind::Cast(Box::new(Expr::var("a".to_string(), at(17, 18)))),
+            at(0, 18),
+        )
+    });
+    test_node("(unsigned char *)a;", expect);
+
+    let expect = StmtKind::Decl(Decl {
+        name: "b".to_string(),
+        ty: Type::integer(2, true),
+        offset: 0,
+        init: Some(Expr::new(
+            ExprKind::Deref(Box::new(Expr::var("p".to_string(), at(15, 16)))),
+            at(14, 16),
+        )),
+        span: at(0, 17),
+    });
+    test_node("short int b = *p;", expect);
+
+    let expect = StmtKind::Decl(Decl {
+        name: "op".to_string(),
+        ty: Type::pointer_to(Type::func(
+            Type::INT,
+            Some(vec![Type::INT, Type::pointer_to(Type::CHAR)]),
+        )),
+        offset: 0,
+        init: None,
+        span: at(0, 23),
+    });
+    test_node("int (*op)(int, char *);", expect);
+
+    let expect = StmtKind::Asm(InlineAsm {
+        template: "cpuid".to_string(),
+        constraints: vec!["=a".to_string(), "=b".to_string(), "a".to_string()],
+        operands: vec![
+            Expr::var("a".to_string(), at(31, 32)),
+            Expr::var("b".to_string(), at(40, 41)),
+            Expr::num(0, at(49, 50)),
+        ],
+        outputs: 2,
+        clobbers: vec!["rcx".to_string(), "rdx".to_string()],
+        extended: true,
+    });
+    test_node(
+        r#"asm volatile("cp" "uid" : "=a"(a), "=b"(b) : "a"(0) : "rcx", "rdx");"#,
+        expect,
+    );
     Ok(())
 }
+
+#[test]
+fn test_recovery() {
+    use crate::token::tokenize;
+    let parse = |source: &str, max_errors: usize| {
+        let mut tokenstream = tokenize(vec![source.to_string()]).unwrap();
+        tokenstream.set_max_errors(max_errors);
+        let (nodes, errors) = program_with_recovery(&mut tokenstream);
+        let errors: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+        (nodes.len(), errors)
+    };
+
+    // Each bad statement is skipped up to its `;`, and the rest is parsed.
+    let (nodes, errors) = parse("1 +; 2; int = 3; 4;", 20);
+    assert_eq!(nodes, 2);
+    assert_eq!(errors.len(), 2);
+
+    // Inside a block, parsing resumes at the next statement of the block.
+    let (nodes, errors) = parse("int f() { 1 2; return (3; } f(); ) 5; 6;", 20);
+    assert_eq!(nodes, 3);
+    assert_eq!(errors.len(), 3);
+    assert_eq!(errors[1], "expect Operation: ), found Semicolon: ;");
+
+    // Skipped statements can contain whole blocks.
+    let (nodes, errors) = parse("if (1 2) { 3; { 4; } } 5;", 20);
+    assert_eq!((nodes, errors.len()), (1, 1));
+
+    let (_, errors) = parse("+; +; +; +;", 2);
+    assert_eq!(errors.len(), 3);
+    assert_eq!(errors[2], "too many errors, stopping after 2");
+    // 0 is no limit.
+    let (_, errors) = parse("+; +; +; +;", 0);
+    assert_eq!(errors.len(), 4);
+}
+
+#[test]
+fn test_dump_ast() {
+    use crate::ast::dump_ast;
+    use crate::token::tokenize;
+    let mut tokenstream = tokenize(vec![
+        "int f(int x) { if (x > 1) return x * 2; else {} } f(3);".to_string(),
+    ])
+    .unwrap();
+    let items = program(&mut tokenstream).unwrap();
+    assert_eq!(
+        dump_ast(&items),
+        "Function f: int (int)
+  Decl x: int
+  If
+    Binary <
+      Num 1
+      Var x
+    Return
+      Binary *
+        Var x
+        Num 2
+  Else
+    Block
+Call
+  Var f
+  Num 3
+"
+    );
+}
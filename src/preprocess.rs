@@ -0,0 +1,1258 @@
+use crate::diagnostic::SourceMap;
+use crate::result::CompileError;
+use crate::token::{line_offsets, remove_comments, split_lines, Lexer, Span, Token, TokenKind};
+use anyhow::Result;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How deep `#include` may nest before we assume it recurses forever.
+const MAX_INCLUDE_DEPTH: usize = 200;
+
+/// Macros whose expansion is computed at the point of use.
+const BUILTINS: [&str; 3] = ["__FILE__", "__LINE__", "__DATE__"];
+
+/// Where the headers the compiler provides itself live, as far as
+/// `#include` and diagnostics are concerned.
+const BUILTIN_HEADER_DIR: &str = "<built-in>";
+
+/// Headers the compiler provides itself. They are found after every include
+/// path, so that a header of the same name there takes precedence.
+const BUILTIN_HEADERS: [(&str, &str); 1] = [(
+    "stdbool.h",
+    "#ifndef __bool_true_false_are_defined
+#define __bool_true_false_are_defined 1
+#define bool _Bool
+#define true 1
+#define false 0
+#endif",
+)];
+
+/// The text of the built-in header at `path`, if it is one.
+fn builtin_header(path: &Path) -> Option<&'static str> {
+    let name = path.strip_prefix(BUILTIN_HEADER_DIR).ok()?;
+    BUILTIN_HEADERS
+        .iter()
+        .find(|(header, _)| Path::new(header) == name)
+        .map(|(_, text)| *text)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Macro {
+    /// The parameters of a function-like macro. `__VA_ARGS__` stands for the
+    /// `...` of a variadic one.
+    pub params: Option<Vec<String>>,
+    pub variadic: bool,
+    pub body: Vec<Token>,
+}
+
+impl Macro {
+    fn param(&self, token: &Token) -> Option<usize> {
+        let TokenKind::Ident(name) = token.kind() else {
+            return None;
+        };
+        self.params
+            .as_ref()?
+            .iter()
+            .position(|param| *param == name)
+    }
+}
+
+/// A line of the file being preprocessed.
+struct SourceLine<'a> {
+    file: &'a Arc<str>,
+    text: &'a str,
+    row: usize,
+    /// Byte offset of the line in its file.
+    offset: usize,
+}
+
+impl SourceLine<'_> {
+    /// Tokenizes `rest`, which is the end of the line.
+    fn lex(&self, rest: &str, tokens: &mut Vec<Token>) -> Result<()> {
+        Lexer::new(self.file, self.text, self.row, self.offset)
+            .skip_to(self.text.len() - rest.len())
+            .lex(tokens)
+    }
+
+    /// The directive starting at byte `col`, up to the end of the line.
+    fn span(&self, col: usize) -> Span {
+        Span {
+            file: self.file.clone(),
+            start: self.offset + col,
+            end: self.offset + self.text.trim_end().len().max(col),
+        }
+    }
+}
+
+/// An open `#if`, `#ifdef` or `#ifndef`.
+struct Conditional {
+    /// Where the conditional started, for reporting a missing `#endif`.
+    at: Span,
+    /// Whether the lines of the current group are kept.
+    active: bool,
+    /// Whether some group has been kept, so later ones must be skipped.
+    taken: bool,
+    seen_else: bool,
+}
+
+/// Evaluates the integer constant expression of an `#if` once macros and
+/// `defined` have been replaced.
+struct Evaluator<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    at: &'a Span,
+}
+
+impl Evaluator<'_> {
+    fn error(&self, message: String) -> CompileError {
+        CompileError::Preprocess {
+            span: self.at.clone(),
+            message,
+        }
+    }
+
+    fn consume(&mut self, kind: TokenKind) -> bool {
+        if self.tokens.get(self.pos).map(Token::kind) == Some(kind) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn conditional(&mut self) -> Result<i64> {
+        let cond = self.logical_or()?;
+        if !self.consume(TokenKind::Question) {
+            return Ok(cond);
+        }
+        let then = self.conditional()?;
+        if !self.consume(TokenKind::Colon) {
+            Err(self.error("expected ':' in preprocessor expression".to_string()))?;
+        }
+        let otherwise = self.conditional()?;
+        Ok(if cond != 0 { then } else { otherwise })
+    }
+
+    fn logical_or(&mut self) -> Result<i64> {
+        let mut value = self.logical_and()?;
+        while self.consume(TokenKind::LogicalOr) {
+            let rhs = self.logical_and()?;
+            value = (value != 0 || rhs != 0) as i64;
+        }
+        Ok(value)
+    }
+
+    fn logical_and(&mut self) -> Result<i64> {
+        let mut value = self.equality()?;
+        while self.consume(TokenKind::LogicalAnd) {
+            let rhs = self.equality()?;
+            value = (value != 0 && rhs != 0) as i64;
+        }
+        Ok(value)
+    }
+
+    fn equality(&mut self) -> Result<i64> {
+        let mut value = self.relational()?;
+        loop {
+            if self.consume(TokenKind::Equal) {
+                value = (value == self.relational()?) as i64;
+            } else if self.consume(TokenKind::NEqual) {
+                value = (value != self.relational()?) as i64;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn relational(&mut self) -> Result<i64> {
+        let mut value = self.add()?;
+        loop {
+            if self.consume(TokenKind::Less) {
+                value = (value < self.add()?) as i64;
+            } else if self.consume(TokenKind::LessOrEqual) {
+                value = (value <= self.add()?) as i64;
+            } else if self.consume(TokenKind::Greater) {
+                value = (value > self.add()?) as i64;
+            } else if self.consume(TokenKind::GreaterOrEqual) {
+                value = (value >= self.add()?) as i64;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn add(&mut self) -> Result<i64> {
+        let mut value = self.mul()?;
+        loop {
+            if self.consume(TokenKind::Add) {
+                value = value.wrapping_add(self.mul()?);
+            } else if self.consume(TokenKind::Sub) {
+                value = value.wrapping_sub(self.mul()?);
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn mul(&mut self) -> Result<i64> {
+        let mut value = self.unary()?;
+        loop {
+            let op = if self.consume(TokenKind::Mul) {
+                TokenKind::Mul
+            } else if self.consume(TokenKind::Div) {
+                TokenKind::Div
+            } else if self.consume(TokenKind::Mod) {
+                TokenKind::Mod
+            } else {
+                return Ok(value);
+            };
+            let rhs = self.unary()?;
+            value = match op {
+                TokenKind::Mul => value.wrapping_mul(rhs),
+                _ if rhs == 0 => Err(self.error("division by zero in #if".to_string()))?,
+                TokenKind::Div => value.wrapping_div(rhs),
+                _ => value.wrapping_rem(rhs),
+            };
+        }
+    }
+
+    fn unary(&mut self) -> Result<i64> {
+        if self.consume(TokenKind::Add) {
+            self.unary()
+        } else if self.consume(TokenKind::Sub) {
+            Ok(self.unary()?.wrapping_neg())
+        } else if self.consume(TokenKind::Not) {
+            Ok((self.unary()? == 0) as i64)
+        } else {
+            self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<i64> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .ok_or_else(|| self.error("expected value in expression".to_string()))?;
+        self.pos += 1;
+        match token.kind() {
+            TokenKind::Num(num) => Ok(num as i64),
+            TokenKind::LRoundBracket => {
+                let value = self.conditional()?;
+                if !self.consume(TokenKind::RRoundBracket) {
+                    Err(self.error("missing ')' in expression".to_string()))?;
+                }
+                Ok(value)
+            }
+            kind => Err(self.error(format!(
+                "token \"{}\" is not valid in preprocessor expressions",
+                kind.spelling()
+            )))?,
+        }
+    }
+}
+
+/// A token along with its hide set: the macros whose expansion produced it,
+/// which must not be expanded again by that token.
+#[derive(Debug, Clone)]
+struct PpToken {
+    token: Token,
+    hideset: Vec<String>,
+}
+
+impl PpToken {
+    fn new(token: Token, hideset: Vec<String>) -> Self {
+        Self { token, hideset }
+    }
+
+    fn is(&self, kind: TokenKind) -> bool {
+        self.token.kind() == kind
+    }
+}
+
+/// The date of `seconds` since the Unix epoch as `__DATE__` spells it,
+/// e.g. `Oct 18 2026`.
+fn date(seconds: i64) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    // Civil date from days since 1970-01-01, after Howard Hinnant.
+    let days = seconds.div_euclid(86400) + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{} {:2} {}", MONTHS[month as usize - 1], day, year)
+}
+
+/// Checks the placement of `#` and `##` in a macro body.
+fn check_body(mac: &Macro) -> Result<(), &'static str> {
+    let body = &mac.body;
+    let is_paste = |token: &Token| token.kind() == TokenKind::HashHash;
+    if body.first().is_some_and(is_paste) || body.last().is_some_and(is_paste) {
+        return Err("'##' cannot appear at either end of a macro expansion");
+    }
+    if mac.params.is_some() {
+        for (i, token) in body.iter().enumerate() {
+            if token.kind() == TokenKind::Hash
+                && body.get(i + 1).and_then(|t| mac.param(t)).is_none()
+            {
+                return Err("'#' is not followed by a macro parameter");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Spells out the tokens of a macro argument for the `#` operator, with a
+/// space wherever the source had whitespace.
+fn stringize(tokens: &[PpToken]) -> String {
+    let mut string = String::new();
+    for (i, token) in tokens.iter().map(|t| &t.token).enumerate() {
+        if let Some(prev) = i.checked_sub(1).map(|i| &tokens[i].token) {
+            if prev.span().file != token.span().file || prev.span().end != token.span().start {
+                string.push(' ');
+            }
+        }
+        string.push_str(&token.kind().spelling());
+    }
+    string
+}
+
+/// Runs before `token::classify`, so macros may be named like keywords and
+/// macro names never take up a variable slot.
+#[derive(Debug, Default)]
+pub struct Preprocessor {
+    include_paths: Vec<PathBuf>,
+    macros: HashMap<String, Macro>,
+    /// The guard macro of every header written as `#ifndef X` / `#define X`
+    /// ... `#endif`, so that it is not even read again once `X` is defined.
+    guards: HashMap<PathBuf, String>,
+    depth: usize,
+    sources: SourceMap,
+    /// The time `__DATE__` expands to, in seconds since the Unix epoch.
+    timestamp: Option<i64>,
+}
+
+/// Splits a line like `  # define X 1` into `("define", " X 1")`, along with
+/// the column of the `#`.
+fn directive(line: &str) -> Option<(&str, &str, usize)> {
+    let start = line.len() - line.trim_start().len();
+    let rest = line[start..].strip_prefix('#')?;
+    let name_start = line.len() - rest.trim_start().len();
+    let name_len = line[name_start..]
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(line.len() - name_start);
+    let rest_start = name_start + name_len;
+    Some((&line[name_start..rest_start], &line[rest_start..], start))
+}
+
+/// The leading identifier of `text`, if any.
+fn identifier(text: &str) -> Option<&str> {
+    let text = text.trim_start();
+    if !text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        return None;
+    }
+    let len = text
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(text.len());
+    Some(&text[..len])
+}
+
+/// Detects an include guard: the first directive is `#ifndef X`, followed by
+/// `#define X`, and the `#endif` closing it is the last line. Returns `X`
+/// and the rows of the `#ifndef` and the `#endif`.
+fn include_guard(lines: &[String]) -> Option<(String, usize, usize)> {
+    let mut rows = (0..lines.len()).filter(|&row| !lines[row].trim().is_empty());
+    let first = rows.next()?;
+    let last = rows.next_back()?;
+
+    let (name, rest, _) = directive(&lines[first])?;
+    let guard = identifier(rest).filter(|_| name == "ifndef")?;
+    let define = rows.next().and_then(|row| directive(&lines[row]))?;
+    if define.0 != "define" || identifier(define.1) != Some(guard) {
+        return None;
+    }
+    if directive(&lines[last]).map(|(name, ..)| name) != Some("endif") {
+        return None;
+    }
+
+    let mut depth = 0;
+    for line in &lines[first + 1..last] {
+        match directive(line).map(|(name, ..)| name) {
+            Some("if" | "ifdef" | "ifndef") => depth += 1,
+            Some("endif") if depth == 0 => return None,
+            Some("endif") => depth -= 1,
+            Some("else" | "elif") if depth == 0 => return None,
+            _ => {}
+        }
+    }
+    (depth == 0).then(|| (guard.to_string(), first, last))
+}
+
+impl Preprocessor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a directory searched by `#include <...>`, and by `#include "..."`
+    /// after the directory of the including file.
+    pub fn add_include_path(&mut self, path: impl Into<PathBuf>) {
+        self.include_paths.push(path.into());
+    }
+
+    /// Defines an object-like macro, as `#define name body` would.
+    pub fn define(&mut self, name: &str, body: &str) -> Result<()> {
+        let mut tokens = Vec::new();
+        Lexer::new(&Arc::from("<command line>"), body, 0, 0).lex(&mut tokens)?;
+        self.macros.insert(
+            name.to_string(),
+            Macro {
+                params: None,
+                variadic: false,
+                body: tokens,
+            },
+        );
+        Ok(())
+    }
+
+    /// Sets the time `__DATE__` expands to, in seconds since the Unix epoch.
+    /// Without it, `SOURCE_DATE_EPOCH` is used if it is set, for
+    /// reproducible builds, and otherwise the current time.
+    pub fn set_timestamp(&mut self, seconds: i64) {
+        self.timestamp = Some(seconds);
+    }
+
+    fn timestamp(&self) -> i64 {
+        self.timestamp.unwrap_or_else(|| {
+            std::env::var("SOURCE_DATE_EPOCH")
+                .ok()
+                .and_then(|epoch| epoch.parse().ok())
+                .unwrap_or_else(|| {
+                    SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |now| now.as_secs() as i64)
+                })
+        })
+    }
+
+    /// Every file read so far, for rendering diagnostics.
+    pub fn sources(&self) -> &SourceMap {
+        &self.sources
+    }
+
+    pub fn is_defined(&self, name: &str) -> bool {
+        self.macros.contains_key(name) || BUILTINS.contains(&name)
+    }
+
+    /// Preprocesses the lines of `file`, which is used to find files included
+    /// with quotes and to label the tokens.
+    pub fn preprocess(&mut self, file: &str, source: Vec<String>) -> Result<Vec<Token>> {
+        let mut output = Vec::new();
+        self.sources.add(file, source.join("\n"));
+        let lines = remove_comments(file, &source)?;
+        self.process_file(file, &lines, &line_offsets(&source), &mut output)?;
+        Ok(output)
+    }
+
+    fn process_file(
+        &mut self,
+        file: &str,
+        lines: &[String],
+        offsets: &[usize],
+        output: &mut Vec<Token>,
+    ) -> Result<()> {
+        match include_guard(lines) {
+            Some((guard, first, last)) => {
+                if self.is_defined(&guard) {
+                    return Ok(());
+                }
+                self.guards.insert(Path::new(file).to_path_buf(), guard);
+                self.process_lines(file, &lines[..last], offsets, first + 1, output)
+            }
+            None => self.process_lines(file, lines, offsets, 0, output),
+        }
+    }
+
+    /// Handles `lines[start..]`. Text between two directives is expanded as
+    /// a whole, since a macro invocation may span several lines. Lines in a
+    /// skipped conditional group are not even tokenized.
+    fn process_lines(
+        &mut self,
+        file: &str,
+        lines: &[String],
+        offsets: &[usize],
+        start: usize,
+        output: &mut Vec<Token>,
+    ) -> Result<()> {
+        let name: Arc<str> = Arc::from(file);
+        let mut pending = Vec::new();
+        let mut conditionals: Vec<Conditional> = Vec::new();
+
+        for (row, text) in lines.iter().enumerate().skip(start) {
+            let skipping = conditionals.last().is_some_and(|c| !c.active);
+            let line = SourceLine {
+                file: &name,
+                text,
+                row,
+                offset: offsets.get(row).copied().unwrap_or_default(),
+            };
+            if let Some((directive, rest, col)) = directive(text) {
+                self.expand(std::mem::take(&mut pending), output)?;
+                let at = line.span(col);
+                let error = |message: &str| CompileError::Preprocess {
+                    span: at.clone(),
+                    message: message.to_string(),
+                };
+
+                match directive {
+                    "if" | "ifdef" | "ifndef" => {
+                        let active = !skipping && self.condition(&line, directive, rest, &at)?;
+                        conditionals.push(Conditional {
+                            at,
+                            active,
+                            // In a skipped group no branch may be taken.
+                            taken: active || skipping,
+                            seen_else: false,
+                        });
+                    }
+                    "elif" | "else" => {
+                        let top = conditionals
+                            .last_mut()
+                            .ok_or_else(|| error(&format!("#{} without #if", directive)))?;
+                        if top.seen_else {
+                            Err(error(&format!("#{} after #else", directive)))?;
+                        }
+                        top.active = !top.taken
+                            && (directive == "else"
+                                || self.condition(&line, directive, rest, &at)?);
+                        top.taken |= top.active;
+                        top.seen_else = directive == "else";
+                    }
+                    "endif" => {
+                        conditionals
+                            .pop()
+                            .ok_or_else(|| error("#endif without #if"))?;
+                    }
+                    _ if skipping => {}
+                    _ => self.directive(&line, directive, rest, &at, output)?,
+                }
+            } else if !skipping {
+                line.lex(text, &mut pending)?;
+            }
+        }
+
+        if let Some(conditional) = conditionals.pop() {
+            Err(CompileError::Preprocess {
+                span: conditional.at,
+                message: "unterminated conditional directive".to_string(),
+            })?;
+        }
+        self.expand(pending, output)
+    }
+
+    /// Evaluates the condition of `#if`, `#elif`, `#ifdef` or `#ifndef`.
+    fn condition(&self, line: &SourceLine, directive: &str, rest: &str, at: &Span) -> Result<bool> {
+        let error = |message: String| CompileError::Preprocess {
+            span: at.clone(),
+            message,
+        };
+        if directive == "ifdef" || directive == "ifndef" {
+            let name = identifier(rest)
+                .ok_or_else(|| error(format!("macro name missing in #{} directive", directive)))?;
+            return Ok(self.is_defined(name) == (directive == "ifdef"));
+        }
+
+        let mut tokens = Vec::new();
+        line.lex(rest, &mut tokens)?;
+        if tokens.is_empty() {
+            Err(error(format!("#{} with no expression", directive)))?;
+        }
+
+        // `defined` is resolved before macros are expanded.
+        let mut resolved = Vec::new();
+        let mut tokens = tokens.into_iter().peekable();
+        while let Some(token) = tokens.next() {
+            if token.kind() != TokenKind::Ident("defined".to_string()) {
+                resolved.push(PpToken::new(token, Vec::new()));
+                continue;
+            }
+            let paren = tokens.next_if(|t| t.kind() == TokenKind::LRoundBracket);
+            let name = match tokens.next().map(|t| t.kind()) {
+                Some(TokenKind::Ident(name)) => name,
+                _ => Err(error("macro names must be identifiers".to_string()))?,
+            };
+            if paren.is_some() && tokens.next().map(|t| t.kind()) != Some(TokenKind::RRoundBracket)
+            {
+                Err(error("missing ')' after \"defined\"".to_string()))?;
+            }
+            let value = TokenKind::Num(self.is_defined(&name) as u32);
+            resolved.push(PpToken::new(
+                Token::new(0, 0, value).relocate(&token),
+                Vec::new(),
+            ));
+        }
+
+        // Identifiers left after expansion are 0, except for `true`.
+        let tokens: Vec<Token> = self
+            .expand_tokens(resolved)?
+            .into_iter()
+            .map(|t| match t.token.kind() {
+                TokenKind::Ident(name) => {
+                    Token::new(0, 0, TokenKind::Num((name == "true") as u32)).relocate(&t.token)
+                }
+                _ => t.token,
+            })
+            .collect();
+
+        let mut evaluator = Evaluator {
+            tokens: &tokens,
+            pos: 0,
+            at,
+        };
+        let value = evaluator.conditional()?;
+        if let Some(token) = tokens.get(evaluator.pos) {
+            Err(error(format!(
+                "missing binary operator before token \"{}\"",
+                token.kind().spelling()
+            )))?;
+        }
+        Ok(value != 0)
+    }
+
+    fn directive(
+        &mut self,
+        line: &SourceLine,
+        directive: &str,
+        rest: &str,
+        at: &Span,
+        output: &mut Vec<Token>,
+    ) -> Result<()> {
+        let file: &str = line.file;
+        let error = |message: &str| CompileError::Preprocess {
+            span: at.clone(),
+            message: message.to_string(),
+        };
+
+        match directive {
+            // The null directive.
+            "" if rest.trim().is_empty() => {}
+            "include" => {
+                let rest = rest.trim();
+                let (name, quoted) = match rest.chars().next() {
+                    Some('"') => (rest[1..].split('"').next(), true),
+                    Some('<') => (rest[1..].split('>').next(), false),
+                    _ => (None, false),
+                };
+                let name = name
+                    .filter(|name| name.len() + 2 <= rest.len())
+                    .ok_or_else(|| error("#include expects \"FILENAME\" or <FILENAME>"))?;
+                let path = self
+                    .find_include(file, name, quoted)
+                    .ok_or_else(|| error(&format!("'{}' file not found", name)))?;
+                self.include(&path, at, output)?;
+            }
+            "define" => {
+                let name =
+                    identifier(rest).ok_or_else(|| error("macro names must be identifiers"))?;
+                let mut body = &rest[rest.find(name).unwrap() + name.len()..];
+                let mut params = None;
+                let mut variadic = false;
+                // Only a parenthesis right after the name makes a function-like macro.
+                if let Some(list) = body.strip_prefix('(') {
+                    let end = list
+                        .find(')')
+                        .ok_or_else(|| error("missing ')' in macro parameter list"))?;
+                    body = &list[end + 1..];
+                    let mut names = Vec::new();
+                    let list = list[..end].trim();
+                    for param in list.split(',').map(str::trim).filter(|_| !list.is_empty()) {
+                        if variadic {
+                            Err(error("'...' must be the last macro parameter"))?;
+                        }
+                        if param == "..." {
+                            variadic = true;
+                            names.push("__VA_ARGS__".to_string());
+                        } else if identifier(param) == Some(param)
+                            && !names.iter().any(|n| n == param)
+                        {
+                            names.push(param.to_string());
+                        } else {
+                            Err(error(&format!("invalid macro parameter '{}'", param)))?;
+                        }
+                    }
+                    params = Some(names);
+                }
+                let mut tokens = Vec::new();
+                line.lex(body, &mut tokens)?;
+                let mac = Macro {
+                    params,
+                    variadic,
+                    body: tokens,
+                };
+                check_body(&mac).map_err(error)?;
+                self.macros.insert(name.to_string(), mac);
+            }
+            "undef" => {
+                let name =
+                    identifier(rest).ok_or_else(|| error("macro names must be identifiers"))?;
+                self.macros.remove(name);
+            }
+            _ => Err(error(&format!(
+                "invalid preprocessing directive #{}",
+                directive
+            )))?,
+        }
+        Ok(())
+    }
+
+    /// Files included with quotes are first looked up next to the file that
+    /// includes them, and built-in headers last. Guarded headers seen before
+    /// count as found, even if they are gone by now.
+    fn find_include(&self, current: &str, name: &str, quoted: bool) -> Option<PathBuf> {
+        let local = quoted.then(|| {
+            Path::new(current)
+                .parent()
+                .unwrap_or(Path::new(""))
+                .join(name)
+        });
+        local
+            .into_iter()
+            .chain(self.include_paths.iter().map(|dir| dir.join(name)))
+            .chain([Path::new(BUILTIN_HEADER_DIR).join(name)])
+            .find(|path| {
+                self.guards.contains_key(path) || builtin_header(path).is_some() || path.is_file()
+            })
+    }
+
+    fn include(&mut self, path: &Path, at: &Span, output: &mut Vec<Token>) -> Result<()> {
+        if let Some(guard) = self.guards.get(path) {
+            if self.is_defined(guard) {
+                return Ok(());
+            }
+        }
+        if self.depth >= MAX_INCLUDE_DEPTH {
+            Err(CompileError::Preprocess {
+                span: at.clone(),
+                message: "#include nested too deeply".to_string(),
+            })?;
+        }
+
+        let source = match builtin_header(path) {
+            Some(text) => text.to_string(),
+            None => fs::read_to_string(path).map_err(|err| CompileError::Preprocess {
+                span: at.clone(),
+                message: format!("cannot read '{}': {}", path.display(), err),
+            })?,
+        };
+        let file = path.to_string_lossy();
+        self.sources.add(&file, source.clone());
+        let source = split_lines(&source);
+        let lines = remove_comments(&file, &source)?;
+
+        self.depth += 1;
+        let result = self.process_file(&file, &lines, &line_offsets(&source), output);
+        self.depth -= 1;
+        result
+    }
+
+    /// Replaces macros in `tokens`.
+    fn expand(&self, tokens: Vec<Token>, output: &mut Vec<Token>) -> Result<()> {
+        let tokens = tokens
+            .into_iter()
+            .map(|t| PpToken::new(t, Vec::new()))
+            .collect();
+        output.extend(self.expand_tokens(tokens)?.into_iter().map(|t| t.token));
+        Ok(())
+    }
+
+    /// Expands macros until none are left. A token is never expanded by a
+    /// macro in its hide set, which is what stops `#define a a` after one
+    /// step, while still allowing `f(f(x))`.
+    fn expand_tokens(&self, tokens: Vec<PpToken>) -> Result<Vec<PpToken>> {
+        let mut input: VecDeque<PpToken> = tokens.into();
+        let mut output = Vec::new();
+
+        while let Some(token) = input.pop_front() {
+            let TokenKind::Ident(name) = token.token.kind() else {
+                output.push(token);
+                continue;
+            };
+            if token.hideset.contains(&name) {
+                output.push(token);
+                continue;
+            }
+            if let Some(kind) = self.builtin(&name, &token.token) {
+                output.push(PpToken::new(
+                    Token::new(0, 0, kind).relocate(&token.token),
+                    token.hideset,
+                ));
+                continue;
+            }
+            let Some(mac) = self.macros.get(&name) else {
+                output.push(token);
+                continue;
+            };
+
+            let expansion = if mac.params.is_none() {
+                let mut hideset = token.hideset.clone();
+                hideset.push(name);
+                self.substitute(mac, &[], &token.token, &hideset)?
+            } else {
+                // A function-like macro name without arguments is left alone.
+                if !input
+                    .front()
+                    .is_some_and(|next| next.is(TokenKind::LRoundBracket))
+                {
+                    output.push(token);
+                    continue;
+                }
+                input.pop_front();
+                let (args, rparen) = self.arguments(&name, mac, &token.token, &mut input)?;
+                let mut hideset: Vec<String> = token
+                    .hideset
+                    .iter()
+                    .filter(|name| rparen.hideset.contains(name))
+                    .cloned()
+                    .collect();
+                hideset.push(name);
+                self.substitute(mac, &args, &token.token, &hideset)?
+            };
+            for token in expansion.into_iter().rev() {
+                input.push_front(token);
+            }
+        }
+
+        Ok(output)
+    }
+
+    fn builtin(&self, name: &str, at: &Token) -> Option<TokenKind> {
+        if self.macros.contains_key(name) {
+            return None;
+        }
+        match name {
+            "__FILE__" => Some(TokenKind::Str(at.file().to_string())),
+            "__LINE__" => Some(TokenKind::Num(at.row() + 1)),
+            "__DATE__" => Some(TokenKind::Str(date(self.timestamp()))),
+            _ => None,
+        }
+    }
+
+    /// Reads the arguments of a macro invocation up to the closing
+    /// parenthesis, which is returned as well.
+    fn arguments(
+        &self,
+        name: &str,
+        mac: &Macro,
+        at: &Token,
+        input: &mut VecDeque<PpToken>,
+    ) -> Result<(Vec<Vec<PpToken>>, PpToken)> {
+        let params = mac.params.as_ref().map_or(0, Vec::len);
+        let error = |message: String| CompileError::Preprocess {
+            span: at.span().clone(),
+            message,
+        };
+
+        let mut args = vec![Vec::new()];
+        let mut depth = 0;
+        let rparen = loop {
+            let token = input.pop_front().ok_or_else(|| {
+                error(format!(
+                    "unterminated argument list invoking macro '{}'",
+                    name
+                ))
+            })?;
+            match token.token.kind() {
+                TokenKind::RRoundBracket if depth == 0 => break token,
+                TokenKind::LRoundBracket => depth += 1,
+                TokenKind::RRoundBracket => depth -= 1,
+                // Commas in the variadic part belong to `__VA_ARGS__`.
+                TokenKind::Comma if depth == 0 && !(mac.variadic && args.len() == params) => {
+                    args.push(Vec::new());
+                    continue;
+                }
+                _ => {}
+            }
+            args.last_mut().unwrap().push(token);
+        };
+
+        if params == 0 && args.len() == 1 && args[0].is_empty() {
+            args.clear();
+        }
+        // `__VA_ARGS__` may be left out entirely.
+        if mac.variadic && args.len() + 1 == params {
+            args.push(Vec::new());
+        }
+        if args.len() != params {
+            Err(error(format!(
+                "macro '{}' requires {} arguments, but {} given",
+                name,
+                params,
+                args.len()
+            )))?;
+        }
+        Ok((args, rparen))
+    }
+
+    /// The body of a macro with its parameters replaced and `##` applied.
+    /// Operands of `#` and `##` are used as written, other arguments are
+    /// expanded first.
+    fn substitute(
+        &self,
+        mac: &Macro,
+        args: &[Vec<PpToken>],
+        at: &Token,
+        hideset: &[String],
+    ) -> Result<Vec<PpToken>> {
+        let body = &mac.body;
+        let local = |token: &Token| PpToken::new(token.relocate(at), Vec::new());
+        let mut result: Vec<PpToken> = Vec::new();
+
+        let mut i = 0;
+        while i < body.len() {
+            let token = &body[i];
+            let next = body.get(i + 1);
+
+            if token.kind() == TokenKind::Hash && mac.params.is_some() {
+                let arg = &args[mac.param(next.unwrap()).unwrap()];
+                let string = TokenKind::Str(stringize(arg));
+                result.push(local(&Token::new(0, 0, string)));
+                i += 2;
+                continue;
+            }
+
+            if token.kind() == TokenKind::HashHash {
+                let next = next.unwrap();
+                match mac.param(next) {
+                    // GNU extension: `, ## __VA_ARGS__` drops the comma when
+                    // there are no variadic arguments.
+                    Some(param)
+                        if mac.variadic
+                            && param + 1 == args.len()
+                            && result.last().is_some_and(|t| t.is(TokenKind::Comma)) =>
+                    {
+                        if args[param].is_empty() {
+                            result.pop();
+                        }
+                        result.extend(args[param].iter().cloned());
+                    }
+                    Some(param) => {
+                        if let Some((first, rest)) = args[param].split_first() {
+                            let lhs = result.pop();
+                            result.push(paste(lhs, first)?);
+                            result.extend(rest.iter().cloned());
+                        }
+                    }
+                    None => {
+                        let lhs = result.pop();
+                        result.push(paste(lhs, &local(next))?);
+                    }
+                }
+                i += 2;
+                continue;
+            }
+
+            match mac.param(token) {
+                Some(param) if next.is_some_and(|t| t.kind() == TokenKind::HashHash) => {
+                    // A placemarker: with an empty left operand, `##` leaves
+                    // the right one as it is.
+                    if args[param].is_empty() {
+                        let rhs = &body[i + 2];
+                        match mac.param(rhs) {
+                            Some(rhs) => result.extend(args[rhs].iter().cloned()),
+                            None => result.push(local(rhs)),
+                        }
+                        i += 3;
+                        continue;
+                    }
+                    result.extend(args[param].iter().cloned());
+                }
+                Some(param) => result.extend(self.expand_tokens(args[param].clone())?),
+                None => result.push(local(token)),
+            }
+            i += 1;
+        }
+
+        for token in result.iter_mut() {
+            token.hideset.extend(hideset.iter().cloned());
+        }
+        Ok(result)
+    }
+}
+
+/// Glues two tokens together with `##`, which must give a single token.
+fn paste(lhs: Option<PpToken>, rhs: &PpToken) -> Result<PpToken> {
+    let Some(lhs) = lhs else {
+        return Ok(rhs.clone());
+    };
+    let text = lhs.token.kind().spelling() + &rhs.token.kind().spelling();
+    let mut tokens = Vec::new();
+    let pasted = Lexer::new(&Arc::from(""), &text, 0, 0)
+        .lex(&mut tokens)
+        .ok()
+        .filter(|_| tokens.len() == 1)
+        .map(|_| tokens.remove(0));
+    match pasted {
+        Some(token) => Ok(PpToken::new(token.relocate(&lhs.token), lhs.hideset)),
+        None => Err(CompileError::Preprocess {
+            span: lhs.token.span().clone(),
+            message: format!(
+                "pasting \"{}\" and \"{}\" does not give a valid preprocessing token",
+                lhs.token.kind().spelling(),
+                rhs.token.kind().spelling()
+            ),
+        })?,
+    }
+}
+
+#[cfg(test)]
+fn lines(source: &str) -> Vec<String> {
+    source.lines().map(str::to_string).collect()
+}
+
+/// An error as `file:line:col: message`.
+#[cfg(test)]
+fn located(preprocessor: &Preprocessor, error: &anyhow::Error) -> String {
+    let diagnostic = crate::diagnostic::Diagnostic::from(error);
+    let span = diagnostic.span.as_ref().unwrap();
+    let (row, line, col) = preprocessor.sources().lookup(span).unwrap();
+    format!(
+        "{}:{}:{}: {}",
+        span.file,
+        row + 1,
+        line[..col].chars().count() + 1,
+        diagnostic.message
+    )
+}
+
+#[test]
+fn test_object_like_macros() {
+    let mut preprocessor = Preprocessor::new();
+    preprocessor.define("ONE", "1").unwrap();
+    let source =
+        "#define TWO ONE + ONE\n#define bool int\n#define a a + TWO\nbool x = a;\n#undef TWO\nTWO;";
+    let tokens = preprocessor.preprocess("main.c", lines(source)).unwrap();
+    let kinds: Vec<TokenKind> = tokens.iter().map(Token::kind).collect();
+    let ident = |name: &str| TokenKind::Ident(name.to_string());
+    assert_eq!(
+        kinds,
+        vec![
+            ident("int"),
+            ident("x"),
+            TokenKind::Assign,
+            ident("a"),
+            TokenKind::Add,
+            TokenKind::Num(1),
+            TokenKind::Add,
+            TokenKind::Num(1),
+            TokenKind::Semicolon,
+            ident("TWO"),
+            TokenKind::Semicolon,
+        ]
+    );
+    // Expanded tokens point at the macro use.
+    assert_eq!((tokens[5].row(), tokens[5].col()), (3, 9));
+    assert_eq!(tokens[5].file(), "main.c");
+}
+
+#[cfg(test)]
+fn render(tokens: &[Token]) -> String {
+    let spellings: Vec<String> = tokens.iter().map(|t| t.kind().spelling()).collect();
+    spellings.join(" ")
+}
+
+#[test]
+fn test_function_like_macros() {
+    let source = r#"#define ADD(a, b) ((a) + (b))
+#define STR(x) #x
+#define CAT(a, b) a ## b
+#define LOG(fmt, ...) log(fmt, ## __VA_ARGS__)
+#define COUNT(...) count(__VA_ARGS__)
+#define f(x) x + f(x)
+#define ID ID ## _
+ADD(1,
+    ADD(2, 3));
+STR(a  +  "b\n");
+CAT(x, 1); CAT(, y); CAT(<, =);
+LOG("a"); LOG("b", 1, (2, 3));
+COUNT();
+f(f(1));
+__LINE__; __FILE__; ADD; ID;"#;
+    let mut preprocessor = Preprocessor::new();
+    let tokens = preprocessor.preprocess("main.c", lines(source)).unwrap();
+    let expect = [
+        "( ( 1 ) + ( ( ( 2 ) + ( 3 ) ) ) ) ;",
+        r#""a + \"b\\n\"" ;"#,
+        "x1 ; y ; <= ;",
+        r#"log ( "a" ) ; log ( "b" , 1 , ( 2 , 3 ) ) ;"#,
+        "count ( ) ;",
+        "1 + f ( 1 ) + f ( 1 + f ( 1 ) ) ;",
+        r#"15 ; "main.c" ; ADD ; ID_ ;"#,
+    ];
+    assert_eq!(render(&tokens), expect.join(" "));
+    // Arguments keep their own location, the rest points at the macro use.
+    assert_eq!((tokens[0].row(), tokens[0].col()), (7, 0));
+    assert_eq!((tokens[8].row(), tokens[8].col()), (8, 8));
+
+    let error = |source: &str| {
+        let mut preprocessor = Preprocessor::new();
+        let error = preprocessor
+            .preprocess("main.c", lines(source))
+            .unwrap_err();
+        located(&preprocessor, &error)
+    };
+    assert_eq!(
+        error("#define ADD(a, b) a + b\nADD(1);"),
+        "main.c:2:1: macro 'ADD' requires 2 arguments, but 1 given"
+    );
+    assert_eq!(
+        error("#define F(a) a\nF(1"),
+        "main.c:2:1: unterminated argument list invoking macro 'F'"
+    );
+    assert_eq!(
+        error("#define CAT(a, b) a ## b\nCAT(+, -)"),
+        "main.c:2:5: pasting \"+\" and \"-\" does not give a valid preprocessing token"
+    );
+    assert_eq!(
+        error("#define S(x) #y"),
+        "main.c:1:1: '#' is not followed by a macro parameter"
+    );
+}
+
+#[test]
+fn test_conditionals() {
+    let source = "#define A 2
+#if A > 1 && defined(A) && !defined B
+one
+#elif 1 / 0
+two
+#else
+three
+#endif
+#ifdef B
+#if (((
+#bogus
+$ not C at all
+#endif
+#elif (A * 3) % 4 == 2 ? true : 0
+four
+#endif
+#ifndef A
+five
+#elif UNDEFINED || A - 2
+six
+#else
+seven
+#endif";
+    let tokens = Preprocessor::new()
+        .preprocess("main.c", lines(source))
+        .unwrap();
+    assert_eq!(render(&tokens), "one four seven");
+
+    let error = |source: &str| {
+        let mut preprocessor = Preprocessor::new();
+        let error = preprocessor
+            .preprocess("main.c", lines(source))
+            .unwrap_err();
+        located(&preprocessor, &error)
+    };
+    assert_eq!(error("#else"), "main.c:1:1: #else without #if");
+    assert_eq!(error("#endif"), "main.c:1:1: #endif without #if");
+    assert_eq!(
+        error("#if 1\n#else\n#elif 1\n#endif"),
+        "main.c:3:1: #elif after #else"
+    );
+    assert_eq!(
+        error("1;\n  #ifdef X\n#if 1\n#endif"),
+        "main.c:2:3: unterminated conditional directive"
+    );
+    assert_eq!(error("#if 1 +"), "main.c:1:1: expected value in expression");
+    assert_eq!(
+        error("#if 1 2"),
+        "main.c:1:1: missing binary operator before token \"2\""
+    );
+    assert_eq!(error("#if 1 % 0"), "main.c:1:1: division by zero in #if");
+    assert_eq!(
+        error("#if defined(+)"),
+        "main.c:1:1: macro names must be identifiers"
+    );
+}
+
+#[test]
+fn test_date() {
+    assert_eq!(date(1709251200), "Mar  1 2024");
+    assert_eq!(date(0), "Jan  1 1970");
+
+    let mut preprocessor = Preprocessor::new();
+    preprocessor.set_timestamp(1709251200);
+    let tokens = preprocessor
+        .preprocess("main.c", lines("__DATE__"))
+        .unwrap();
+    assert_eq!(render(&tokens), "\"Mar  1 2024\"");
+}
+
+#[test]
+fn test_include() {
+    let dir = std::env::temp_dir().join(format!("ccompiler-include-{}", std::process::id()));
+    fs::create_dir_all(dir.join("sys")).unwrap();
+    let header = dir.join("local.h");
+    fs::write(
+        &header,
+        "\n#ifndef LOCAL_H\n#define LOCAL_H\nint local;\n#endif\n",
+    )
+    .unwrap();
+    fs::write(dir.join("sys").join("lib.h"), "#define LIB 7\n").unwrap();
+
+    let mut preprocessor = Preprocessor::new();
+    preprocessor.add_include_path(dir.join("sys"));
+    let main = dir.join("main.c");
+    let main = main.to_str().unwrap();
+    let source = "#include \"local.h\"\n#include \"local.h\"\n# include <lib.h>\nLIB;";
+    let tokens = preprocessor.preprocess(main, lines(source)).unwrap();
+    assert_eq!(tokens.len(), 5);
+    assert_eq!(tokens[0].file(), header.to_str().unwrap());
+    assert_eq!(tokens[0].row(), 3);
+    assert_eq!(tokens[3].kind(), TokenKind::Num(7));
+    assert_eq!(tokens[3].file(), main);
+
+    // A guarded header is not read again once its guard is defined.
+    fs::remove_file(&header).unwrap();
+    let tokens = preprocessor
+        .preprocess(main, lines("#include \"local.h\""))
+        .unwrap();
+    assert!(tokens.is_empty());
+
+    let error = preprocessor
+        .preprocess("main.c", lines("1;\n#include <missing.h>"))
+        .unwrap_err();
+    assert_eq!(
+        located(&preprocessor, &error),
+        "main.c:2:1: 'missing.h' file not found"
+    );
+    let error = preprocessor
+        .preprocess("main.c", lines("  #pragma once"))
+        .unwrap_err();
+    assert_eq!(
+        located(&preprocessor, &error),
+        "main.c:1:3: invalid preprocessing directive #pragma"
+    );
+
+    // <stdbool.h> is built in, unless an include path has one.
+    let source = "#include <stdbool.h>\n#include \"stdbool.h\"\nbool b = true;";
+    let tokens = Preprocessor::new()
+        .preprocess("main.c", lines(source))
+        .unwrap();
+    assert_eq!(render(&tokens), "_Bool b = 1 ;");
+    fs::write(dir.join("sys").join("stdbool.h"), "#define bool int\n").unwrap();
+    let tokens = preprocessor.preprocess("main.c", lines(source)).unwrap();
+    assert_eq!(render(&tokens), "int b = true ;");
+    fs::remove_dir_all(dir).unwrap();
+}
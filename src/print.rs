@@ -0,0 +1,430 @@
+use crate::ast::{BinaryOp, Decl, Expr, ExprKind, FunctionDef, InlineAsm, Item, Stmt, StmtKind};
+use crate::token::TokenKind;
+use crate::types::{Type, TypeKind};
+use crate::visit::Visitor;
+
+/// How `print_program` lays out the program.
+#[derive(Debug, Clone, Default)]
+pub struct PrintOptions {
+    /// Parenthesize every operator inside another expression, e.g.
+    /// `a = 1 + (2 * 3)` rather than `a = 1 + 2 * 3`.
+    pub parenthesize: bool,
+}
+
+/// Renders the program as C, indented by four spaces with the opening brace
+/// of a block on the line it belongs to. The parser turns `a > b` into
+/// `b < a` and `-a` into `0 - a`, so they are printed that way, and
+/// parsing the output gives the same tree back apart from the spans.
+pub fn print_program(program: &[Item], options: &PrintOptions) -> String {
+    let mut printer = Printer {
+        options,
+        output: String::new(),
+        depth: 0,
+        precedence: ASSIGN,
+    };
+    printer.visit_program(program);
+    printer.output
+}
+
+// How tightly each kind of expression binds, loosest first.
+const ASSIGN: u8 = 1;
+const EQUALITY: u8 = 2;
+const RELATIONAL: u8 = 3;
+const ADDITIVE: u8 = 4;
+const MULTIPLICATIVE: u8 = 5;
+const UNARY: u8 = 6;
+const POSTFIX: u8 = 7;
+const PRIMARY: u8 = 8;
+
+fn precedence(expr: &Expr) -> u8 {
+    match &expr.kind {
+        ExprKind::Num(_) | ExprKind::Str(_) | ExprKind::Var { .. } => PRIMARY,
+        ExprKind::Binary { op, .. } => binary_precedence(*op),
+        ExprKind::Assign { .. } => ASSIGN,
+        ExprKind::Deref(_) | ExprKind::Addr(_) | ExprKind::Cast(_) => UNARY,
+        ExprKind::Call { .. } => POSTFIX,
+    }
+}
+
+fn binary_precedence(op: BinaryOp) -> u8 {
+    match op {
+        BinaryOp::Equal | BinaryOp::NEqual => EQUALITY,
+        BinaryOp::Less | BinaryOp::LessOrEqual => RELATIONAL,
+        BinaryOp::Add | BinaryOp::Sub => ADDITIVE,
+        BinaryOp::Mul | BinaryOp::Div => MULTIPLICATIVE,
+    }
+}
+
+/// `ty` declaring `inner`, e.g. `int *(*f)(char)` for a pointer `(*f)` to a
+/// function. An empty `inner` gives the type name written in a cast.
+pub fn declaration(ty: &Type, inner: String) -> String {
+    match &ty.kind {
+        TypeKind::Ptr(pointee) => {
+            let mut pointer = String::from("*");
+            for (qualifier, present) in [("const", ty.is_const), ("volatile", ty.is_volatile)] {
+                if present {
+                    if pointer.len() > 1 {
+                        pointer.push(' ');
+                    }
+                    pointer.push_str(qualifier);
+                }
+            }
+            if pointer.len() > 1 && !inner.is_empty() {
+                pointer.push(' ');
+            }
+            pointer.push_str(&inner);
+            if pointee.is_func() {
+                pointer = format!("({})", pointer);
+            }
+            declaration(pointee, pointer)
+        }
+        TypeKind::Func { ret, params } => {
+            let params = match params {
+                None => String::new(),
+                Some(params) if params.is_empty() => "void".to_string(),
+                Some(params) => params
+                    .iter()
+                    .map(|param| declaration(param, String::new()))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            };
+            declaration(ret, format!("{}({})", inner, params))
+        }
+        _ if inner.is_empty() => ty.to_string(),
+        _ => format!("{} {}", ty, inner),
+    }
+}
+
+/// `text` as a string literal, escaped as the lexer reads it back.
+fn quote(text: &str) -> String {
+    TokenKind::Str(text.to_string()).spelling()
+}
+
+/// Whether an `else` after `stmt` would be taken as the `else` of an `if`
+/// inside it.
+fn ends_in_open_if(stmt: &Stmt) -> bool {
+    match &stmt.kind {
+        StmtKind::If {
+            otherwise: None, ..
+        } => true,
+        StmtKind::If {
+            otherwise: Some(otherwise),
+            ..
+        } => ends_in_open_if(otherwise),
+        _ => false,
+    }
+}
+
+struct Printer<'a> {
+    options: &'a PrintOptions,
+    output: String,
+    depth: usize,
+    /// How tightly the expression being visited has to bind to go without
+    /// parentheses.
+    precedence: u8,
+}
+
+impl Printer<'_> {
+    fn indent(&mut self) {
+        self.output.push_str(&"    ".repeat(self.depth));
+    }
+
+    fn expr(&mut self, expr: &Expr, precedence: u8) {
+        let outer = std::mem::replace(&mut self.precedence, precedence);
+        self.visit_expr(expr);
+        self.precedence = outer;
+    }
+
+    /// `{`, the statements one level deeper, and `}` on a line of its own,
+    /// or `{}` for no statements. The rest of the line is left to the caller.
+    fn block(&mut self, stmts: &[Stmt]) {
+        if stmts.is_empty() {
+            self.output.push_str("{}");
+            return;
+        }
+        self.output.push_str("{\n");
+        self.depth += 1;
+        for stmt in stmts {
+            self.visit_stmt(stmt);
+        }
+        self.depth -= 1;
+        self.indent();
+        self.output.push('}');
+    }
+
+    /// The branch of an `if` after its condition or `else`. A block stays on
+    /// the same line and leaves it open, which is reported by returning
+    /// true; any other statement goes on the next line, one level deeper.
+    fn branch(&mut self, stmt: &Stmt, braced: bool) -> bool {
+        if let StmtKind::Block(stmts) = &stmt.kind {
+            self.output.push(' ');
+            self.block(stmts);
+            return true;
+        }
+        if braced {
+            self.output.push(' ');
+            self.block(std::slice::from_ref(stmt));
+            return true;
+        }
+        self.output.push('\n');
+        self.depth += 1;
+        self.visit_stmt(stmt);
+        self.depth -= 1;
+        false
+    }
+
+    /// An `if` from its keyword on, with `else if` kept on one line.
+    fn if_stmt(&mut self, cond: &Expr, then: &Stmt, otherwise: Option<&Stmt>) {
+        self.output.push_str("if (");
+        self.expr(cond, ASSIGN);
+        self.output.push(')');
+        // A tree built by hand may have an `if` without `else` as the
+        // branch of one with it, which needs braces to keep its meaning.
+        let open = self.branch(then, otherwise.is_some() && ends_in_open_if(then));
+        let Some(otherwise) = otherwise else {
+            if open {
+                self.output.push('\n');
+            }
+            return;
+        };
+        if open {
+            self.output.push_str(" else");
+        } else {
+            self.indent();
+            self.output.push_str("else");
+        }
+        match &otherwise.kind {
+            StmtKind::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                self.output.push(' ');
+                self.if_stmt(cond, then, otherwise.as_deref());
+            }
+            _ => {
+                if self.branch(otherwise, false) {
+                    self.output.push('\n');
+                }
+            }
+        }
+    }
+
+    /// `"constraint"(expr), ...`
+    fn asm_operands(&mut self, constraints: &[String], operands: &[Expr]) {
+        for (i, (constraint, operand)) in constraints.iter().zip(operands).enumerate() {
+            self.output.push_str(if i == 0 { " " } else { ", " });
+            self.output.push_str(&quote(constraint));
+            self.output.push('(');
+            self.expr(operand, ASSIGN);
+            self.output.push(')');
+        }
+    }
+}
+
+impl Visitor for Printer<'_> {
+    /// Function definitions are set apart by blank lines.
+    fn visit_program(&mut self, program: &[Item]) {
+        for (i, item) in program.iter().enumerate() {
+            let function = |item: &Item| matches!(item, Item::Function(_));
+            if i > 0 && (function(item) || function(&program[i - 1])) {
+                self.output.push('\n');
+            }
+            self.visit_item(item);
+        }
+    }
+
+    fn visit_function(&mut self, function: &FunctionDef) {
+        let params = match &function.ty.kind {
+            TypeKind::Func { params: None, .. } => String::new(),
+            TypeKind::Func { .. } if function.params.is_empty() => "void".to_string(),
+            _ => function
+                .params
+                .iter()
+                .map(|param| declaration(&param.ty, param.name.clone()))
+                .collect::<Vec<_>>()
+                .join(", "),
+        };
+        let ret = match &function.ty.kind {
+            TypeKind::Func { ret, .. } => ret,
+            _ => &function.ty,
+        };
+        self.indent();
+        self.output
+            .push_str(&declaration(ret, format!("{}({})", function.name, params)));
+        self.output.push(' ');
+        self.block(&function.body);
+        self.output.push('\n');
+    }
+
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        match &stmt.kind {
+            StmtKind::Expr(expr) => {
+                self.indent();
+                self.expr(expr, ASSIGN);
+                self.output.push_str(";\n");
+            }
+            StmtKind::Decl(decl) => self.visit_decl(decl),
+            StmtKind::Block(stmts) => {
+                self.indent();
+                self.block(stmts);
+                self.output.push('\n');
+            }
+            StmtKind::If {
+                cond,
+                then,
+                otherwise,
+            } => {
+                self.indent();
+                self.if_stmt(cond, then, otherwise.as_deref());
+            }
+            StmtKind::Return(value) => {
+                self.indent();
+                self.output.push_str("return");
+                if let Some(value) = value {
+                    self.output.push(' ');
+                    self.expr(value, ASSIGN);
+                }
+                self.output.push_str(";\n");
+            }
+            StmtKind::Asm(asm) => self.visit_asm(asm),
+        }
+    }
+
+    fn visit_decl(&mut self, decl: &Decl) {
+        self.indent();
+        self.output
+            .push_str(&declaration(&decl.ty, decl.name.clone()));
+        if let Some(init) = &decl.init {
+            self.output.push_str(" = ");
+            self.expr(init, ASSIGN);
+        }
+        self.output.push_str(";\n");
+    }
+
+    /// `asm("template" : outputs : inputs : clobbers);`, leaving out the
+    /// trailing parts that are empty.
+    fn visit_asm(&mut self, asm: &InlineAsm) {
+        self.indent();
+        self.output.push_str("asm(");
+        self.output.push_str(&quote(&asm.template));
+        if asm.extended {
+            let (outputs, inputs) = asm.constraints.split_at(asm.outputs);
+            let (output_exprs, input_exprs) = asm.operands.split_at(asm.outputs);
+            self.output.push_str(" :");
+            self.asm_operands(outputs, output_exprs);
+            if !inputs.is_empty() || !asm.clobbers.is_empty() {
+                self.output.push_str(" :");
+                self.asm_operands(inputs, input_exprs);
+            }
+            if !asm.clobbers.is_empty() {
+                self.output.push_str(" : ");
+                let clobbers: Vec<_> = asm.clobbers.iter().map(|c| quote(c)).collect();
+                self.output.push_str(&clobbers.join(", "));
+            }
+        }
+        self.output.push_str(");\n");
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        let precedence = precedence(expr);
+        let parens = precedence < self.precedence
+            || (self.options.parenthesize && self.precedence > ASSIGN && precedence < POSTFIX);
+        if parens {
+            self.output.push('(');
+        }
+        match &expr.kind {
+            ExprKind::Num(num) => self.output.push_str(&num.to_string()),
+            ExprKind::Str(text) => self.output.push_str(&quote(text)),
+            ExprKind::Var { name, .. } => self.output.push_str(name),
+            // Operators of the same precedence group to the left.
+            ExprKind::Binary { op, lhs, rhs } => {
+                self.expr(lhs, precedence);
+                self.output.push_str(&format!(" {} ", op.spelling()));
+                self.expr(rhs, precedence + 1);
+            }
+            ExprKind::Assign { lhs, rhs } => {
+                self.expr(lhs, EQUALITY);
+                self.output.push_str(" = ");
+                self.expr(rhs, ASSIGN);
+            }
+            ExprKind::Deref(operand) => {
+                self.output.push('*');
+                self.expr(operand, UNARY);
+            }
+            ExprKind::Addr(operand) => {
+                self.output.push('&');
+                self.expr(operand, UNARY);
+            }
+            ExprKind::Cast(operand) => {
+                if let Some(ty) = &expr.ty {
+                    self.output
+                        .push_str(&format!("({})", declaration(ty, String::new())));
+                }
+                self.expr(operand, UNARY);
+            }
+            ExprKind::Call { callee, args } => {
+                self.expr(callee, POSTFIX);
+                self.output.push('(');
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        self.output.push_str(", ");
+                    }
+                    self.expr(arg, ASSIGN);
+                }
+                self.output.push(')');
+            }
+        }
+        if parens {
+            self.output.push(')');
+        }
+    }
+}
+
+#[test]
+fn test_print_program() {
+    use crate::node::program;
+    use crate::token::tokenize;
+    let print = |source: &str, parenthesize: bool| {
+        let mut tokenstream = tokenize(vec![source.to_string()]).unwrap();
+        print_program(
+            &program(&mut tokenstream).unwrap(),
+            &PrintOptions { parenthesize },
+        )
+    };
+
+    assert_eq!(
+        print(
+            concat!(
+                "int *(*pick(int n))(char *, long) { if (n > 1) return 0; else if (n) { n = -n; } else n; return 0; }",
+                "unsigned char c=(unsigned char)f(1,2)*(3+4); const int *const p = &c;",
+                r#"{ asm("add %0, 1\n" : "+r"(c)); asm("nop" : : : "cc"); if (c) {} }"#,
+                "int g(void);"
+            ),
+            false
+        ),
+        r#"int *(*pick(int n))(char *, long) {
+    if (1 < n)
+        return 0;
+    else if (n) {
+        n = 0 - n;
+    } else
+        n;
+    return 0;
+}
+
+unsigned char c = (unsigned char)f(1, 2) * (3 + 4);
+const int *const p = &c;
+{
+    asm("add %0, 1\n" : "+r"(c));
+    asm("nop" : : : "cc");
+    if (c) {}
+}
+int g(void);
+"#
+    );
+    assert_eq!(
+        print("a = *p + 2 * f(b == 1 < c) - (long)-x;", true),
+        "a = ((*p) + (2 * f(b == (1 < c)))) - ((long)(0 - x));\n"
+    );
+}
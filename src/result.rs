@@ -1,16 +1,239 @@
-use crate::token::{Token, TokenKind};
+use crate::ast::BinaryOp;
+use crate::token::{Span, Token, TokenKind};
+use crate::types::Type;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
 pub enum CompileError {
-    #[error("{}/{}: expect {}, found {}", stop.row(), stop.col(), expect, result)]
+    /// `stop` is the token that was found instead.
+    #[error("expect {}, found {}", expect, result)]
     Unexpected {
         stop: Token,
         expect: TokenKind,
         result: TokenKind,
     },
-    #[error("{}/{}: expect {}", stop.row(), stop.col(), expect)]
+    /// The source ended after `stop`.
+    #[error("expect {}", expect)]
     Expected { stop: Token, expect: TokenKind },
-    #[error("ParseError")]
-    ParseError(Option<&'static str>),
+    /// A closing bracket is missing for `open`.
+    #[error("{error}")]
+    Unclosed {
+        error: Box<CompileError>,
+        open: Token,
+    },
+    #[error("invalid combination of type specifiers")]
+    InvalidTypeSpecifier { stop: Token },
+    #[error("redefinition of '{name}'")]
+    Redefinition { name: String, span: Span },
+    #[error("lvalue required as {what}")]
+    NotLvalue { what: &'static str, span: Span },
+    #[error("cannot dereference a value of type '{ty}'")]
+    InvalidDeref { ty: Type, span: Span },
+    #[error("variable '{name}' declared void")]
+    VoidVariable { name: String, span: Span },
+    #[error("called object of type '{ty}' is not a function or function pointer")]
+    NotCallable { ty: Type, span: Span },
+    #[error("too {} arguments to function of type '{ty}'", if *found > *expected { "many" } else { "few" })]
+    ArgumentCount {
+        ty: Type,
+        expected: usize,
+        found: usize,
+        span: Span,
+    },
+    #[error("functions with more than {max} arguments are not supported")]
+    TooManyArguments { max: usize, span: Span },
+    #[error("assignment of read-only {what}")]
+    ReadOnly { what: String, span: Span },
+    #[error("invalid operands to {op} ('{lhs}' and '{rhs}')")]
+    InvalidOperands {
+        op: BinaryOp,
+        lhs: Type,
+        rhs: Type,
+        span: Span,
+    },
+    #[error("invalid constraint '{constraint}' in asm")]
+    InvalidAsmConstraint { constraint: String, span: Span },
+    #[error("unknown register name '{clobber}' in asm clobbers")]
+    InvalidAsmClobber { clobber: String, span: Span },
+    #[error("impossible register constraints in asm")]
+    AsmRegisters { span: Span },
+    #[error("invalid operand reference '{operand}' in asm template")]
+    InvalidAsmOperand { operand: String, span: Span },
+    #[error("too many errors, stopping after {0}")]
+    TooManyErrors(usize),
+    #[error("{message}")]
+    Preprocess { span: Span, message: String },
+    #[error("{message}")]
+    Lex { span: Span, message: String },
+    #[error("unterminated comment")]
+    UnterminatedComment { span: Span },
+    /// `stop` is the last token of the declarator.
+    #[error("function definition without a name")]
+    MissingFunctionName { stop: Token },
+    #[error("parameter name omitted in function definition")]
+    ParameterNameOmitted { stop: Token },
+    /// The source ended after `stop`.
+    #[error("expected statement")]
+    ExpectedStatement { stop: Token },
+    #[error("source code is empty")]
+    EmptySource,
+    /// A node is missing something earlier passes always fill in.
+    #[error("internal compiler error: malformed {0}")]
+    Internal(&'static str),
+    #[error("'return' with a value, in function returning void")]
+    VoidReturnValue { span: Span },
+    /// `span` is the expression of type `void`.
+    #[error("void value not ignored as it ought to be")]
+    VoidValue { span: Span },
+    /// A function is declared again with a different type.
+    #[error("conflicting types for '{name}'")]
+    ConflictingTypes { name: String, span: Span },
+}
+
+impl CompileError {
+    /// The source the error is about, if it is known.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            CompileError::Unexpected { stop, .. }
+            | CompileError::InvalidTypeSpecifier { stop }
+            | CompileError::MissingFunctionName { stop }
+            | CompileError::ParameterNameOmitted { stop } => Some(stop.span().clone()),
+            // Points just past the last token.
+            CompileError::Expected { stop, .. } | CompileError::ExpectedStatement { stop } => {
+                Some(Span {
+                    start: stop.span().end,
+                    ..stop.span().clone()
+                })
+            }
+            CompileError::Unclosed { error, .. } => error.span(),
+            CompileError::Preprocess { span, .. }
+            | CompileError::Lex { span, .. }
+            | CompileError::UnterminatedComment { span }
+            | CompileError::Redefinition { span, .. }
+            | CompileError::NotLvalue { span, .. }
+            | CompileError::InvalidDeref { span, .. }
+            | CompileError::VoidVariable { span, .. }
+            | CompileError::NotCallable { span, .. }
+            | CompileError::ArgumentCount { span, .. }
+            | CompileError::TooManyArguments { span, .. }
+            | CompileError::ReadOnly { span, .. }
+            | CompileError::InvalidOperands { span, .. }
+            | CompileError::InvalidAsmConstraint { span, .. }
+            | CompileError::InvalidAsmClobber { span, .. }
+            | CompileError::AsmRegisters { span }
+            | CompileError::InvalidAsmOperand { span, .. }
+            | CompileError::VoidReturnValue { span }
+            | CompileError::VoidValue { span }
+            | CompileError::ConflictingTypes { span, .. } => Some(span.clone()),
+            CompileError::TooManyErrors(_)
+            | CompileError::EmptySource
+            | CompileError::Internal(_) => None,
+        }
+    }
+
+    /// A code that identifies the kind of error and never changes, so that
+    /// it can be searched for and passed to `--explain`. New variants take
+    /// the next free number; numbers are never reused.
+    pub fn code(&self) -> &'static str {
+        match self {
+            CompileError::Unexpected { .. } => "C0001",
+            CompileError::Expected { .. } => "C0002",
+            CompileError::Unclosed { .. } => "C0003",
+            CompileError::InvalidTypeSpecifier { .. } => "C0004",
+            CompileError::Redefinition { .. } => "C0005",
+            CompileError::NotLvalue { .. } => "C0006",
+            CompileError::InvalidDeref { .. } => "C0007",
+            CompileError::VoidVariable { .. } => "C0008",
+            CompileError::NotCallable { .. } => "C0009",
+            CompileError::ArgumentCount { .. } => "C0010",
+            CompileError::TooManyArguments { .. } => "C0011",
+            CompileError::ReadOnly { .. } => "C0012",
+            CompileError::InvalidOperands { .. } => "C0013",
+            CompileError::InvalidAsmConstraint { .. } => "C0014",
+            CompileError::InvalidAsmClobber { .. } => "C0015",
+            CompileError::AsmRegisters { .. } => "C0016",
+            CompileError::InvalidAsmOperand { .. } => "C0017",
+            CompileError::TooManyErrors(_) => "C0018",
+            CompileError::Preprocess { .. } => "C0019",
+            CompileError::Lex { .. } => "C0020",
+            CompileError::UnterminatedComment { .. } => "C0021",
+            CompileError::MissingFunctionName { .. } => "C0022",
+            CompileError::ParameterNameOmitted { .. } => "C0023",
+            CompileError::ExpectedStatement { .. } => "C0024",
+            CompileError::EmptySource => "C0025",
+            CompileError::Internal(_) => "C0026",
+            CompileError::VoidReturnValue { .. } => "C0027",
+            CompileError::VoidValue { .. } => "C0028",
+            CompileError::ConflictingTypes { .. } => "C0029",
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum CompileWarning {
+    /// `span` is the expression being converted.
+    #[error("implicit conversion from '{from}' to '{to}' may change value")]
+    Conversion { from: Type, to: Type, span: Span },
+    #[error("implicit conversion from '{from}' to '{to}' changes value from {value} to {result}")]
+    Overflow {
+        from: Type,
+        to: Type,
+        value: i64,
+        result: i64,
+        span: Span,
+    },
+    #[error("implicit conversion from '{from}' to '{to}' without a cast")]
+    PointerConversion { from: Type, to: Type, span: Span },
+    #[error("implicit conversion from '{from}' to '{to}' discards qualifiers")]
+    DiscardsQualifiers { from: Type, to: Type, span: Span },
+    #[error("comparison of integer expressions of different signedness: '{lhs}' and '{rhs}'")]
+    SignCompare { lhs: Type, rhs: Type, span: Span },
+    /// `span` is the declaration.
+    #[error("unused variable '{name}'")]
+    UnusedVariable { name: String, span: Span },
+    #[error("statement with no effect")]
+    UnusedValue { span: Span },
+    #[error("suggest parentheses around assignment used as truth value")]
+    AssignmentInCondition { span: Span },
+    /// `span` is the first statement that is never executed.
+    #[error("statement will never be executed")]
+    Unreachable { span: Span },
+    /// `span` is the name of the function.
+    #[error("implicit declaration of function '{name}'")]
+    ImplicitDeclaration { name: String, span: Span },
+}
+
+impl CompileWarning {
+    /// The name used to turn the warning on and off, one of
+    /// `warning::CATEGORIES`.
+    pub fn category(&self) -> &'static str {
+        match self {
+            CompileWarning::Conversion { .. } => "conversion",
+            CompileWarning::Overflow { .. } => "overflow",
+            CompileWarning::PointerConversion { .. } => "int-conversion",
+            CompileWarning::DiscardsQualifiers { .. } => "discarded-qualifiers",
+            CompileWarning::SignCompare { .. } => "sign-compare",
+            CompileWarning::UnusedVariable { .. } => "unused-variable",
+            CompileWarning::UnusedValue { .. } => "unused-value",
+            CompileWarning::AssignmentInCondition { .. } => "parentheses",
+            CompileWarning::Unreachable { .. } => "unreachable-code",
+            CompileWarning::ImplicitDeclaration { .. } => "implicit-function-declaration",
+        }
+    }
+
+    /// The source the warning is about.
+    pub fn span(&self) -> &Span {
+        match self {
+            CompileWarning::Conversion { span, .. }
+            | CompileWarning::Overflow { span, .. }
+            | CompileWarning::PointerConversion { span, .. }
+            | CompileWarning::DiscardsQualifiers { span, .. }
+            | CompileWarning::SignCompare { span, .. }
+            | CompileWarning::UnusedVariable { span, .. }
+            | CompileWarning::UnusedValue { span }
+            | CompileWarning::AssignmentInCondition { span }
+            | CompileWarning::Unreachable { span }
+            | CompileWarning::ImplicitDeclaration { span, .. } => span,
+        }
+    }
 }
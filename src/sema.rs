@@ -0,0 +1,431 @@
+use crate::ast::{Decl, Expr, ExprKind, Item, Stmt, StmtKind};
+use crate::result::{CompileError, CompileWarning};
+use crate::token::Span;
+use crate::types::{add_type_stmt, check_params, Type, TypeKind};
+use crate::visit::{walk_decl_mut, walk_expr_mut, walk_stmt_mut, VisitorMut};
+use anyhow::Result;
+use std::collections::HashMap;
+
+/// What a name refers to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub ty: Type,
+    pub kind: SymbolKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SymbolKind {
+    /// A function, declared or defined, which is visible in every function
+    /// after it.
+    Function,
+    /// A variable in the stack frame of its function, at `rbp - offset`.
+    Local { offset: u32 },
+}
+
+/// The names visible at some point of the program: functions, then the
+/// variables of the function being analyzed, innermost block last. There
+/// are no globals, typedefs or labels in the language yet; statements
+/// outside of any function make up `main`, so their variables are its
+/// locals.
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    /// The type of each function, and whether it has been defined.
+    functions: HashMap<String, (Type, bool)>,
+    scopes: Vec<HashMap<String, Symbol>>,
+    /// The slot most recently given out in the current function.
+    offset: u32,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The symbol `name` refers to, looking through the blocks outwards and
+    /// then at the functions.
+    pub fn lookup(&self, name: &str) -> Option<Symbol> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).cloned())
+            .or_else(|| {
+                self.functions.get(name).map(|(ty, _)| Symbol {
+                    ty: ty.clone(),
+                    kind: SymbolKind::Function,
+                })
+            })
+    }
+
+    /// Declares a function, or defines it if `defined`. Functions may be
+    /// declared any number of times with compatible types, but defined only
+    /// once. `span` is the declaration, for errors.
+    pub fn declare_function(
+        &mut self,
+        name: &str,
+        ty: Type,
+        defined: bool,
+        span: &Span,
+    ) -> Result<()> {
+        if self.scopes.iter().any(|scope| scope.contains_key(name)) {
+            Err(redefinition(name, span))?;
+        }
+        let (ty, defined) = match self.functions.get(name) {
+            Some((_, true)) if defined => Err(redefinition(name, span))?,
+            Some((old, was_defined)) => {
+                let ty =
+                    composite_type(old, &ty).ok_or_else(|| CompileError::ConflictingTypes {
+                        name: name.to_string(),
+                        span: span.clone(),
+                    })?;
+                (ty, defined || *was_defined)
+            }
+            None => (ty, defined),
+        };
+        self.functions.insert(name.to_string(), (ty, defined));
+        Ok(())
+    }
+
+    /// Declares a variable in the innermost block and gives it the next
+    /// stack slot. Each name may be declared once per block, and not at all
+    /// in the outermost block of a function if it names a function.
+    pub fn declare_local(&mut self, name: &str, ty: Type, span: &Span) -> Result<Symbol> {
+        let depth = self.scopes.len().saturating_sub(1);
+        if depth == 0 && self.functions.contains_key(name) {
+            Err(redefinition(name, span))?;
+        }
+        self.declare_in(depth, name, ty, span)
+    }
+
+    /// Declares a variable used without a declaration, which is a `long` in
+    /// the outermost block of the function using it.
+    pub fn declare_implicit(&mut self, name: &str, span: &Span) -> Result<Symbol> {
+        self.declare_in(0, name, Type::LONG, span)
+    }
+
+    fn declare_in(&mut self, depth: usize, name: &str, ty: Type, span: &Span) -> Result<Symbol> {
+        let scope = self
+            .scopes
+            .get_mut(depth)
+            .ok_or(CompileError::Internal("scope"))?;
+        if scope.contains_key(name) {
+            Err(redefinition(name, span))?;
+        }
+        self.offset += 8;
+        let symbol = Symbol {
+            ty,
+            kind: SymbolKind::Local {
+                offset: self.offset,
+            },
+        };
+        scope.insert(name.to_string(), symbol.clone());
+        Ok(symbol)
+    }
+
+    pub fn enter_block(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    pub fn leave_block(&mut self) {
+        self.scopes.pop();
+    }
+
+    /// Starts a function with an empty frame, returning the one that was
+    /// being analyzed, to be given back to `leave_function`.
+    fn enter_function(&mut self) -> (Vec<HashMap<String, Symbol>>, u32) {
+        let outer = (std::mem::take(&mut self.scopes), self.offset);
+        self.offset = 0;
+        self.enter_block();
+        outer
+    }
+
+    fn leave_function(&mut self, (scopes, offset): (Vec<HashMap<String, Symbol>>, u32)) {
+        self.scopes = scopes;
+        self.offset = offset;
+    }
+}
+
+/// The type of a function declared both as `old` and as `new`, if the two
+/// are compatible: they return the same type, and take the same parameters
+/// unless one of them leaves the parameters unspecified, as `int f()` does.
+/// Qualifiers on the parameters themselves do not matter.
+fn composite_type(old: &Type, new: &Type) -> Option<Type> {
+    let (
+        TypeKind::Func {
+            ret: old_ret,
+            params: old_params,
+        },
+        TypeKind::Func {
+            ret: new_ret,
+            params: new_params,
+        },
+    ) = (&old.kind, &new.kind)
+    else {
+        return None;
+    };
+    if old_ret.unqualified() != new_ret.unqualified() {
+        return None;
+    }
+    match (old_params, new_params) {
+        (Some(old_params), Some(new_params)) => {
+            let same = old_params.len() == new_params.len()
+                && old_params
+                    .iter()
+                    .zip(new_params)
+                    .all(|(old, new)| old.unqualified() == new.unqualified());
+            same.then(|| new.clone())
+        }
+        (Some(_), None) => Some(old.clone()),
+        (None, _) => Some(new.clone()),
+    }
+}
+
+fn redefinition(name: &str, span: &Span) -> CompileError {
+    CompileError::Redefinition {
+        name: name.to_string(),
+        span: span.clone(),
+    }
+}
+
+/// Resolves every name in the program and adds types. Afterwards each
+/// variable has its stack slot and declared type, and each function name its
+/// type, so that `gen` needs no tables of its own.
+pub fn analyze(program: &mut [Item], warnings: &mut Vec<CompileWarning>) -> Result<()> {
+    let mut table = SymbolTable::new();
+    // The frame of the implicit `main`.
+    table.enter_block();
+
+    for item in program.iter_mut() {
+        match item {
+            Item::Stmt(stmt) => {
+                Resolver::run(&mut table, warnings, |resolver| {
+                    resolver.visit_stmt_mut(stmt)
+                })?;
+                // The implicit `main` returns `int`.
+                add_type_stmt(stmt, &Type::INT, warnings)?;
+            }
+            Item::Function(function) => {
+                check_params(&function.ty, &function.span)?;
+                table.declare_function(
+                    &function.name,
+                    function.ty.clone(),
+                    true,
+                    &function.span,
+                )?;
+                // The parameters and the outermost block of the body share
+                // a scope.
+                let outer = table.enter_function();
+                let resolved = Resolver::run(&mut table, warnings, |resolver| {
+                    resolver.visit_function_mut(function)
+                });
+                table.leave_function(outer);
+                resolved?;
+
+                let TypeKind::Func { ret, .. } = &function.ty.kind else {
+                    Err(CompileError::Internal("function type"))?
+                };
+                for stmt in function.body.iter_mut() {
+                    add_type_stmt(stmt, ret, warnings)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Gives the variable `decl` declares its slot.
+fn declare(decl: &mut Decl, table: &mut SymbolTable) -> Result<()> {
+    let symbol = table.declare_local(&decl.name, decl.ty.clone(), &decl.span)?;
+    if let SymbolKind::Local { offset } = symbol.kind {
+        decl.offset = offset;
+    }
+    Ok(())
+}
+
+/// Declares names and resolves their uses, in the order they appear. The
+/// first error is kept, and nothing is resolved after it.
+struct Resolver<'a> {
+    table: &'a mut SymbolTable,
+    warnings: &'a mut Vec<CompileWarning>,
+    error: Option<anyhow::Error>,
+}
+
+impl<'a> Resolver<'a> {
+    fn run(
+        table: &'a mut SymbolTable,
+        warnings: &'a mut Vec<CompileWarning>,
+        visit: impl FnOnce(&mut Self),
+    ) -> Result<()> {
+        let mut resolver = Resolver {
+            table,
+            warnings,
+            error: None,
+        };
+        visit(&mut resolver);
+        match resolver.error {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    fn check<T>(&mut self, result: Result<T>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(error) => {
+                self.error.get_or_insert(error);
+                None
+            }
+        }
+    }
+}
+
+impl VisitorMut for Resolver<'_> {
+    fn visit_stmt_mut(&mut self, stmt: &mut Stmt) {
+        if self.error.is_some() {
+            return;
+        }
+        if let StmtKind::Block(_) = stmt.kind {
+            self.table.enter_block();
+            walk_stmt_mut(self, stmt);
+            self.table.leave_block();
+        } else {
+            walk_stmt_mut(self, stmt);
+        }
+    }
+
+    fn visit_decl_mut(&mut self, decl: &mut Decl) {
+        let declared = check_params(&decl.ty, &decl.span).and_then(|()| {
+            if decl.ty.is_func() {
+                self.table
+                    .declare_function(&decl.name, decl.ty.clone(), false, &decl.span)
+            } else {
+                declare(decl, self.table)
+            }
+        });
+        // The variable is in scope in its own initializer.
+        if self.check(declared).is_some() {
+            walk_decl_mut(self, decl);
+        }
+    }
+
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        if self.error.is_some() {
+            return;
+        }
+        match &mut expr.kind {
+            ExprKind::Var { name, offset } => {
+                let found = match self.table.lookup(name) {
+                    Some(found) => found,
+                    None => {
+                        let declared = self.table.declare_implicit(name, &expr.span);
+                        let Some(found) = self.check(declared) else {
+                            return;
+                        };
+                        found
+                    }
+                };
+                if let SymbolKind::Local { offset: slot } = found.kind {
+                    *offset = slot;
+                }
+                expr.ty = Some(found.ty);
+            }
+            // Calling an undeclared identifier implicitly declares `int f()`.
+            ExprKind::Call { callee, .. } => {
+                if let ExprKind::Var { name, .. } = &callee.kind {
+                    if self.table.lookup(name).is_none() {
+                        self.warnings.push(CompileWarning::ImplicitDeclaration {
+                            name: name.clone(),
+                            span: callee.span.clone(),
+                        });
+                        let declared = self.table.declare_function(
+                            name,
+                            Type::func(Type::INT, None),
+                            false,
+                            &callee.span,
+                        );
+                        self.check(declared);
+                    }
+                }
+                walk_expr_mut(self, expr);
+            }
+            _ => walk_expr_mut(self, expr),
+        }
+    }
+}
+
+#[test]
+fn test_analyze() {
+    use crate::ast::dump_ast;
+    use crate::node::program;
+    use crate::token::tokenize;
+    let analyzed = |source: &str| {
+        let mut tokenstream = tokenize(vec![source.to_string()])?;
+        let mut program = program(&mut tokenstream)?;
+        analyze(&mut program, &mut Vec::new())?;
+        Ok::<_, anyhow::Error>(dump_ast(&program))
+    };
+    // The slot of every name, in the order `dump_ast` shows them. Function
+    // names have none.
+    let slots = |source: &str| {
+        let dump = analyzed(source).unwrap();
+        let names = dump.lines().filter_map(|line| {
+            let line = line.trim_start();
+            let rest = line
+                .strip_prefix("Var ")
+                .or_else(|| line.strip_prefix("Decl "))?;
+            let (name, rest) = rest.split_once([' ', ':']).unwrap_or((rest, ""));
+            let offset = rest
+                .strip_prefix("[rbp - ")
+                .and_then(|rest| rest.split_once(']'))
+                .map_or("0", |(offset, _)| offset);
+            Some(format!("{name}@{offset}"))
+        });
+        names.collect::<Vec<_>>().join(" ")
+    };
+
+    // Each function has a frame of its own.
+    assert_eq!(
+        slots("int f(int a) { int b; return a + b; } int x = f(1);"),
+        "a@8 b@16 a@8 b@16 x@8 f@0"
+    );
+    // An inner block may shadow a name, and its variable gets a new slot.
+    assert_eq!(
+        slots("int a = 1; { int a = 2; a; } a;"),
+        "a@8 a@16 a@16 a@8"
+    );
+    // Undeclared variables are `long`s of the function using them.
+    assert_eq!(slots("{ b = 1; } b;"), "b@8 b@8");
+    assert!(analyzed("b = 1;")
+        .unwrap()
+        .contains("Var b [rbp - 8]: long"));
+
+    let redefined = |source: &str| match analyzed(source) {
+        Err(error) => matches!(
+            error.downcast_ref::<CompileError>(),
+            Some(CompileError::Redefinition { .. })
+        ),
+        Ok(_) => false,
+    };
+    assert!(redefined("int a; int a;"));
+    assert!(redefined("int f(int a) { int a; return a; }"));
+    assert!(redefined("int f(); int f;"));
+    assert!(!redefined("int a; { int a; }"));
+    assert!(!redefined("int f(); int f() { return 1; }"));
+    assert!(redefined("int f() { return 1; } int f() { return 2; }"));
+
+    let conflicting = |source: &str| match analyzed(source) {
+        Err(error) => matches!(
+            error.downcast_ref::<CompileError>(),
+            Some(CompileError::ConflictingTypes { .. })
+        ),
+        Ok(_) => false,
+    };
+    assert!(conflicting(
+        "int f(int); long f(int a, int b) { return a; }"
+    ));
+    assert!(conflicting("int f(int); long f(int);"));
+    assert!(conflicting("int f(int); int f(char);"));
+    assert!(!conflicting("int f(int); int f(const int a) { return a; }"));
+    assert!(!conflicting("int f(); int f(int a) { return a; } int f();"));
+    assert!(analyzed("g(1); int g(int a) { return a; }").is_ok());
+}
@@ -1,9 +1,7 @@
 use crate::result::CompileError;
 use anyhow::Result;
-use std::collections::HashMap;
 use std::fmt::Display;
-use std::iter::Peekable;
-use std::vec::IntoIter;
+use std::sync::Arc;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum TokenKind {
@@ -33,8 +31,8 @@ pub enum TokenKind {
     GreaterOrEqual,
     /// Number | 1, 2, ... , 255
     Num(u32),
-    /// Local variable | (a..z | A..Z | _)(a..z | A..Z | _ | 0..9)*
-    LocalVar { symbol: String, offset: u32 },
+    /// Identifier | (a..z | A..Z | _)(a..z | A..Z | _ | 0..9)*
+    Ident(String),
     /// Semicolon | ;
     Semicolon,
     /// Assign | =
@@ -45,41 +43,146 @@ pub enum TokenKind {
     If,
     /// Else | else
     Else,
+    /// Address | &
+    Addr,
+    /// Char | char
+    Char,
+    /// Short | short
+    Short,
+    /// Int | int
+    Int,
+    /// Long | long
+    Long,
+    /// Signed | signed
+    Signed,
+    /// Unsigned | unsigned
+    Unsigned,
+    /// Const | const
+    Const,
+    /// Volatile | volatile
+    Volatile,
+    /// Bool | _Bool
+    Bool,
+    /// Void | void
+    Void,
+    /// Comma | ,
+    Comma,
+    /// Left hand curly Bracket | {
+    LCurlyBracket,
+    /// Right hand curly Bracket | }
+    RCurlyBracket,
+    /// Colon | :
+    Colon,
+    /// String literal | "..."
+    Str(String),
+    /// Asm | asm, __asm__
+    Asm,
+    /// Remainder | %
+    Mod,
+    /// Logical not | !
+    Not,
+    /// Logical and | &&
+    LogicalAnd,
+    /// Logical or | ||
+    LogicalOr,
+    /// Question mark | ?
+    Question,
+    /// Hash | # (preprocessing only)
+    Hash,
+    /// Hash hash | ## (preprocessing only)
+    HashHash,
 }
 
-fn digits(mut x: u32) -> u32 {
-    let mut count = 0;
-    while x > 0 {
-        count += 1;
-        x /= 10;
+impl TokenKind {
+    /// The token as it is written in source code.
+    pub fn spelling(&self) -> String {
+        let spelling = match self {
+            TokenKind::Num(num) => return num.to_string(),
+            TokenKind::Ident(symbol) => return symbol.clone(),
+            TokenKind::Str(string) => {
+                let mut spelling = String::from('"');
+                for c in string.chars() {
+                    match c {
+                        '\n' => spelling.push_str("\\n"),
+                        '\t' => spelling.push_str("\\t"),
+                        '\r' => spelling.push_str("\\r"),
+                        '\0' => spelling.push_str("\\0"),
+                        '\\' | '"' => {
+                            spelling.push('\\');
+                            spelling.push(c);
+                        }
+                        c => spelling.push(c),
+                    }
+                }
+                spelling.push('"');
+                return spelling;
+            }
+            TokenKind::Add => "+",
+            TokenKind::Sub => "-",
+            TokenKind::Mul => "*",
+            TokenKind::Div => "/",
+            TokenKind::LRoundBracket => "(",
+            TokenKind::RRoundBracket => ")",
+            TokenKind::Equal => "==",
+            TokenKind::NEqual => "!=",
+            TokenKind::Less => "<",
+            TokenKind::Greater => ">",
+            TokenKind::LessOrEqual => "<=",
+            TokenKind::GreaterOrEqual => ">=",
+            TokenKind::Semicolon => ";",
+            TokenKind::Assign => "=",
+            TokenKind::Return => "return",
+            TokenKind::If => "if",
+            TokenKind::Else => "else",
+            TokenKind::Addr => "&",
+            TokenKind::Char => "char",
+            TokenKind::Short => "short",
+            TokenKind::Int => "int",
+            TokenKind::Long => "long",
+            TokenKind::Signed => "signed",
+            TokenKind::Unsigned => "unsigned",
+            TokenKind::Const => "const",
+            TokenKind::Volatile => "volatile",
+            TokenKind::Bool => "_Bool",
+            TokenKind::Void => "void",
+            TokenKind::Comma => ",",
+            TokenKind::LCurlyBracket => "{",
+            TokenKind::RCurlyBracket => "}",
+            TokenKind::Colon => ":",
+            TokenKind::Asm => "asm",
+            TokenKind::Mod => "%",
+            TokenKind::Not => "!",
+            TokenKind::LogicalAnd => "&&",
+            TokenKind::LogicalOr => "||",
+            TokenKind::Question => "?",
+            TokenKind::Hash => "#",
+            TokenKind::HashHash => "##",
+        };
+        spelling.to_string()
     }
-    count
-}
 
-#[test]
-fn test_digits() {
-    assert_eq!(digits(32), 2);
-    assert_eq!(digits(10923480), 8);
-    assert_eq!(digits(0), 0);
-}
+    /// Whether the token is an integer type specifier, e.g. `unsigned` or `char`.
+    pub fn is_type_specifier(&self) -> bool {
+        matches!(
+            self,
+            TokenKind::Char
+                | TokenKind::Short
+                | TokenKind::Int
+                | TokenKind::Long
+                | TokenKind::Signed
+                | TokenKind::Unsigned
+                | TokenKind::Bool
+                | TokenKind::Void
+        )
+    }
 
-impl TokenKind {
-    pub fn len(&self) -> u32 {
-        match self {
-            TokenKind::Add | TokenKind::Sub | TokenKind::Mul | TokenKind::Div => 1,
-            TokenKind::LRoundBracket | TokenKind::RRoundBracket => 1,
-            TokenKind::Less | TokenKind::Greater => 1,
-            TokenKind::Semicolon | TokenKind::Assign => 1,
-            TokenKind::Equal
-            | TokenKind::NEqual
-            | TokenKind::LessOrEqual
-            | TokenKind::If
-            | TokenKind::GreaterOrEqual => 2,
-            TokenKind::Num(num) => digits(*num),
-            TokenKind::LocalVar { symbol, .. } => symbol.len() as u32,
-            TokenKind::Else => 4,
-            TokenKind::Return => 6,
-        }
+    pub fn is_type_qualifier(&self) -> bool {
+        matches!(self, TokenKind::Const | TokenKind::Volatile)
+    }
+
+    /// Whether the token starts a type name, e.g. `const unsigned char`.
+    pub fn is_type_name(&self) -> bool {
+        self.is_type_specifier() || self.is_type_qualifier()
     }
 }
 
@@ -99,26 +202,99 @@ impl Display for TokenKind {
             TokenKind::Greater => write!(f, "Operation: >"),
             TokenKind::LessOrEqual => write!(f, "Operation: <="),
             TokenKind::GreaterOrEqual => write!(f, "Operation: >="),
-            TokenKind::LocalVar { .. } => write!(f, "Variable"),
+            TokenKind::Ident(_) => write!(f, "Identifier"),
             TokenKind::Semicolon => write!(f, "Semicolon: ;"),
             TokenKind::Assign => write!(f, "Assign: ="),
             TokenKind::If => write!(f, "If"),
             TokenKind::Return => write!(f, "Return"),
             TokenKind::Else => write!(f, "Else"),
+            TokenKind::Addr => write!(f, "Operation: &"),
+            TokenKind::Char => write!(f, "Type: char"),
+            TokenKind::Short => write!(f, "Type: short"),
+            TokenKind::Int => write!(f, "Type: int"),
+            TokenKind::Long => write!(f, "Type: long"),
+            TokenKind::Signed => write!(f, "Type: signed"),
+            TokenKind::Unsigned => write!(f, "Type: unsigned"),
+            TokenKind::Const => write!(f, "Qualifier: const"),
+            TokenKind::Volatile => write!(f, "Qualifier: volatile"),
+            TokenKind::Bool => write!(f, "Type: _Bool"),
+            TokenKind::Void => write!(f, "Type: void"),
+            TokenKind::Comma => write!(f, "Comma: ,"),
+            TokenKind::LCurlyBracket => write!(f, "Operation: {{"),
+            TokenKind::RCurlyBracket => write!(f, "Operation: }}"),
+            TokenKind::Colon => write!(f, "Colon: :"),
+            TokenKind::Str(_) => write!(f, "String"),
+            TokenKind::Asm => write!(f, "Asm"),
+            TokenKind::Mod => write!(f, "Operation: %"),
+            TokenKind::Not => write!(f, "Operation: !"),
+            TokenKind::LogicalAnd => write!(f, "Operation: &&"),
+            TokenKind::LogicalOr => write!(f, "Operation: ||"),
+            TokenKind::Question => write!(f, "Operation: ?"),
+            TokenKind::Hash => write!(f, "Hash: #"),
+            TokenKind::HashHash => write!(f, "Hash hash: ##"),
         }
     }
 }
 
+/// The bytes of a file a token was read from.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct Span {
+    /// Empty for source without a name.
+    pub file: Arc<str>,
+    /// Byte offset of the first byte in the file.
+    pub start: usize,
+    /// Byte offset just past the last byte.
+    pub end: usize,
+}
+
+impl Span {
+    /// From the start of this span to the end of `end`. Spans in different
+    /// files, such as one from an included file, do not join.
+    pub fn to(&self, end: &Span) -> Span {
+        if self.file != end.file || end.end < self.start {
+            return self.clone();
+        }
+        Span {
+            file: self.file.clone(),
+            start: self.start,
+            end: end.end,
+        }
+    }
+}
+
+/// What comes between tokens. Only `lex_with_trivia` keeps it; everywhere
+/// else comments are blanked out and whitespace is skipped.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Trivia {
+    /// Spaces and tabs.
+    Space(String),
+    /// The end of a line.
+    Newline,
+    /// A `// ...` or `/* ... */` comment as written, which may span lines.
+    Comment(String),
+    /// A preprocessing directive, with any lines it continues onto.
+    Directive(String),
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Token {
     col: u32,
     row: u32,
+    span: Span,
     kind: TokenKind,
+    /// The trivia between the previous token and this one.
+    leading: Vec<Trivia>,
 }
 
 impl Token {
     pub fn new(col: u32, row: u32, kind: TokenKind) -> Self {
-        Self { col, row, kind }
+        Self {
+            col,
+            row,
+            span: Span::default(),
+            kind,
+            leading: Vec::new(),
+        }
     }
 
     pub fn col(&self) -> u32 {
@@ -129,45 +305,46 @@ impl Token {
         self.row
     }
 
+    pub fn file(&self) -> &str {
+        &self.span.file
+    }
+
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
     pub fn kind(&self) -> TokenKind {
         self.kind.clone()
     }
-}
 
-fn num_token(s: &str) -> Result<(u32, usize)> {
-    let mut result = (&s[0..1])
-        .parse::<u32>()
-        .or_else(|_| Err(CompileError::ParseError(Some("Number"))))?;
-    if s.len() <= 1 {
-        return Ok((result, 1));
+    pub fn leading(&self) -> &[Trivia] {
+        &self.leading
     }
-    let mut count = 1;
 
-    while let Ok(num) = (&s[count..count + 1]).parse::<u32>() {
-        result = result * 10 + num;
-        count += 1;
-        if count >= s.len() {
-            break;
+    /// The same token, as if it had been read at `at`. Used for tokens that
+    /// come out of a macro expansion.
+    pub fn relocate(&self, at: &Token) -> Token {
+        Token {
+            col: at.col,
+            row: at.row,
+            span: at.span.clone(),
+            kind: self.kind.clone(),
+            leading: Vec::new(),
         }
     }
-    Ok((result, count))
-}
-
-fn var_token(line: &str) -> Option<(String, usize)> {
-    if is_var_first(line.chars().next()?) {
-        let var = line.split_once(|c| !is_alnum(c)).unwrap().0;
-        Some((var.to_string(), var.len()))
-    } else {
-        None
-    }
 }
 
 fn is_var_first(c: char) -> bool {
-    'a' <= c && c <= 'z' || 'A' <= c && c <= 'Z' || c == '_'
+    c.is_ascii_lowercase() || c.is_ascii_uppercase() || c == '_'
 }
 
 fn is_alnum(c: char) -> bool {
-    is_var_first(c) || '0' <= c && c <= '9'
+    is_var_first(c) || c.is_ascii_digit()
+}
+
+/// Whitespace within a line.
+fn is_space(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\r' | '\x0b' | '\x0c')
 }
 
 #[test]
@@ -179,213 +356,583 @@ fn test_match_word() {
 #[derive(Debug)]
 pub struct TokenStream {
     token: Token,
-    stream: Peekable<IntoIter<Token>>,
+    stream: Vec<Token>,
+    pos: usize,
+    /// Syntax errors the parser has recovered from.
+    errors: Vec<anyhow::Error>,
+    max_errors: usize,
 }
 
-pub fn six_word_token(tokens: &mut Vec<Token>, line: &String, row: usize, col: &mut usize) -> bool {
-    match &line[*col..*col + 6] {
-        "return" if !is_alnum(line[*col + 6..].chars().next().unwrap()) => {
-            tokens.push(Token::new(*col as u32, row as u32, TokenKind::Return));
-            *col += 6;
-            true
-        }
-        _ => four_word_token(tokens, line, row, col),
-    }
+/// How many syntax errors are reported by default before giving up.
+pub const MAX_ERRORS: usize = 20;
+
+/// Keywords, which the lexer reads as identifiers so that macros can have
+/// the same names. `classify` tells them apart afterwards.
+const KEYWORDS: [(&str, TokenKind); 17] = [
+    ("return", TokenKind::Return),
+    ("if", TokenKind::If),
+    ("else", TokenKind::Else),
+    ("char", TokenKind::Char),
+    ("short", TokenKind::Short),
+    ("int", TokenKind::Int),
+    ("long", TokenKind::Long),
+    ("signed", TokenKind::Signed),
+    ("unsigned", TokenKind::Unsigned),
+    ("const", TokenKind::Const),
+    ("volatile", TokenKind::Volatile),
+    ("__volatile__", TokenKind::Volatile),
+    // `bool`, `true` and `false` come from <stdbool.h>.
+    ("_Bool", TokenKind::Bool),
+    ("void", TokenKind::Void),
+    ("asm", TokenKind::Asm),
+    ("__asm", TokenKind::Asm),
+    ("__asm__", TokenKind::Asm),
+];
+
+fn keyword(word: &str) -> Option<TokenKind> {
+    KEYWORDS
+        .iter()
+        .find(|(keyword, _)| *keyword == word)
+        .map(|(_, kind)| kind.clone())
 }
 
-pub fn four_word_token(
-    tokens: &mut Vec<Token>,
-    line: &String,
-    row: usize,
-    col: &mut usize,
-) -> bool {
-    match &line[*col..*col + 4] {
-        "else" if !is_alnum(line[*col + 4..].chars().next().unwrap()) => {
-            tokens.push(Token::new(*col as u32, row as u32, TokenKind::Else));
-            *col += 4;
-            true
+/// Punctuators, longest first so that the first match is the longest one.
+const PUNCTUATORS: [(&str, TokenKind); 26] = [
+    ("##", TokenKind::HashHash),
+    ("==", TokenKind::Equal),
+    ("!=", TokenKind::NEqual),
+    ("<=", TokenKind::LessOrEqual),
+    (">=", TokenKind::GreaterOrEqual),
+    ("&&", TokenKind::LogicalAnd),
+    ("||", TokenKind::LogicalOr),
+    ("+", TokenKind::Add),
+    ("-", TokenKind::Sub),
+    ("*", TokenKind::Mul),
+    ("/", TokenKind::Div),
+    ("%", TokenKind::Mod),
+    ("(", TokenKind::LRoundBracket),
+    (")", TokenKind::RRoundBracket),
+    ("{", TokenKind::LCurlyBracket),
+    ("}", TokenKind::RCurlyBracket),
+    ("<", TokenKind::Less),
+    (">", TokenKind::Greater),
+    (";", TokenKind::Semicolon),
+    ("=", TokenKind::Assign),
+    ("&", TokenKind::Addr),
+    (",", TokenKind::Comma),
+    (":", TokenKind::Colon),
+    ("!", TokenKind::Not),
+    ("?", TokenKind::Question),
+    ("#", TokenKind::Hash),
+];
+
+/// Joins lines ending in a backslash with the next one and blanks out
+/// comments, ahead of preprocessing. Positions stay where they were: a
+/// spliced line leaves an empty line behind and every byte of a comment
+/// turns into a space, so a block comment spanning lines also ends a
+/// directive it starts on.
+pub fn remove_comments(file: &str, source: &[String]) -> Result<Vec<String>> {
+    let mut lines = Vec::with_capacity(source.len());
+    let mut current = String::new();
+    let mut spliced = 0;
+    for line in source {
+        current.push_str(line.strip_suffix('\r').unwrap_or(line));
+        if current.ends_with('\\') {
+            current.pop();
+            spliced += 1;
+            continue;
         }
-        _ => two_word_token(tokens, line, row, col),
+        lines.push(std::mem::take(&mut current));
+        lines.extend(std::iter::repeat_n(String::new(), spliced));
+        spliced = 0;
+    }
+    if spliced > 0 {
+        lines.push(current);
+        lines.extend(std::iter::repeat_n(String::new(), spliced - 1));
     }
-}
 
-pub fn two_word_token(tokens: &mut Vec<Token>, line: &String, row: usize, col: &mut usize) -> bool {
-    match &line[*col..*col + 2] {
-        "==" => {
-            tokens.push(Token::new(*col as u32, row as u32, TokenKind::Equal));
-            *col += 2;
-            true
-        }
-        "!=" => {
-            tokens.push(Token::new(*col as u32, row as u32, TokenKind::NEqual));
-            *col += 2;
-            true
-        }
-        ">=" => {
-            tokens.push(Token::new(
-                *col as u32,
-                row as u32,
-                TokenKind::GreaterOrEqual,
-            ));
-            *col += 2;
-            true
-        }
-        "<=" => {
-            tokens.push(Token::new(*col as u32, row as u32, TokenKind::LessOrEqual));
-            *col += 2;
-            true
-        }
-        "if" => {
-            tokens.push(Token::new(*col as u32, row as u32, TokenKind::If));
-            *col += 2;
-            true
+    // Where the open block comment started, if any.
+    let mut block: Option<(usize, usize)> = None;
+    for (row, line) in lines.iter_mut().enumerate() {
+        let mut result = String::with_capacity(line.len());
+        let mut quote = None;
+        let mut chars = line.char_indices().peekable();
+        while let Some((col, c)) = chars.next() {
+            let blank = " ".repeat(c.len_utf8());
+            if block.is_some() {
+                if c == '*' && chars.next_if(|&(_, c)| c == '/').is_some() {
+                    result.push_str("  ");
+                    block = None;
+                } else {
+                    result.push_str(&blank);
+                }
+                continue;
+            }
+            match (quote, c) {
+                (Some(_), '\\') => {
+                    result.push(c);
+                    if let Some((_, escaped)) = chars.next() {
+                        result.push(escaped);
+                    }
+                }
+                (Some(open), c) if c == open => {
+                    result.push(c);
+                    quote = None;
+                }
+                (Some(_), c) => result.push(c),
+                (None, '"' | '\'') => {
+                    result.push(c);
+                    quote = Some(c);
+                }
+                (None, '/') if chars.next_if(|&(_, c)| c == '/').is_some() => {
+                    result.push_str(&" ".repeat(line.len() - col));
+                    break;
+                }
+                (None, '/') if chars.next_if(|&(_, c)| c == '*').is_some() => {
+                    result.push_str("  ");
+                    block = Some((row, col));
+                }
+                (None, c) => result.push(c),
+            }
         }
-        _ => one_word_token(tokens, line, row, col),
+        *line = result;
+    }
+
+    if let Some((row, col)) = block {
+        let start = line_offsets(source)[row] + col;
+        Err(CompileError::UnterminatedComment {
+            span: Span {
+                file: Arc::from(file),
+                start,
+                end: start + 2,
+            },
+        })?;
     }
+    Ok(lines)
 }
 
-pub fn one_word_token(tokens: &mut Vec<Token>, line: &String, row: usize, col: &mut usize) -> bool {
-    let word = &line[*col..*col + 1];
-    match word {
-        " " => {
-            *col += 1;
-        }
-        "<" => {
-            tokens.push(Token::new(*col as u32, row as u32, TokenKind::Less));
-            *col += 1;
-        }
-        ">" => {
-            tokens.push(Token::new(*col as u32, row as u32, TokenKind::Greater));
-            *col += 1;
-        }
-        "+" => {
-            tokens.push(Token::new(*col as u32, row as u32, TokenKind::Add));
-            *col += 1;
-        }
-        "-" => {
-            tokens.push(Token::new(*col as u32, row as u32, TokenKind::Sub));
-            *col += 1;
-        }
-        "*" => {
-            tokens.push(Token::new(*col as u32, row as u32, TokenKind::Mul));
-            *col += 1;
-        }
-        "/" => {
-            tokens.push(Token::new(*col as u32, row as u32, TokenKind::Div));
-            *col += 1;
+/// Splits file contents into lines. A `\r` before a newline stays at the end
+/// of its line, so byte offsets into the file can be recovered.
+pub fn split_lines(text: &str) -> Vec<String> {
+    text.split('\n').map(str::to_string).collect()
+}
+
+/// The byte offset in the file of each of `lines`.
+pub fn line_offsets(lines: &[String]) -> Vec<usize> {
+    lines
+        .iter()
+        .scan(0, |offset, line| {
+            let start = *offset;
+            *offset += line.len() + 1;
+            Some(start)
+        })
+        .collect()
+}
+
+/// Reads the tokens of one line of source, moving a cursor over its bytes.
+/// Every token is as long as possible, so `a<=b` has a `<=` and `iffy` is
+/// one identifier. Identifiers are left as `TokenKind::Ident` until
+/// `classify`.
+pub struct Lexer<'a> {
+    file: Arc<str>,
+    line: &'a str,
+    row: usize,
+    /// Byte offset of the line in its file.
+    offset: usize,
+    pos: usize,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(file: &Arc<str>, line: &'a str, row: usize, offset: usize) -> Self {
+        Self {
+            file: file.clone(),
+            line,
+            row,
+            offset,
+            pos: 0,
         }
-        "(" => {
-            tokens.push(Token::new(
-                *col as u32,
-                row as u32,
-                TokenKind::LRoundBracket,
-            ));
-            *col += 1;
+    }
+
+    /// Starts reading at byte `col` of the line instead of at its start.
+    pub fn skip_to(mut self, col: usize) -> Self {
+        self.pos = col;
+        self
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.line[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    /// An error about the bytes from `col` to the cursor, or about the
+    /// character at `col` when the cursor has not moved past it.
+    fn error(&self, col: usize, message: String) -> CompileError {
+        let end = self
+            .pos
+            .max(col + self.line[col..].chars().next().map_or(0, char::len_utf8));
+        CompileError::Lex {
+            span: Span {
+                file: self.file.clone(),
+                start: self.offset + col,
+                end: self.offset + end,
+            },
+            message,
         }
-        ")" => {
-            tokens.push(Token::new(
-                *col as u32,
-                row as u32,
-                TokenKind::RRoundBracket,
-            ));
-            *col += 1;
+    }
+
+    pub fn lex(mut self, tokens: &mut Vec<Token>) -> Result<()> {
+        while let Some(c) = self.peek() {
+            if is_space(c) {
+                self.pos += 1;
+                continue;
+            }
+            tokens.push(self.token()?);
         }
-        ";" => {
-            tokens.push(Token::new(*col as u32, row as u32, TokenKind::Semicolon));
-            *col += 1;
+        Ok(())
+    }
+
+    /// Reads the token at the cursor, which is not on whitespace.
+    fn token(&mut self) -> Result<Token> {
+        let start = self.pos;
+        let c = self.peek().unwrap_or_default();
+        let kind = if c.is_ascii_digit() {
+            self.number()?
+        } else if is_var_first(c) {
+            let len = self
+                .rest()
+                .find(|c| !is_alnum(c))
+                .unwrap_or(self.rest().len());
+            self.pos += len;
+            TokenKind::Ident(self.line[start..self.pos].to_string())
+        } else if c == '"' {
+            self.string()?
+        } else if let Some((text, kind)) = PUNCTUATORS
+            .iter()
+            .find(|(text, _)| self.rest().starts_with(text))
+        {
+            self.pos += text.len();
+            kind.clone()
+        } else if c.is_ascii() {
+            Err(self.error(start, format!("stray '{}' in program", c.escape_default())))?
+        } else {
+            Err(self.error(
+                start,
+                format!(
+                    "non-ASCII character '{}' is not allowed outside of strings",
+                    c
+                ),
+            ))?
+        };
+
+        Ok(Token {
+            col: start as u32,
+            row: self.row as u32,
+            span: Span {
+                file: self.file.clone(),
+                start: self.offset + start,
+                end: self.offset + self.pos,
+            },
+            kind,
+            leading: Vec::new(),
+        })
+    }
+
+    fn number(&mut self) -> Result<TokenKind> {
+        let start = self.pos;
+        let len = self
+            .rest()
+            .find(|c| !is_alnum(c))
+            .unwrap_or(self.rest().len());
+        self.pos += len;
+        let text = &self.line[start..self.pos];
+
+        let digits = text
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(text.len());
+        if digits < text.len() {
+            Err(self.error(
+                start,
+                format!("invalid suffix '{}' on integer constant", &text[digits..]),
+            ))?;
         }
-        "=" => {
-            tokens.push(Token::new(*col as u32, row as u32, TokenKind::Assign));
-            *col += 1;
+        let num = text
+            .parse::<u32>()
+            .map_err(|_| self.error(start, "integer constant is too large".to_string()))?;
+        Ok(TokenKind::Num(num))
+    }
+
+    /// Reads a string literal starting at the opening quote and resolves
+    /// its escape sequences.
+    fn string(&mut self) -> Result<TokenKind> {
+        let start = self.pos;
+        let mut result = String::new();
+        let mut chars = self.rest().char_indices().skip(1);
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(TokenKind::Str(result));
+                }
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('n') => result.push('\n'),
+                    Some('t') => result.push('\t'),
+                    Some('r') => result.push('\r'),
+                    Some('0') => result.push('\0'),
+                    Some(c @ ('\\' | '"' | '\'')) => result.push(c),
+                    Some(c) => {
+                        Err(self.error(start + i, format!("unknown escape sequence '\\{}'", c)))?
+                    }
+                    None => break,
+                },
+                c => result.push(c),
+            }
         }
-        _ => return false,
+        Err(self.error(start, "missing terminating '\"' character".to_string()))?
     }
-    true
 }
 
-fn other_word_token(
-    tokens: &mut Vec<Token>,
-    line: &String,
-    variables: &mut HashMap<String, u32>,
-    row: usize,
-    col: &mut usize,
-) -> Result<()> {
-    if let Some((var, len)) = var_token(&line[*col..]) {
-        if let Some(&offset) = variables.get(&var) {
-            tokens.push(Token::new(
-                *col as u32,
-                row as u32,
-                TokenKind::LocalVar {
-                    symbol: var,
-                    offset,
-                },
-            ));
-            *col += len;
-        } else {
-            let offset = (variables.len() as u32 + 1) * 8;
-            tokens.push(Token::new(
-                *col as u32,
-                row as u32,
-                TokenKind::LocalVar {
-                    symbol: var.clone(),
-                    offset,
-                },
-            ));
-            *col += len;
-            variables.insert(var, offset);
+/// Turns identifiers that are keywords into keywords. Every other
+/// identifier is left for `sema` to resolve once the program is parsed.
+pub fn classify(mut tokens: Vec<Token>) -> Result<TokenStream> {
+    for token in tokens.iter_mut() {
+        if let TokenKind::Ident(word) = &token.kind {
+            if let Some(kind) = keyword(word) {
+                token.kind = kind;
+            }
         }
-    } else {
-        let (num, count) = num_token(&line[*col..])?;
-        tokens.push(Token::new(*col as u32, row as u32, TokenKind::Num(num)));
-        *col += count;
     }
 
-    Ok(())
+    if tokens.is_empty() {
+        Err(CompileError::EmptySource)?;
+    }
+
+    Ok(TokenStream {
+        token: tokens[0].clone(),
+        stream: tokens,
+        pos: 0,
+        errors: Vec::new(),
+        max_errors: MAX_ERRORS,
+    })
 }
 
-pub fn tokenize(source: Vec<String>) -> Result<(TokenStream, u32)> {
+/// Tokenizes source that needs no preprocessing.
+pub fn tokenize(source: Vec<String>) -> Result<TokenStream> {
     let mut tokens: Vec<Token> = Vec::new();
-    let mut variables: HashMap<String, u32> = HashMap::new();
 
     // If source code is empty, return parse error.
     if source.is_empty() {
-        Err(CompileError::ParseError(Some("A Source code is empty")))?;
+        Err(CompileError::EmptySource)?;
     }
 
-    for (row, line) in source.into_iter().enumerate() {
+    let file: Arc<str> = Arc::from("");
+    let offsets = line_offsets(&source);
+    for (row, line) in remove_comments("", &source)?.iter().enumerate() {
+        Lexer::new(&file, line, row, offsets[row]).lex(&mut tokens)?;
+    }
+
+    classify(tokens)
+}
+
+/// Reads the tokens of a whole file without preprocessing it, for
+/// `format`. Each token keeps the whitespace, comments and directives before
+/// it as its `leading` trivia, and the trivia after the last token is
+/// returned along with the tokens, so that the file can be put back together
+/// from them. Macro names stay identifiers; keywords are classified as by
+/// `classify`.
+pub fn lex_with_trivia(file: &str, text: &str) -> Result<(Vec<Token>, Vec<Trivia>)> {
+    let file: Arc<str> = Arc::from(file);
+    let mut lines = split_lines(text);
+    let offsets = line_offsets(&lines);
+    let mut tokens = Vec::new();
+    let mut trivia = Vec::new();
+
+    let mut row = 0;
+    while row < lines.len() {
+        if row > 0 {
+            trivia.push(Trivia::Newline);
+        }
+        if lines[row].trim_start().starts_with('#') {
+            let mut directive = lines[row].clone();
+            while directive
+                .strip_suffix('\r')
+                .unwrap_or(&directive)
+                .ends_with('\\')
+                && row + 1 < lines.len()
+            {
+                row += 1;
+                directive.push('\n');
+                directive.push_str(&lines[row]);
+            }
+            trivia.push(Trivia::Directive(directive));
+            row += 1;
+            continue;
+        }
+
+        // A backslash at the end of a line splices the next one onto it,
+        // even in the middle of a token, so the two are lexed as one line.
+        let mut segments = vec![(0, offsets[row])];
+        let mut next = row + 1;
+        while next < lines.len() && lines[row].trim_end_matches('\r').ends_with('\\') {
+            let joined = lines[row].trim_end_matches('\r').len() - 1;
+            let spliced = std::mem::take(&mut lines[next]);
+            lines[row].truncate(joined);
+            lines[row].push_str(&spliced);
+            segments.push((joined, offsets[next]));
+            next += 1;
+        }
+
         let mut col = 0;
-        let max = line.len();
-        loop {
-            let result = match max - col {
-                0 => break,
-                1 => one_word_token(&mut tokens, &line, row, &mut col),
-                2..=4 => two_word_token(&mut tokens, &line, row, &mut col),
-                5..=6 => four_word_token(&mut tokens, &line, row, &mut col),
-                _ => six_word_token(&mut tokens, &line, row, &mut col),
-            };
-
-            if !result {
-                other_word_token(&mut tokens, &line, &mut variables, row, &mut col)?;
+        while col < lines[row].len() {
+            let line = &lines[row];
+            let rest = &line[col..];
+            let spaces = rest.len() - rest.trim_start_matches(is_space).len();
+            if spaces > 0 {
+                trivia.push(Trivia::Space(rest[..spaces].to_string()));
+                col += spaces;
+            } else if rest.starts_with("//") {
+                trivia.push(Trivia::Comment(rest.to_string()));
+                break;
+            } else if rest.starts_with("/*") {
+                let start = source_offset(&segments, col, false);
+                let first = row;
+                let mut from = col + 2;
+                let end = loop {
+                    if let Some(end) = lines[row][from..].find("*/") {
+                        break from + end + 2;
+                    }
+                    row += 1;
+                    if row == lines.len() {
+                        Err(CompileError::UnterminatedComment {
+                            span: Span {
+                                file: file.clone(),
+                                start,
+                                end: start + 2,
+                            },
+                        })?;
+                    }
+                    from = 0;
+                };
+                if row != first {
+                    segments = vec![(0, offsets[row])];
+                }
+                let end_offset = source_offset(&segments, end, true);
+                trivia.push(Trivia::Comment(text[start..end_offset].to_string()));
+                col = end;
+            } else {
+                let mut lexer = Lexer::new(&file, line, row, 0).skip_to(col);
+                let mut token = lexer.token().map_err(|mut error| {
+                    if let Some(CompileError::Lex { span, .. }) = error.downcast_mut() {
+                        span.start = source_offset(&segments, span.start, false);
+                        span.end = source_offset(&segments, span.end, true);
+                    }
+                    error
+                })?;
+                col = lexer.pos;
+                token.span.start = source_offset(&segments, token.span.start, false);
+                token.span.end = source_offset(&segments, token.span.end, true);
+                if let TokenKind::Ident(word) = &token.kind {
+                    if let Some(kind) = keyword(word) {
+                        token.kind = kind;
+                    }
+                }
+                token.leading = std::mem::take(&mut trivia);
+                tokens.push(token);
+            }
+        }
+        row = next.max(row + 1);
+    }
+
+    Ok((tokens, trivia))
+}
+
+/// Where byte `col` of a line spliced together from `segments` is in the
+/// source, or with `end`, where the byte before it ends. Each segment is
+/// where a physical line starts in the spliced one and in the source.
+fn source_offset(segments: &[(usize, usize)], col: usize, end: bool) -> usize {
+    let &(from, offset) = segments
+        .iter()
+        .rev()
+        .find(|(from, _)| *from < col || !end && *from == col)
+        .unwrap_or(&segments[0]);
+    offset + col - from
+}
+
+#[test]
+fn test_lex_with_trivia() {
+    let source = concat!(
+        "#define ADD(a, b) \\\n    ((a) + (b))\n",
+        "int a; // one\n",
+        "\n",
+        "/* two\n",
+        "   three */ a\t= ADD(a, 1) /**/;\n",
+    );
+    let (tokens, trailing) = lex_with_trivia("", source).unwrap();
+    assert_eq!(
+        tokens.iter().map(Token::kind).collect::<Vec<_>>()[..4],
+        [
+            TokenKind::Int,
+            TokenKind::Ident("a".to_string()),
+            TokenKind::Semicolon,
+            TokenKind::Ident("a".to_string()),
+        ]
+    );
+    assert_eq!(
+        tokens[0].leading(),
+        [
+            Trivia::Directive("#define ADD(a, b) \\\n    ((a) + (b))".to_string()),
+            Trivia::Newline,
+        ]
+    );
+    assert_eq!(
+        tokens[3].leading(),
+        [
+            Trivia::Space(" ".to_string()),
+            Trivia::Comment("// one".to_string()),
+            Trivia::Newline,
+            Trivia::Newline,
+            Trivia::Comment("/* two\n   three */".to_string()),
+            Trivia::Space(" ".to_string()),
+        ]
+    );
+    assert_eq!(trailing, [Trivia::Newline]);
+
+    // Nothing is lost.
+    let mut text = String::new();
+    let mut add = |trivia: &[Trivia]| {
+        for trivia in trivia {
+            match trivia {
+                Trivia::Newline => text.push('\n'),
+                Trivia::Space(s) | Trivia::Comment(s) | Trivia::Directive(s) => text.push_str(s),
             }
         }
+    };
+    for token in &tokens {
+        add(token.leading());
+        add(&[Trivia::Space(
+            source[token.span().start..token.span().end].to_string(),
+        )]);
     }
+    add(&trailing);
+    assert_eq!(text, source);
 
-    Ok((
-        TokenStream {
-            token: tokens[0].clone(),
-            stream: tokens.into_iter().peekable(),
-        },
-        variables.len() as u32,
-    ))
+    assert!(lex_with_trivia("", "a; /* b").is_err());
 }
 
 #[test]
 fn testrunner_tokenize() {
     let test_tokenize = |source: &str, expect: Vec<Token>| {
-        let (tokenstream, _) = tokenize(vec![source.to_string()]).unwrap();
+        let tokenstream = tokenize(vec![source.to_string()]).unwrap();
 
         for (expect, result) in expect.into_iter().zip(tokenstream.stream) {
-            assert_eq!(result, expect, "{}", source);
+            assert_eq!(
+                (result.col(), result.row(), result.kind()),
+                (expect.col(), expect.row(), expect.kind()),
+                "{}",
+                source
+            );
         }
     };
     let expect = vec![
@@ -412,28 +959,14 @@ fn testrunner_tokenize() {
     test_tokenize("7+3-4", expect);
 
     let expect = vec![
-        Token::new(
-            0,
-            0,
-            TokenKind::LocalVar {
-                symbol: String::from("a"),
-                offset: 8,
-            },
-        ),
+        Token::new(0, 0, TokenKind::Ident(String::from("a"))),
         Token::new(2, 0, TokenKind::Assign),
         Token::new(4, 0, TokenKind::Num(3)),
     ];
     test_tokenize("a = 3", expect);
 
     let expect = vec![
-        Token::new(
-            0,
-            0,
-            TokenKind::LocalVar {
-                symbol: "hello1".into(),
-                offset: 8,
-            },
-        ),
+        Token::new(0, 0, TokenKind::Ident("hello1".into())),
         Token::new(7, 0, TokenKind::Assign),
         Token::new(9, 0, TokenKind::Num(3)),
         Token::new(10, 0, TokenKind::Semicolon),
@@ -447,14 +980,7 @@ fn testrunner_tokenize() {
     ];
     test_tokenize("return 8;", expect);
 
-    let expect = vec![Token::new(
-        0,
-        0,
-        TokenKind::LocalVar {
-            symbol: "returned".into(),
-            offset: 8,
-        },
-    )];
+    let expect = vec![Token::new(0, 0, TokenKind::Ident("returned".into()))];
     test_tokenize("returned;", expect);
 
     let expect = vec![
@@ -462,14 +988,7 @@ fn testrunner_tokenize() {
         Token::new(3, 0, TokenKind::LRoundBracket),
         Token::new(4, 0, TokenKind::Num(1)),
         Token::new(5, 0, TokenKind::RRoundBracket),
-        Token::new(
-            7,
-            0,
-            TokenKind::LocalVar {
-                symbol: "b".into(),
-                offset: 8,
-            },
-        ),
+        Token::new(7, 0, TokenKind::Ident("b".into())),
         Token::new(9, 0, TokenKind::Assign),
         Token::new(11, 0, TokenKind::Num(20)),
         Token::new(13, 0, TokenKind::Semicolon),
@@ -488,21 +1007,218 @@ fn testrunner_tokenize() {
         Token::new(16, 0, TokenKind::Semicolon),
     ];
     test_tokenize("if (1) 4; else 5;", expect);
+
+    let expect = vec![
+        Token::new(0, 0, TokenKind::Asm),
+        Token::new(3, 0, TokenKind::LRoundBracket),
+        Token::new(4, 0, TokenKind::Str("nop\n\t\"".into())),
+        Token::new(15, 0, TokenKind::Colon),
+        Token::new(16, 0, TokenKind::RRoundBracket),
+    ];
+    test_tokenize(r#"asm("nop\n\t\"":)"#, expect);
+}
+
+#[test]
+fn test_lexer() {
+    let lex = |source: &str| {
+        let mut tokens = Vec::new();
+        Lexer::new(&Arc::from("a.c"), source, 0, 10)
+            .lex(&mut tokens)
+            .map(|_| tokens)
+    };
+
+    let tokens = lex("iffy<=b").unwrap();
+    let spans: Vec<_> = tokens
+        .iter()
+        .map(|t| (t.span().start, t.span().end))
+        .collect();
+    assert_eq!(spans, vec![(10, 14), (14, 16), (16, 17)]);
+    assert_eq!(tokens[0].kind(), TokenKind::Ident("iffy".to_string()));
+    assert_eq!(tokens[1].kind(), TokenKind::LessOrEqual);
+    assert_eq!(tokens[0].file(), "a.c");
+
+    let tokens = lex("a===b").unwrap();
+    let kinds: Vec<_> = tokens.iter().map(Token::kind).collect();
+    assert_eq!(kinds[1..3], [TokenKind::Equal, TokenKind::Assign]);
+
+    let tokens = lex("\"é\" x").unwrap();
+    assert_eq!(tokens[0].kind(), TokenKind::Str("é".to_string()));
+    assert_eq!((tokens[1].span().start, tokens[1].col()), (15, 5));
+
+    assert!(lex("é").is_err());
+    assert!(lex("a @ b").is_err());
+    assert!(lex("\"abc").is_err());
+    assert!(lex("12abc").is_err());
+}
+
+#[test]
+fn test_remove_comments() {
+    let source = [
+        "a = 1; // one",
+        "b /* two */ = \"/* not a comment */\";",
+        "c = /* three",
+        "   still three */ 4; /**/",
+        "#define LONG \\",
+        "  1 + \\",
+        "  2",
+        "\"//\" '\\'' // x",
+    ];
+    let source: Vec<String> = source.iter().map(|s| s.to_string()).collect();
+    let lines = remove_comments("", &source).unwrap();
+    assert_eq!(
+        lines,
+        vec![
+            "a = 1;       ",
+            "b           = \"/* not a comment */\";",
+            "c =         ",
+            "                  4;     ",
+            "#define LONG   1 +   2",
+            "",
+            "",
+            "\"//\" '\\''     ",
+        ]
+    );
+
+    let source = vec!["1;".to_string(), "2; /* open".to_string(), "3;".to_string()];
+    let error = remove_comments("main.c", &source).unwrap_err();
+    let error = error.downcast_ref::<CompileError>().unwrap();
+    assert_eq!(error.to_string(), "unterminated comment");
+    let span = error.span().unwrap();
+    assert_eq!((&*span.file, span.start, span.end), ("main.c", 6, 8));
+
+    // Tabs and carriage returns are whitespace.
+    let tokenstream = tokenize(vec!["\tx =\t1; /* x */\r".to_string()]).unwrap();
+    assert_eq!(tokenstream.stream.len(), 4);
+}
+
+/// One line for each token, with its row and column counted from 1, as
+/// shown by `--emit=tokens`.
+pub fn dump_tokens(tokens: &[Token]) -> String {
+    tokens
+        .iter()
+        .map(|token| format!("{}:{}\t{:?}\n", token.row + 1, token.col + 1, token.kind))
+        .collect()
+}
+
+#[test]
+fn test_dump_tokens() {
+    let tokenstream = tokenize(vec!["int a;".to_string(), "a;".to_string()]).unwrap();
+    assert_eq!(
+        dump_tokens(tokenstream.tokens()),
+        "1:1\tInt\n\
+         1:5\tIdent(\"a\")\n\
+         1:6\tSemicolon\n\
+         2:1\tIdent(\"a\")\n\
+         2:2\tSemicolon\n"
+    );
 }
 
 impl TokenStream {
+    /// Every token of the stream, consumed or not.
+    pub fn tokens(&self) -> &[Token] {
+        &self.stream
+    }
+
+    /// The most recently consumed token.
+    pub fn token(&self) -> Token {
+        self.token.clone()
+    }
+
     pub fn is_empty(&mut self) -> bool {
-        self.stream.peek().is_none()
+        self.pos >= self.stream.len()
+    }
+
+    /// Returns the kind of the `n`-th token ahead without consuming anything.
+    pub fn peek_kind(&self, n: usize) -> Option<TokenKind> {
+        self.stream.get(self.pos + n).map(Token::kind)
+    }
+
+    /// Where the next token is, or the most recently consumed one at the end
+    /// of the stream.
+    pub fn peek_span(&self) -> Span {
+        self.stream
+            .get(self.pos)
+            .map_or_else(|| self.token.span().clone(), |token| token.span().clone())
+    }
+
+    /// From `start` to the end of the most recently consumed token.
+    pub fn span_from(&self, start: &Span) -> Span {
+        start.to(self.token.span())
+    }
+
+    /// Position in the stream, to be passed back to `rewind`.
+    pub fn checkpoint(&self) -> usize {
+        self.pos
+    }
+
+    pub fn rewind(&mut self, pos: usize) {
+        self.pos = pos;
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.stream.get(self.pos).cloned();
+        if token.is_some() {
+            self.pos += 1;
+        }
+        token
+    }
+
+    /// Records a syntax error and skips to where parsing can resume: past
+    /// the next `;`, or up to the `}` closing the current block. Tokens in
+    /// nested blocks are skipped along with the brackets. Fails once
+    /// `max_errors` errors have been recorded.
+    pub fn recover(&mut self, error: anyhow::Error) -> Result<()> {
+        self.errors.push(error);
+        if self.errors.len() >= self.max_errors {
+            Err(CompileError::TooManyErrors(self.max_errors))?;
+        }
+
+        let mut depth = 0;
+        while let Some(kind) = self.peek_kind(0) {
+            match kind {
+                TokenKind::RCurlyBracket if depth == 0 => break,
+                TokenKind::Semicolon if depth == 0 => {
+                    self.next();
+                    break;
+                }
+                TokenKind::LCurlyBracket => depth += 1,
+                TokenKind::RCurlyBracket => {
+                    depth -= 1;
+                    if depth == 0 {
+                        self.next();
+                        break;
+                    }
+                }
+                _ => {}
+            }
+            self.next();
+        }
+        Ok(())
+    }
+
+    /// How many syntax errors to report before giving up. 0 means there is
+    /// no limit, as with GCC's `-fmax-errors=0`.
+    pub fn set_max_errors(&mut self, max_errors: usize) {
+        self.max_errors = match max_errors {
+            0 => usize::MAX,
+            max_errors => max_errors,
+        };
+    }
+
+    /// The errors passed to `recover`, in the order they were found.
+    pub fn take_errors(&mut self) -> Vec<anyhow::Error> {
+        std::mem::take(&mut self.errors)
     }
 
     pub fn expect(&mut self, expect: TokenKind) -> Result<TokenKind> {
-        if let Some(token) = self.stream.next() {
+        if let Some(token) = self.stream.get(self.pos).cloned() {
             if token.kind() == expect {
+                self.pos += 1;
                 self.token = token.clone();
                 Ok(token.kind())
             } else {
                 Err(CompileError::Unexpected {
-                    stop: self.token.clone(),
+                    stop: token.clone(),
                     expect,
                     result: token.kind(),
                 })?
@@ -516,13 +1232,14 @@ impl TokenStream {
     }
 
     pub fn expect_number(&mut self) -> Result<u32> {
-        if let Some(token) = self.stream.next() {
+        if let Some(token) = self.stream.get(self.pos).cloned() {
             if let TokenKind::Num(num) = token.kind() {
+                self.pos += 1;
                 self.token = token.clone();
                 Ok(num)
             } else {
                 Err(CompileError::Unexpected {
-                    stop: self.token.clone(),
+                    stop: token.clone(),
                     expect: TokenKind::Num(0),
                     result: token.kind(),
                 })?
@@ -535,42 +1252,66 @@ impl TokenStream {
         }
     }
 
-    pub fn expect_local_variable(&mut self) -> Result<TokenKind> {
-        if let Some(token) = self.stream.peek() {
-            if let TokenKind::LocalVar { .. } = token.kind() {
-                self.token = token.clone();
-                let kind = token.kind();
-                self.stream.next();
-                Ok(kind)
+    pub fn expect_identifier(&mut self) -> Result<String> {
+        if let Some(token) = self.stream.get(self.pos).cloned() {
+            if let TokenKind::Ident(name) = token.kind() {
+                self.token = token;
+                self.pos += 1;
+                Ok(name)
             } else {
                 Err(CompileError::Unexpected {
-                    stop: self.token.clone(),
-                    expect: TokenKind::LocalVar {
-                        symbol: String::new(),
-                        offset: 0,
-                    },
+                    stop: token.clone(),
+                    expect: TokenKind::Ident(String::new()),
                     result: token.kind(),
                 })?
             }
         } else {
             Err(CompileError::Expected {
                 stop: self.token.clone(),
-                expect: TokenKind::LocalVar {
-                    symbol: String::new(),
-                    offset: 0,
-                },
+                expect: TokenKind::Ident(String::new()),
             })?
         }
     }
 
-    pub fn consume(&mut self, expect: TokenKind) -> bool {
-        if let Some(token) = self.stream.peek() {
-            if expect == token.kind() {
-                self.token = self.stream.next().unwrap();
-                true
+    pub fn expect_string(&mut self) -> Result<String> {
+        if let Some(token) = self.stream.get(self.pos).cloned() {
+            if let TokenKind::Str(string) = token.kind() {
+                self.pos += 1;
+                self.token = token;
+                Ok(string)
             } else {
-                false
+                Err(CompileError::Unexpected {
+                    stop: token.clone(),
+                    expect: TokenKind::Str(String::new()),
+                    result: token.kind(),
+                })?
             }
+        } else {
+            Err(CompileError::Expected {
+                stop: self.token.clone(),
+                expect: TokenKind::Str(String::new()),
+            })?
+        }
+    }
+
+    /// Expects the bracket closing `open`, noting where `open` is if it is
+    /// missing.
+    pub fn expect_closing(&mut self, expect: TokenKind, open: &Token) -> Result<TokenKind> {
+        self.expect(expect)
+            .map_err(|error| match error.downcast::<CompileError>() {
+                Ok(error) => CompileError::Unclosed {
+                    error: Box::new(error),
+                    open: open.clone(),
+                }
+                .into(),
+                Err(error) => error,
+            })
+    }
+
+    pub fn consume(&mut self, expect: TokenKind) -> bool {
+        if self.peek_kind(0) == Some(expect) {
+            self.token = self.next().unwrap();
+            true
         } else {
             false
         }
@@ -0,0 +1,866 @@
+use crate::ast::{BinaryOp, Decl, Expr, ExprKind, Stmt, StmtKind};
+use crate::result::{CompileError, CompileWarning};
+use crate::token::Span;
+use anyhow::Result;
+use std::fmt::Display;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypeKind {
+    /// Integer | char, short, int, long (optionally unsigned)
+    Integer { size: u32, signed: bool },
+    /// Boolean | _Bool, only ever holds 0 or 1
+    Bool,
+    /// Pointer | type *
+    Ptr(Box<Type>),
+    /// Void | void
+    Void,
+    /// Function | type (params), where `()` leaves the parameters unspecified
+    Func {
+        ret: Box<Type>,
+        params: Option<Vec<Type>>,
+    },
+}
+
+/// A type together with its `const` and `volatile` qualifiers.
+///
+/// Volatile objects need no special handling in `gen`: every read and write
+/// in the source is emitted as exactly one load or store, and there is no
+/// pass that removes or merges memory accesses.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Type {
+    pub kind: TypeKind,
+    pub is_const: bool,
+    pub is_volatile: bool,
+}
+
+impl Type {
+    pub const CHAR: Type = Type::integer(1, true);
+    pub const INT: Type = Type::integer(4, true);
+    pub const LONG: Type = Type::integer(8, true);
+    pub const BOOL: Type = Type {
+        kind: TypeKind::Bool,
+        is_const: false,
+        is_volatile: false,
+    };
+
+    pub const fn integer(size: u32, signed: bool) -> Type {
+        Type {
+            kind: TypeKind::Integer { size, signed },
+            is_const: false,
+            is_volatile: false,
+        }
+    }
+
+    pub const VOID: Type = Type {
+        kind: TypeKind::Void,
+        is_const: false,
+        is_volatile: false,
+    };
+
+    pub fn func(ret: Type, params: Option<Vec<Type>>) -> Type {
+        Type {
+            kind: TypeKind::Func {
+                ret: Box::new(ret),
+                params,
+            },
+            is_const: false,
+            is_volatile: false,
+        }
+    }
+
+    pub fn pointer_to(ty: Type) -> Type {
+        Type {
+            kind: TypeKind::Ptr(Box::new(ty)),
+            is_const: false,
+            is_volatile: false,
+        }
+    }
+
+    /// The same type without its top-level qualifiers.
+    pub fn unqualified(&self) -> Type {
+        Type {
+            kind: self.kind.clone(),
+            is_const: false,
+            is_volatile: false,
+        }
+    }
+
+    pub fn size(&self) -> u32 {
+        match self.kind {
+            TypeKind::Integer { size, .. } => size,
+            TypeKind::Bool | TypeKind::Void | TypeKind::Func { .. } => 1,
+            TypeKind::Ptr(_) => 8,
+        }
+    }
+
+    pub fn is_signed(&self) -> bool {
+        matches!(self.kind, TypeKind::Integer { signed: true, .. })
+    }
+
+    pub fn is_integer(&self) -> bool {
+        matches!(self.kind, TypeKind::Integer { .. } | TypeKind::Bool)
+    }
+
+    pub fn is_bool(&self) -> bool {
+        matches!(self.kind, TypeKind::Bool)
+    }
+
+    pub fn is_void(&self) -> bool {
+        matches!(self.kind, TypeKind::Void)
+    }
+
+    pub fn is_func(&self) -> bool {
+        matches!(self.kind, TypeKind::Func { .. })
+    }
+
+    pub fn is_pointer(&self) -> bool {
+        matches!(self.kind, TypeKind::Ptr(_))
+    }
+
+    pub fn pointee(&self) -> Option<&Type> {
+        match &self.kind {
+            TypeKind::Ptr(ty) => Some(ty),
+            _ => None,
+        }
+    }
+
+    /// Truncates `value` to this type and extends it back to 64 bits,
+    /// the same way the generated code does.
+    pub fn convert(&self, value: i64) -> i64 {
+        match self.kind {
+            TypeKind::Bool => (value != 0) as i64,
+            TypeKind::Integer { size: 1, signed } if signed => value as i8 as i64,
+            TypeKind::Integer { size: 1, .. } => value as u8 as i64,
+            TypeKind::Integer { size: 2, signed } if signed => value as i16 as i64,
+            TypeKind::Integer { size: 2, .. } => value as u16 as i64,
+            TypeKind::Integer { size: 4, signed } if signed => value as i32 as i64,
+            TypeKind::Integer { size: 4, .. } => value as u32 as i64,
+            _ => value,
+        }
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let qualifiers = match (self.is_const, self.is_volatile) {
+            (true, true) => "const volatile",
+            (true, false) => "const",
+            (false, true) => "volatile",
+            (false, false) => "",
+        };
+
+        match &self.kind {
+            TypeKind::Integer { size, signed } => {
+                if !qualifiers.is_empty() {
+                    write!(f, "{} ", qualifiers)?;
+                }
+                if !signed {
+                    write!(f, "unsigned ")?;
+                }
+                match size {
+                    1 => write!(f, "char"),
+                    2 => write!(f, "short"),
+                    4 => write!(f, "int"),
+                    _ => write!(f, "long"),
+                }
+            }
+            TypeKind::Bool if qualifiers.is_empty() => write!(f, "_Bool"),
+            TypeKind::Bool => write!(f, "{} _Bool", qualifiers),
+            TypeKind::Void if qualifiers.is_empty() => write!(f, "void"),
+            TypeKind::Void => write!(f, "{} void", qualifiers),
+            TypeKind::Ptr(ty) => match &ty.kind {
+                TypeKind::Func { ret, params } => {
+                    write!(f, "{} (*{})", ret, qualifiers)?;
+                    write_params(f, params)
+                }
+                _ => write!(f, "{} *{}", ty, qualifiers),
+            },
+            TypeKind::Func { ret, params } => {
+                write!(f, "{} ", ret)?;
+                write_params(f, params)
+            }
+        }
+    }
+}
+
+fn write_params(f: &mut std::fmt::Formatter<'_>, params: &Option<Vec<Type>>) -> std::fmt::Result {
+    match params {
+        None => write!(f, "()"),
+        Some(params) if params.is_empty() => write!(f, "(void)"),
+        Some(params) => {
+            write!(f, "(")?;
+            for (i, param) in params.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}", param)?;
+            }
+            write!(f, ")")
+        }
+    }
+}
+
+/// Integer promotion followed by the usual arithmetic conversions.
+pub fn common_type(lhs: &Type, rhs: &Type) -> Type {
+    let promote = |ty: &Type| {
+        if ty.is_integer() && ty.size() < 4 {
+            Type::INT
+        } else {
+            ty.unqualified()
+        }
+    };
+    let (lhs, rhs) = (promote(lhs), promote(rhs));
+
+    if lhs.is_pointer() {
+        return lhs;
+    }
+    if rhs.is_pointer() {
+        return rhs;
+    }
+    if lhs.is_signed() == rhs.is_signed() {
+        return if lhs.size() >= rhs.size() { lhs } else { rhs };
+    }
+
+    let (signed, unsigned) = if lhs.is_signed() {
+        (lhs, rhs)
+    } else {
+        (rhs, lhs)
+    };
+    if unsigned.size() >= signed.size() {
+        unsigned
+    } else {
+        signed
+    }
+}
+
+/// Evaluates `expr` if it is an integer constant expression. Every value is
+/// reduced to the type of its expression and computed as the generated code
+/// would, so folding never changes what a program does.
+pub fn const_value(expr: &Expr) -> Option<i64> {
+    let value = match &expr.kind {
+        ExprKind::Num(num) => *num as i64,
+        ExprKind::Binary { op, lhs, rhs } => {
+            let operand = lhs.ty.as_ref()?;
+            // Pointer arithmetic is scaled, and only done at run time.
+            if !operand.is_integer() || !rhs.ty.as_ref()?.is_integer() {
+                return None;
+            }
+            let signed = operand.is_signed();
+            let (lhs, rhs) = (const_value(lhs)?, const_value(rhs)?);
+            match op {
+                BinaryOp::Add => lhs.wrapping_add(rhs),
+                BinaryOp::Sub => lhs.wrapping_sub(rhs),
+                BinaryOp::Mul => lhs.wrapping_mul(rhs),
+                // Division by zero, and overflowing `long` division, fault
+                // when run and are left for then.
+                BinaryOp::Div if signed => lhs.checked_div(rhs)?,
+                BinaryOp::Div => (lhs as u64).checked_div(rhs as u64)? as i64,
+                BinaryOp::Equal => (lhs == rhs) as i64,
+                BinaryOp::NEqual => (lhs != rhs) as i64,
+                BinaryOp::Less if signed => (lhs < rhs) as i64,
+                BinaryOp::Less => ((lhs as u64) < rhs as u64) as i64,
+                BinaryOp::LessOrEqual if signed => (lhs <= rhs) as i64,
+                BinaryOp::LessOrEqual => (lhs as u64 <= rhs as u64) as i64,
+            }
+        }
+        ExprKind::Cast(operand) => const_value(operand)?,
+        _ => return None,
+    };
+    Some(expr.ty.as_ref()?.convert(value))
+}
+
+fn is_lvalue(expr: &Expr) -> bool {
+    matches!(expr.kind, ExprKind::Var { .. } | ExprKind::Deref(_))
+}
+
+fn cast_to(expr: &mut Expr, ty: &Type) {
+    if expr.ty.as_ref().map(Type::unqualified) != Some(ty.unqualified()) {
+        expr.wrap(ExprKind::Cast, ty.unqualified());
+    }
+}
+
+/// Checks an implicit conversion of `expr` to `to`, as done by an assignment.
+fn check_conversion(expr: &Expr, to: &Type, warnings: &mut Vec<CompileWarning>) {
+    // Any scalar converts to _Bool by comparing it against zero, which
+    // never loses information the program cares about.
+    let from = match &expr.ty {
+        Some(from) if from.kind != to.kind && !to.is_bool() => from.clone(),
+        _ => return,
+    };
+
+    if let (Some(from_pointee), Some(to_pointee)) = (from.pointee(), to.pointee()) {
+        if from_pointee.is_const && !to_pointee.is_const
+            || from_pointee.is_volatile && !to_pointee.is_volatile
+        {
+            warnings.push(CompileWarning::DiscardsQualifiers {
+                from,
+                to: to.clone(),
+                span: expr.span.clone(),
+            });
+        }
+        return;
+    }
+
+    if from.is_pointer() != to.is_pointer() {
+        if const_value(expr) != Some(0) {
+            warnings.push(CompileWarning::PointerConversion {
+                from,
+                to: to.clone(),
+                span: expr.span.clone(),
+            });
+        }
+        return;
+    }
+    if !to.is_integer() {
+        return;
+    }
+
+    if let Some(value) = const_value(expr) {
+        let result = to.convert(from.convert(value));
+        if result != value {
+            warnings.push(CompileWarning::Overflow {
+                from,
+                to: to.clone(),
+                value,
+                result,
+                span: expr.span.clone(),
+            });
+        }
+    } else if from.size() > to.size() {
+        warnings.push(CompileWarning::Conversion {
+            from,
+            to: to.clone(),
+            span: expr.span.clone(),
+        });
+    }
+}
+
+/// Rejects `expr` if it is of type `void`. Such an expression may only be
+/// an expression statement or cast to `void`, as it has no value.
+fn check_value(expr: &Expr) -> Result<()> {
+    if expr.ty.as_ref().is_some_and(Type::is_void) {
+        Err(CompileError::VoidValue {
+            span: expr.span.clone(),
+        })?;
+    }
+    Ok(())
+}
+
+fn expr_type(expr: &Expr) -> Result<Type> {
+    Ok(expr
+        .ty
+        .clone()
+        .ok_or(CompileError::Internal("operand type"))?)
+}
+
+/// Converts an initializer or the right hand side of an assignment to the
+/// type of what it is stored to.
+fn convert_for_store(value: &mut Expr, ty: &Type, warnings: &mut Vec<CompileWarning>) {
+    check_conversion(value, ty, warnings);
+    cast_to(value, ty);
+}
+
+/// Annotates the expressions of `stmt` with their types. See `add_type`.
+/// `ret` is the return type of the function the statement is in, which
+/// returned values are converted to.
+pub fn add_type_stmt(
+    stmt: &mut Stmt,
+    ret: &Type,
+    warnings: &mut Vec<CompileWarning>,
+) -> Result<()> {
+    match &mut stmt.kind {
+        StmtKind::Expr(expr) => add_type(expr, warnings)?,
+        StmtKind::Decl(decl) => add_type_decl(decl, warnings)?,
+        StmtKind::Block(stmts) => {
+            for stmt in stmts {
+                add_type_stmt(stmt, ret, warnings)?;
+            }
+        }
+        StmtKind::If {
+            cond,
+            then,
+            otherwise,
+        } => {
+            add_type(cond, warnings)?;
+            check_value(cond)?;
+            add_type_stmt(then, ret, warnings)?;
+            if let Some(otherwise) = otherwise {
+                add_type_stmt(otherwise, ret, warnings)?;
+            }
+        }
+        StmtKind::Return(value) => {
+            if let Some(value) = value {
+                if ret.is_void() {
+                    Err(CompileError::VoidReturnValue {
+                        span: stmt.span.clone(),
+                    })?;
+                }
+                add_type(value, warnings)?;
+                check_value(value)?;
+                convert_for_store(value, ret, warnings);
+            }
+        }
+        StmtKind::Asm(asm) => {
+            for operand in asm.operands.iter_mut() {
+                add_type(operand, warnings)?;
+                check_value(operand)?;
+            }
+            for output in asm.operands.iter().take(asm.outputs) {
+                if !is_lvalue(output) {
+                    Err(CompileError::NotLvalue {
+                        what: "asm output operand",
+                        span: output.span.clone(),
+                    })?;
+                }
+                if output.ty.as_ref().is_some_and(|ty| ty.is_const) {
+                    Err(CompileError::ReadOnly {
+                        what: "location in asm output".to_string(),
+                        span: output.span.clone(),
+                    })?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Converts the initializer of a variable to its type. Initializing a const
+/// object is fine, only later assignments are rejected.
+pub fn add_type_decl(decl: &mut Decl, warnings: &mut Vec<CompileWarning>) -> Result<()> {
+    if decl.ty.is_func() {
+        return Ok(());
+    }
+    if let Some(init) = decl.init.as_mut() {
+        add_type(init, warnings)?;
+        check_value(init)?;
+        convert_for_store(init, &decl.ty, warnings);
+    }
+    Ok(())
+}
+
+/// Annotates `expr` and its operands with their types, inserting casts for
+/// implicit conversions and collecting warnings about them. Names must have
+/// been resolved by `sema`, which puts the declared type on each of them.
+pub fn add_type(expr: &mut Expr, warnings: &mut Vec<CompileWarning>) -> Result<()> {
+    match &mut expr.kind {
+        // The array of characters decays to a pointer to the first one.
+        ExprKind::Str(_) => expr.ty = Some(Type::pointer_to(Type::CHAR)),
+        ExprKind::Num(num) => {
+            expr.ty = Some(if *num <= i32::MAX as u32 {
+                Type::INT
+            } else {
+                Type::LONG
+            });
+        }
+        ExprKind::Var { .. } => {
+            let ty = expr_type(expr)?;
+            // A function designator is only ever used through its address.
+            if ty.is_func() {
+                expr.wrap(ExprKind::Addr, Type::pointer_to(ty));
+            }
+        }
+        ExprKind::Binary { op, lhs, rhs } => {
+            add_type(lhs, warnings)?;
+            add_type(rhs, warnings)?;
+            check_value(lhs)?;
+            check_value(rhs)?;
+            let op = *op;
+            let (lhs_ty, rhs_ty) = (expr_type(lhs)?, expr_type(rhs)?);
+            expr.ty = Some(binary_type(
+                op, &expr.span, lhs, rhs, lhs_ty, rhs_ty, warnings,
+            )?);
+        }
+        ExprKind::Assign { lhs, rhs } => {
+            add_type(lhs, warnings)?;
+            add_type(rhs, warnings)?;
+            check_value(lhs)?;
+            check_value(rhs)?;
+            if !is_lvalue(lhs) {
+                Err(CompileError::NotLvalue {
+                    what: "left operand of assignment",
+                    span: lhs.span.clone(),
+                })?;
+            }
+            let ty = expr_type(lhs)?;
+            if ty.is_const {
+                let what = match &lhs.kind {
+                    ExprKind::Var { name, .. } => format!("variable '{}'", name),
+                    _ => "location".to_string(),
+                };
+                Err(CompileError::ReadOnly {
+                    what,
+                    span: expr.span.clone(),
+                })?;
+            }
+            convert_for_store(rhs, &ty, warnings);
+            expr.ty = Some(ty.unqualified());
+        }
+        ExprKind::Deref(operand) => {
+            add_type(operand, warnings)?;
+            match expr_type(operand)?.kind {
+                // `*op` on a function pointer is the same function again.
+                TypeKind::Ptr(pointee) if pointee.is_func() => *expr = operand.take(),
+                TypeKind::Ptr(pointee) => expr.ty = Some(*pointee),
+                _ => Err(CompileError::InvalidDeref {
+                    ty: expr_type(operand)?,
+                    span: expr.span.clone(),
+                })?,
+            }
+        }
+        ExprKind::Addr(operand) => {
+            add_type(operand, warnings)?;
+            if is_function_designator(operand) {
+                // `&f` is the same as `f`, which has already decayed.
+                *expr = operand.take();
+                return Ok(());
+            }
+            if !is_lvalue(operand) {
+                Err(CompileError::NotLvalue {
+                    what: "unary '&' operand",
+                    span: operand.span.clone(),
+                })?;
+            }
+            expr.ty = operand.ty.clone().map(Type::pointer_to);
+        }
+        ExprKind::Cast(operand) => {
+            if let Some(ty) = &expr.ty {
+                check_params(ty, &expr.span)?;
+            }
+            add_type(operand, warnings)?;
+            // Casting to `void` is how a value is thrown away.
+            if !expr.ty.as_ref().is_some_and(Type::is_void) {
+                check_value(operand)?;
+            }
+        }
+        ExprKind::Call { callee, args } => {
+            add_type(callee, warnings)?;
+            for arg in args.iter_mut() {
+                add_type(arg, warnings)?;
+                check_value(arg)?;
+            }
+            let callee = expr_type(callee)?;
+            let Some(TypeKind::Func { ret, params }) = callee.pointee().map(|ty| &ty.kind) else {
+                return Err(CompileError::NotCallable {
+                    ty: callee,
+                    span: expr.span.clone(),
+                }
+                .into());
+            };
+
+            if args.len() > ARG_REGISTERS {
+                Err(CompileError::TooManyArguments {
+                    max: ARG_REGISTERS,
+                    span: expr.span.clone(),
+                })?;
+            }
+            if let Some(params) = params {
+                if params.len() != args.len() {
+                    Err(CompileError::ArgumentCount {
+                        ty: callee.pointee().unwrap().clone(),
+                        expected: params.len(),
+                        found: args.len(),
+                        span: expr.span.clone(),
+                    })?;
+                }
+                for (arg, param) in args.iter_mut().zip(params) {
+                    convert_for_store(arg, param, warnings);
+                }
+            }
+            expr.ty = Some(ret.unqualified());
+        }
+    }
+
+    Ok(())
+}
+
+/// The type of `lhs op rhs`, converting the operands as needed.
+fn binary_type(
+    op: BinaryOp,
+    span: &Span,
+    lhs: &mut Box<Expr>,
+    rhs: &mut Box<Expr>,
+    lhs_ty: Type,
+    rhs_ty: Type,
+    warnings: &mut Vec<CompileWarning>,
+) -> Result<Type> {
+    let invalid = |lhs, rhs| {
+        CompileError::InvalidOperands {
+            op,
+            lhs,
+            rhs,
+            span: span.clone(),
+        }
+        .into()
+    };
+    match op {
+        BinaryOp::Add | BinaryOp::Sub => {
+            if lhs_ty.is_pointer() && rhs_ty.is_pointer() {
+                if op == BinaryOp::Add {
+                    return Err(invalid(lhs_ty, rhs_ty));
+                }
+                Ok(Type::LONG)
+            } else if lhs_ty.is_pointer() {
+                Ok(lhs_ty)
+            } else if rhs_ty.is_pointer() {
+                if op == BinaryOp::Sub {
+                    return Err(invalid(lhs_ty, rhs_ty));
+                }
+                // Keep the pointer on the left so that codegen only has to
+                // scale the right hand side.
+                std::mem::swap(lhs, rhs);
+                Ok(rhs_ty)
+            } else {
+                let ty = common_type(&lhs_ty, &rhs_ty);
+                cast_to(lhs, &ty);
+                cast_to(rhs, &ty);
+                Ok(ty)
+            }
+        }
+        BinaryOp::Mul | BinaryOp::Div => {
+            if lhs_ty.is_pointer() || rhs_ty.is_pointer() {
+                return Err(invalid(lhs_ty, rhs_ty));
+            }
+            let ty = common_type(&lhs_ty, &rhs_ty);
+            cast_to(lhs, &ty);
+            cast_to(rhs, &ty);
+            Ok(ty)
+        }
+        BinaryOp::Equal | BinaryOp::NEqual | BinaryOp::Less | BinaryOp::LessOrEqual => {
+            let ty = common_type(&lhs_ty, &rhs_ty);
+            if !ty.is_signed() && ty.is_integer() {
+                let signed_operand = [&**lhs, &**rhs].into_iter().any(|operand| {
+                    operand.ty.as_ref().is_some_and(Type::is_signed)
+                        && const_value(operand).is_none_or(|value| value < 0)
+                });
+                if signed_operand {
+                    warnings.push(CompileWarning::SignCompare {
+                        lhs: lhs_ty,
+                        rhs: rhs_ty,
+                        span: span.clone(),
+                    });
+                }
+            }
+            if ty.is_integer() {
+                cast_to(lhs, &ty);
+                cast_to(rhs, &ty);
+            }
+            Ok(Type::INT)
+        }
+    }
+}
+
+/// Number of arguments that can be passed in registers.
+pub const ARG_REGISTERS: usize = 6;
+
+/// Rejects function types anywhere in `ty`, such as the pointee of a
+/// function pointer or one of its parameters, with more parameters than can
+/// be passed in registers. `span` is where the type is written.
+pub fn check_params(ty: &Type, span: &Span) -> Result<()> {
+    match &ty.kind {
+        TypeKind::Ptr(pointee) => check_params(pointee, span),
+        TypeKind::Func { ret, params } => {
+            if params
+                .as_ref()
+                .is_some_and(|params| params.len() > ARG_REGISTERS)
+            {
+                Err(CompileError::TooManyArguments {
+                    max: ARG_REGISTERS,
+                    span: span.clone(),
+                })?;
+            }
+            check_params(ret, span)?;
+            params
+                .iter()
+                .flatten()
+                .try_for_each(|param| check_params(param, span))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn is_function_designator(expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::Addr(operand) => operand.ty.as_ref().is_some_and(Type::is_func),
+        _ => false,
+    }
+}
+
+#[test]
+fn test_common_type() {
+    let uint = Type::integer(4, false);
+    let ulong = Type::integer(8, false);
+    assert_eq!(common_type(&Type::CHAR, &Type::CHAR), Type::INT);
+    assert_eq!(common_type(&Type::INT, &uint), uint);
+    assert_eq!(common_type(&uint, &Type::LONG), Type::LONG);
+    assert_eq!(common_type(&Type::LONG, &ulong), ulong);
+}
+
+#[test]
+fn test_convert() {
+    let uchar = Type::integer(1, false);
+    assert_eq!(Type::CHAR.convert(300), 44);
+    assert_eq!(Type::CHAR.convert(200), -56);
+    assert_eq!(uchar.convert(-1), 255);
+    assert_eq!(Type::INT.convert(1 << 32), 0);
+    assert_eq!(Type::LONG.convert(-1), -1);
+    assert_eq!(Type::BOOL.convert(256), 1);
+    assert_eq!(Type::BOOL.convert(0), 0);
+}
+
+#[test]
+fn test_warnings() {
+    use crate::gen::compile_with_warnings;
+    let warnings = |source: &str| {
+        let (_, warnings) = compile_with_warnings(vec![source.to_string()]).unwrap();
+        warnings
+    };
+
+    assert!(warnings("char c = 1; c = -1;").is_empty());
+    assert!(matches!(
+        warnings("char c = 300; c;")[..],
+        [CompileWarning::Overflow {
+            value: 300,
+            result: 44,
+            ..
+        }]
+    ));
+    assert!(matches!(
+        warnings("long a = 1; int b = a; b;")[..],
+        [CompileWarning::Conversion { .. }]
+    ));
+    assert!(matches!(
+        warnings("int a = 1; unsigned int b = 2; a < b;")[..],
+        [CompileWarning::SignCompare { .. }]
+    ));
+    assert!(warnings("unsigned int b = 2; b < 1;").is_empty());
+    assert!(matches!(
+        warnings("int a = 1; long b = &a; b;")[..],
+        [CompileWarning::PointerConversion { .. }]
+    ));
+}
+
+#[test]
+fn test_qualifiers() {
+    use crate::gen::compile_with_warnings;
+    let compile = |source: &str| compile_with_warnings(vec![source.to_string()]);
+    let read_only = |source: &str| {
+        let err = compile(source).unwrap_err();
+        matches!(
+            err.downcast_ref::<CompileError>(),
+            Some(CompileError::ReadOnly { .. })
+        )
+    };
+
+    assert!(read_only("const int a = 1; a = 2;"));
+    assert!(read_only("int a = 1; const int *p = &a; *p = 2;"));
+    assert!(read_only("int a = 1; int *const p = &a; p = &a;"));
+    assert!(compile("int a = 1; const int *p = &a; p = &a;").is_ok());
+    assert!(compile("int a = 1; int *const p = &a; *p = 2;").is_ok());
+
+    let (_, warnings) = compile("const int a = 1; int *p = &a; p;").unwrap();
+    assert!(matches!(
+        warnings[..],
+        [CompileWarning::DiscardsQualifiers { .. }]
+    ));
+}
+
+#[test]
+fn test_calls() {
+    use crate::gen::compile_with_warnings;
+    let error = |source: &str| {
+        let err = compile_with_warnings(vec![source.to_string()]).unwrap_err();
+        err.downcast::<CompileError>().unwrap()
+    };
+
+    assert!(matches!(
+        error("int f(int a) { return a; } f(1, 2);"),
+        CompileError::ArgumentCount {
+            expected: 1,
+            found: 2,
+            ..
+        }
+    ));
+    assert!(matches!(
+        error("int a = 1; a(2);"),
+        CompileError::NotCallable { .. }
+    ));
+    assert!(matches!(
+        error("int f() { return 1; } f = 2;"),
+        CompileError::NotLvalue { .. }
+    ));
+    assert!(matches!(
+        error("int f(int a,int b,int c,int d,int e,int f2,int g){return 0;}"),
+        CompileError::TooManyArguments { max: 6, .. }
+    ));
+    assert!(matches!(
+        error("int (*p)(int, int, int, int, int, int, int);"),
+        CompileError::TooManyArguments { max: 6, .. }
+    ));
+    assert!(matches!(
+        error("int a = 1; (int (*)(int, int, int, int, int, int, int))a;"),
+        CompileError::TooManyArguments { max: 6, .. }
+    ));
+    assert!(matches!(
+        error("int main() { return 0; } 1;"),
+        CompileError::Redefinition { .. }
+    ));
+}
+
+#[test]
+fn test_return() {
+    use crate::ast::dump_ast;
+    use crate::node::program;
+    use crate::sema::analyze;
+    use crate::token::tokenize;
+    let typed = |source: &str| {
+        let mut tokenstream = tokenize(vec![source.to_string()])?;
+        let mut program = program(&mut tokenstream)?;
+        analyze(&mut program, &mut Vec::new())?;
+        Ok::<_, anyhow::Error>(dump_ast(&program))
+    };
+
+    // The value is converted to the return type, as by an assignment.
+    let dump = typed("_Bool f() { return 5; }").unwrap();
+    assert!(dump.contains("  Return\n    Cast: _Bool\n      Num 5: int\n"));
+    let dump = typed("char f(long a) { return a; }").unwrap();
+    assert!(dump.contains("  Return\n    Cast: char\n"));
+
+    assert!(matches!(
+        typed("void f() { return 1; }")
+            .unwrap_err()
+            .downcast_ref::<CompileError>(),
+        Some(CompileError::VoidReturnValue { .. })
+    ));
+    assert!(typed("void f() { return; }").is_ok());
+}
+
+#[test]
+fn test_void_value() {
+    use crate::gen::compile_with_warnings;
+    let is_void_value = |source: &str| match compile_with_warnings(vec![source.to_string()]) {
+        Err(error) => matches!(
+            error.downcast_ref::<CompileError>(),
+            Some(CompileError::VoidValue { .. })
+        ),
+        Ok(_) => false,
+    };
+
+    for used in [
+        "f() + 1;",
+        "int x = f();",
+        "int *p = f();",
+        "int g(int a); g(f());",
+        "if (f()) 1;",
+        "int x; x = f();",
+        "int h() { return f(); }",
+        "(int)f();",
+        "void *p; *p + 1;",
+    ] {
+        assert!(
+            is_void_value(&format!("void f() {{}} {}", used)),
+            "{}",
+            used
+        );
+    }
+    assert!(compile_with_warnings(vec!["void f() {} f(); (void)f(); 0;".to_string()]).is_ok());
+}
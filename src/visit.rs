@@ -0,0 +1,357 @@
+use crate::ast::{Decl, Expr, ExprKind, FunctionDef, InlineAsm, Item, Stmt, StmtKind};
+
+/// Walks the syntax tree. Each `visit_*` method walks into the node's
+/// children by default, so that a pass only overrides the nodes it is
+/// interested in and calls the matching `walk_*` function to go on deeper.
+///
+/// ```
+/// use ccompiler::ast::{Expr, ExprKind};
+/// use ccompiler::node::program;
+/// use ccompiler::token::tokenize;
+/// use ccompiler::visit::{walk_expr, Visitor};
+///
+/// /// Counts the calls in a program.
+/// struct Calls(usize);
+///
+/// impl Visitor for Calls {
+///     fn visit_expr(&mut self, expr: &Expr) {
+///         if let ExprKind::Call { .. } = expr.kind {
+///             self.0 += 1;
+///         }
+///         walk_expr(self, expr);
+///     }
+/// }
+///
+/// let mut tokens = tokenize(vec!["f(g(1), 2); h();".to_string()]).unwrap();
+/// let mut calls = Calls(0);
+/// calls.visit_program(&program(&mut tokens).unwrap());
+/// assert_eq!(calls.0, 3);
+/// ```
+pub trait Visitor {
+    fn visit_program(&mut self, program: &[Item]) {
+        for item in program {
+            self.visit_item(item);
+        }
+    }
+
+    fn visit_item(&mut self, item: &Item) {
+        walk_item(self, item);
+    }
+
+    fn visit_function(&mut self, function: &FunctionDef) {
+        walk_function(self, function);
+    }
+
+    fn visit_stmt(&mut self, stmt: &Stmt) {
+        walk_stmt(self, stmt);
+    }
+
+    fn visit_decl(&mut self, decl: &Decl) {
+        walk_decl(self, decl);
+    }
+
+    fn visit_asm(&mut self, asm: &InlineAsm) {
+        walk_asm(self, asm);
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        walk_expr(self, expr);
+    }
+}
+
+pub fn walk_item<V: Visitor + ?Sized>(visitor: &mut V, item: &Item) {
+    match item {
+        Item::Function(function) => visitor.visit_function(function),
+        Item::Stmt(stmt) => visitor.visit_stmt(stmt),
+    }
+}
+
+/// The parameters, then the statements of the body.
+pub fn walk_function<V: Visitor + ?Sized>(visitor: &mut V, function: &FunctionDef) {
+    for param in &function.params {
+        visitor.visit_decl(param);
+    }
+    for stmt in &function.body {
+        visitor.visit_stmt(stmt);
+    }
+}
+
+pub fn walk_stmt<V: Visitor + ?Sized>(visitor: &mut V, stmt: &Stmt) {
+    match &stmt.kind {
+        StmtKind::Expr(expr) => visitor.visit_expr(expr),
+        StmtKind::Decl(decl) => visitor.visit_decl(decl),
+        StmtKind::Block(stmts) => {
+            for stmt in stmts {
+                visitor.visit_stmt(stmt);
+            }
+        }
+        StmtKind::If {
+            cond,
+            then,
+            otherwise,
+        } => {
+            visitor.visit_expr(cond);
+            visitor.visit_stmt(then);
+            if let Some(otherwise) = otherwise {
+                visitor.visit_stmt(otherwise);
+            }
+        }
+        StmtKind::Return(value) => {
+            if let Some(value) = value {
+                visitor.visit_expr(value);
+            }
+        }
+        StmtKind::Asm(asm) => visitor.visit_asm(asm),
+    }
+}
+
+pub fn walk_decl<V: Visitor + ?Sized>(visitor: &mut V, decl: &Decl) {
+    if let Some(init) = &decl.init {
+        visitor.visit_expr(init);
+    }
+}
+
+pub fn walk_asm<V: Visitor + ?Sized>(visitor: &mut V, asm: &InlineAsm) {
+    for operand in &asm.operands {
+        visitor.visit_expr(operand);
+    }
+}
+
+/// The operands in the order they are written.
+pub fn walk_expr<V: Visitor + ?Sized>(visitor: &mut V, expr: &Expr) {
+    match &expr.kind {
+        ExprKind::Num(_) | ExprKind::Str(_) | ExprKind::Var { .. } => {}
+        ExprKind::Binary { lhs, rhs, .. } | ExprKind::Assign { lhs, rhs } => {
+            visitor.visit_expr(lhs);
+            visitor.visit_expr(rhs);
+        }
+        ExprKind::Deref(operand) | ExprKind::Addr(operand) | ExprKind::Cast(operand) => {
+            visitor.visit_expr(operand);
+        }
+        ExprKind::Call { callee, args } => {
+            visitor.visit_expr(callee);
+            for arg in args {
+                visitor.visit_expr(arg);
+            }
+        }
+    }
+}
+
+/// Walks the syntax tree like `Visitor`, with each node borrowed mutably so
+/// that a pass can rewrite it.
+pub trait VisitorMut {
+    fn visit_program_mut(&mut self, program: &mut [Item]) {
+        for item in program {
+            self.visit_item_mut(item);
+        }
+    }
+
+    fn visit_item_mut(&mut self, item: &mut Item) {
+        walk_item_mut(self, item);
+    }
+
+    fn visit_function_mut(&mut self, function: &mut FunctionDef) {
+        walk_function_mut(self, function);
+    }
+
+    fn visit_stmt_mut(&mut self, stmt: &mut Stmt) {
+        walk_stmt_mut(self, stmt);
+    }
+
+    fn visit_decl_mut(&mut self, decl: &mut Decl) {
+        walk_decl_mut(self, decl);
+    }
+
+    fn visit_asm_mut(&mut self, asm: &mut InlineAsm) {
+        walk_asm_mut(self, asm);
+    }
+
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        walk_expr_mut(self, expr);
+    }
+}
+
+pub fn walk_item_mut<V: VisitorMut + ?Sized>(visitor: &mut V, item: &mut Item) {
+    match item {
+        Item::Function(function) => visitor.visit_function_mut(function),
+        Item::Stmt(stmt) => visitor.visit_stmt_mut(stmt),
+    }
+}
+
+pub fn walk_function_mut<V: VisitorMut + ?Sized>(visitor: &mut V, function: &mut FunctionDef) {
+    for param in function.params.iter_mut() {
+        visitor.visit_decl_mut(param);
+    }
+    for stmt in function.body.iter_mut() {
+        visitor.visit_stmt_mut(stmt);
+    }
+}
+
+pub fn walk_stmt_mut<V: VisitorMut + ?Sized>(visitor: &mut V, stmt: &mut Stmt) {
+    match &mut stmt.kind {
+        StmtKind::Expr(expr) => visitor.visit_expr_mut(expr),
+        StmtKind::Decl(decl) => visitor.visit_decl_mut(decl),
+        StmtKind::Block(stmts) => {
+            for stmt in stmts.iter_mut() {
+                visitor.visit_stmt_mut(stmt);
+            }
+        }
+        StmtKind::If {
+            cond,
+            then,
+            otherwise,
+        } => {
+            visitor.visit_expr_mut(cond);
+            visitor.visit_stmt_mut(then);
+            if let Some(otherwise) = otherwise {
+                visitor.visit_stmt_mut(otherwise);
+            }
+        }
+        StmtKind::Return(value) => {
+            if let Some(value) = value {
+                visitor.visit_expr_mut(value);
+            }
+        }
+        StmtKind::Asm(asm) => visitor.visit_asm_mut(asm),
+    }
+}
+
+pub fn walk_decl_mut<V: VisitorMut + ?Sized>(visitor: &mut V, decl: &mut Decl) {
+    if let Some(init) = decl.init.as_mut() {
+        visitor.visit_expr_mut(init);
+    }
+}
+
+pub fn walk_asm_mut<V: VisitorMut + ?Sized>(visitor: &mut V, asm: &mut InlineAsm) {
+    for operand in asm.operands.iter_mut() {
+        visitor.visit_expr_mut(operand);
+    }
+}
+
+pub fn walk_expr_mut<V: VisitorMut + ?Sized>(visitor: &mut V, expr: &mut Expr) {
+    match &mut expr.kind {
+        ExprKind::Num(_) | ExprKind::Str(_) | ExprKind::Var { .. } => {}
+        ExprKind::Binary { lhs, rhs, .. } | ExprKind::Assign { lhs, rhs } => {
+            visitor.visit_expr_mut(lhs);
+            visitor.visit_expr_mut(rhs);
+        }
+        ExprKind::Deref(operand) | ExprKind::Addr(operand) | ExprKind::Cast(operand) => {
+            visitor.visit_expr_mut(operand);
+        }
+        ExprKind::Call { callee, args } => {
+            visitor.visit_expr_mut(callee);
+            for arg in args.iter_mut() {
+                visitor.visit_expr_mut(arg);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_visitor() {
+    use crate::node::program;
+    use crate::token::tokenize;
+    use std::collections::BTreeMap;
+
+    /// Counts functions, declarations, and statements and expressions by
+    /// kind. Expression statements and declarations are only wrappers.
+    #[derive(Default)]
+    struct Counter(BTreeMap<&'static str, usize>);
+
+    impl Visitor for Counter {
+        fn visit_function(&mut self, function: &FunctionDef) {
+            *self.0.entry("Function").or_default() += 1;
+            walk_function(self, function);
+        }
+
+        fn visit_decl(&mut self, decl: &Decl) {
+            *self.0.entry("Decl").or_default() += 1;
+            walk_decl(self, decl);
+        }
+
+        fn visit_stmt(&mut self, stmt: &Stmt) {
+            let kind = match stmt.kind {
+                StmtKind::Expr(_) | StmtKind::Decl(_) => None,
+                StmtKind::Block(_) => Some("Block"),
+                StmtKind::If { .. } => Some("If"),
+                StmtKind::Return(_) => Some("Return"),
+                StmtKind::Asm(_) => Some("Asm"),
+            };
+            if let Some(kind) = kind {
+                *self.0.entry(kind).or_default() += 1;
+            }
+            walk_stmt(self, stmt);
+        }
+
+        fn visit_expr(&mut self, expr: &Expr) {
+            let kind = match expr.kind {
+                ExprKind::Num(_) => "Num",
+                ExprKind::Str(_) => "Str",
+                ExprKind::Var { .. } => "Var",
+                ExprKind::Binary { .. } => "Binary",
+                ExprKind::Assign { .. } => "Assign",
+                ExprKind::Deref(_) => "Deref",
+                ExprKind::Addr(_) => "Addr",
+                ExprKind::Cast(_) => "Cast",
+                ExprKind::Call { .. } => "Call",
+            };
+            *self.0.entry(kind).or_default() += 1;
+            walk_expr(self, expr);
+        }
+    }
+
+    let source = concat!(
+        "int f(int a) { if (a < 2) return 1; return f(a - 1) * a; }",
+        "int x = f(3);",
+        r#"{ x = *&x + 1; asm("nop"); }"#,
+    );
+    let mut tokenstream = tokenize(vec![source.to_string()]).unwrap();
+    let mut items = program(&mut tokenstream).unwrap();
+    let mut counter = Counter::default();
+    counter.visit_program(&items);
+    let counts: Vec<_> = counter.0.into_iter().collect();
+    assert_eq!(
+        counts,
+        [
+            ("Addr", 1),
+            ("Asm", 1),
+            ("Assign", 1),
+            ("Binary", 4),
+            ("Block", 1),
+            ("Call", 2),
+            ("Decl", 2),
+            ("Deref", 1),
+            ("Function", 1),
+            ("If", 1),
+            ("Num", 5),
+            ("Return", 2),
+            ("Var", 7),
+        ]
+    );
+
+    /// Multiplies every number by ten.
+    struct Scale;
+
+    impl VisitorMut for Scale {
+        fn visit_expr_mut(&mut self, expr: &mut Expr) {
+            if let ExprKind::Num(num) = &mut expr.kind {
+                *num *= 10;
+            }
+            walk_expr_mut(self, expr);
+        }
+    }
+
+    Scale.visit_program_mut(&mut items);
+    let mut numbers = Vec::new();
+    struct Numbers<'a>(&'a mut Vec<u32>);
+    impl Visitor for Numbers<'_> {
+        fn visit_expr(&mut self, expr: &Expr) {
+            if let ExprKind::Num(num) = expr.kind {
+                self.0.push(num);
+            }
+            walk_expr(self, expr);
+        }
+    }
+    Numbers(&mut numbers).visit_program(&items);
+    assert_eq!(numbers, [20, 10, 10, 30, 10]);
+}
@@ -0,0 +1,307 @@
+use crate::ast::{Decl, Expr, ExprKind, Item, Stmt, StmtKind};
+use crate::diagnostic::{Diagnostic, Level};
+use crate::result::CompileWarning;
+use crate::token::Span;
+use crate::types::TypeKind;
+use crate::visit::{walk_decl, walk_expr, Visitor};
+use std::collections::{HashMap, HashSet};
+
+/// The name of every warning category, as used by `-Wname` and `-Wno-name`.
+pub const CATEGORIES: [&str; 10] = [
+    "conversion",
+    "overflow",
+    "int-conversion",
+    "discarded-qualifiers",
+    "sign-compare",
+    "unused-variable",
+    "unused-value",
+    "parentheses",
+    "unreachable-code",
+    "implicit-function-declaration",
+];
+
+/// Which warnings are shown, and whether they are errors. Every category is
+/// enabled unless turned off.
+#[derive(Debug, Clone, Default)]
+pub struct WarningOptions {
+    disabled: HashSet<&'static str>,
+    /// `-Werror`: a program with warnings fails to compile.
+    pub werror: bool,
+}
+
+impl WarningOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies a `-W` flag. Returns `Ok(false)` for arguments that are not
+    /// warning flags, and an error for unknown categories.
+    pub fn parse_flag(&mut self, arg: &str) -> Result<bool, String> {
+        let Some(flag) = arg.strip_prefix("-W") else {
+            return Ok(false);
+        };
+        match flag {
+            "error" => self.werror = true,
+            "no-error" => self.werror = false,
+            "all" => self.disabled.clear(),
+            _ => {
+                let (name, enable) = match flag.strip_prefix("no-") {
+                    Some(name) => (name, false),
+                    None => (flag, true),
+                };
+                let name = CATEGORIES
+                    .iter()
+                    .find(|category| **category == name)
+                    .ok_or_else(|| format!("unknown warning option '{}'", arg))?;
+                if enable {
+                    self.disabled.remove(name);
+                } else {
+                    self.disabled.insert(name);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn is_enabled(&self, category: &str) -> bool {
+        !self.disabled.contains(category)
+    }
+
+    /// The warning as it is shown, with its category as the code. With
+    /// `-Werror` it is an error.
+    pub fn diagnostic(&self, warning: &CompileWarning) -> Diagnostic {
+        let category = warning.category();
+        let span = Some(warning.span().clone());
+        if self.werror {
+            Diagnostic::new(Level::Error, warning.to_string(), span)
+                .with_code(category)
+                .with_note(
+                    format!(
+                        "-Werror makes warnings errors; -Wno-{} turns this one off",
+                        category
+                    ),
+                    None,
+                )
+        } else {
+            Diagnostic::new(Level::Warning, warning.to_string(), span).with_code(category)
+        }
+    }
+
+    /// The warnings that are enabled.
+    pub fn filter(&self, warnings: Vec<CompileWarning>) -> Vec<CompileWarning> {
+        warnings
+            .into_iter()
+            .filter(|warning| self.is_enabled(warning.category()))
+            .collect()
+    }
+}
+
+/// Looks for code that is valid but likely a mistake, once types have been
+/// added. Each function is checked on its own, as are the statements making
+/// up the implicit `main`.
+pub fn check_program(program: &[Item], warnings: &mut Vec<CompileWarning>) {
+    let mut main = Vec::new();
+    for item in program {
+        match item {
+            Item::Function(function) => {
+                let body: Vec<&Stmt> = function.body.iter().collect();
+                check_function(&body, warnings);
+            }
+            Item::Stmt(Stmt {
+                kind: StmtKind::Decl(decl),
+                ..
+            }) if decl.ty.is_func() => {}
+            Item::Stmt(stmt) => main.push(stmt),
+        }
+    }
+    check_function(&main, warnings);
+}
+
+/// The value of the last statement of a function is what it returns when it
+/// has no `return`, so that one is never unused.
+fn check_function(body: &[&Stmt], warnings: &mut Vec<CompileWarning>) {
+    check_statements(body, true, warnings);
+
+    let mut variables = Variables::default();
+    for stmt in body {
+        variables.visit_stmt(stmt);
+    }
+    for (name, offset, span) in variables.declared {
+        if !variables.used.contains_key(&offset) {
+            warnings.push(CompileWarning::UnusedVariable { name, span });
+        }
+    }
+}
+
+fn check_statements(statements: &[&Stmt], returns_last: bool, warnings: &mut Vec<CompileWarning>) {
+    for (i, stmt) in statements.iter().enumerate() {
+        if i > 0 && matches!(statements[i - 1].kind, StmtKind::Return(_)) {
+            warnings.push(CompileWarning::Unreachable {
+                span: stmt.span.clone(),
+            });
+        }
+        let last = returns_last && i + 1 == statements.len();
+        check_statement(stmt, last, warnings);
+    }
+}
+
+fn check_statement(stmt: &Stmt, last: bool, warnings: &mut Vec<CompileWarning>) {
+    match &stmt.kind {
+        StmtKind::Block(stmts) => {
+            let stmts: Vec<&Stmt> = stmts.iter().collect();
+            check_statements(&stmts, false, warnings);
+        }
+        StmtKind::If {
+            cond,
+            then,
+            otherwise,
+        } => {
+            if matches!(cond.kind, ExprKind::Assign { .. }) {
+                warnings.push(CompileWarning::AssignmentInCondition {
+                    span: cond.span.clone(),
+                });
+            }
+            check_statement(then, false, warnings);
+            if let Some(otherwise) = otherwise {
+                check_statement(otherwise, false, warnings);
+            }
+        }
+        StmtKind::Expr(expr) if !last && !has_side_effects(expr) => {
+            warnings.push(CompileWarning::UnusedValue {
+                span: expr.span.clone(),
+            })
+        }
+        StmtKind::Expr(_) | StmtKind::Return(_) | StmtKind::Decl(_) | StmtKind::Asm(_) => {}
+    }
+}
+
+/// Whether evaluating `expr` does anything besides computing a value. Casts
+/// to `void` count, as they are how a value is thrown away on purpose.
+fn has_side_effects(expr: &Expr) -> bool {
+    let mut side_effects = SideEffects(false);
+    side_effects.visit_expr(expr);
+    side_effects.0
+}
+
+struct SideEffects(bool);
+
+impl Visitor for SideEffects {
+    fn visit_expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Assign { .. } | ExprKind::Call { .. } => self.0 = true,
+            ExprKind::Cast(_) if expr.ty.as_ref().is_some_and(|ty| ty.kind == TypeKind::Void) => {
+                self.0 = true
+            }
+            _ if expr.ty.as_ref().is_some_and(|ty| ty.is_volatile) => self.0 = true,
+            _ => walk_expr(self, expr),
+        }
+    }
+}
+
+/// The variables declared in a function, in order, and the uses of every
+/// stack slot, so that shadowed variables are told apart. A declaration
+/// itself is not a use.
+#[derive(Default)]
+struct Variables {
+    declared: Vec<(String, u32, Span)>,
+    used: HashMap<u32, usize>,
+}
+
+impl Visitor for Variables {
+    fn visit_decl(&mut self, decl: &Decl) {
+        if !decl.ty.is_func() {
+            self.declared
+                .push((decl.name.clone(), decl.offset, decl.span.clone()));
+        }
+        walk_decl(self, decl);
+    }
+
+    fn visit_expr(&mut self, expr: &Expr) {
+        if let ExprKind::Var { offset, .. } = expr.kind {
+            *self.used.entry(offset).or_default() += 1;
+        }
+        walk_expr(self, expr);
+    }
+}
+
+#[test]
+fn test_check_program() {
+    use crate::gen::compile_with_warnings;
+    let warnings = |source: &str| {
+        let (_, warnings) = compile_with_warnings(vec![source.to_string()]).unwrap();
+        warnings
+    };
+
+    assert!(warnings("int a = 1; a;").is_empty());
+    assert!(matches!(
+        warnings("int a = 1; int b = 2; a;")[..],
+        [CompileWarning::UnusedVariable { ref name, .. }] if name == "b"
+    ));
+    assert!(matches!(
+        warnings("int f() { int x; return 1; } f();")[..],
+        [CompileWarning::UnusedVariable { .. }]
+    ));
+    assert!(matches!(
+        warnings("int a = 1; { int a; } a;")[..],
+        [CompileWarning::UnusedVariable { ref name, .. }] if name == "a"
+    ));
+
+    assert!(matches!(
+        warnings("int a = 1; a + 1; a;")[..],
+        [CompileWarning::UnusedValue { .. }]
+    ));
+    assert!(warnings("int a = 1; a = a + 1; (void)a; a;").is_empty());
+
+    assert!(matches!(
+        warnings("int a = 0; if (a = 1) a = 2; a;")[..],
+        [CompileWarning::AssignmentInCondition { .. }]
+    ));
+    assert!(matches!(
+        warnings("int f() { return 1; f(); }")[..],
+        [CompileWarning::Unreachable { .. }]
+    ));
+    assert!(matches!(
+        warnings("g(1);")[..],
+        [CompileWarning::ImplicitDeclaration { ref name, .. }] if name == "g"
+    ));
+    assert!(warnings("int g(int); g(1);").is_empty());
+}
+
+#[test]
+fn test_options() {
+    let mut options = WarningOptions::new();
+    assert_eq!(options.parse_flag("-Ifoo"), Ok(false));
+    assert_eq!(options.parse_flag("-Wno-unused-variable"), Ok(true));
+    assert!(!options.is_enabled("unused-variable"));
+    assert!(options.is_enabled("unused-value"));
+    assert!(options.parse_flag("-Wunused-variable").unwrap());
+    assert!(options.is_enabled("unused-variable"));
+    assert!(options.parse_flag("-Wbogus").is_err());
+
+    options.parse_flag("-Wno-parentheses").unwrap();
+    let kept = options.filter(vec![
+        CompileWarning::AssignmentInCondition {
+            span: Span::default(),
+        },
+        CompileWarning::UnusedValue {
+            span: Span::default(),
+        },
+    ]);
+    assert!(matches!(kept[..], [CompileWarning::UnusedValue { .. }]));
+
+    let warning = CompileWarning::UnusedValue {
+        span: Span::default(),
+    };
+    assert_eq!(
+        options.diagnostic(&warning).render(&Default::default()),
+        "warning[unused-value]: statement with no effect\n"
+    );
+    assert!(!options.werror);
+    options.parse_flag("-Werror").unwrap();
+    assert!(options.werror);
+    assert_eq!(
+        options.diagnostic(&warning).render(&Default::default()),
+        "error[unused-value]: statement with no effect\n\
+         note: -Werror makes warnings errors; -Wno-unused-value turns this one off\n"
+    );
+}
@@ -1,20 +1,83 @@
+use ccompiler::ast::{Decl, Expr, FunctionDef, Item, Stmt};
+use ccompiler::format::{format_source, FormatOptions};
+use ccompiler::gen::{lex, parse};
+use ccompiler::preprocess::Preprocessor;
+use ccompiler::print::{print_program, PrintOptions};
+use ccompiler::token::Span;
+use ccompiler::visit::{
+    walk_decl_mut, walk_expr_mut, walk_function_mut, walk_stmt_mut, VisitorMut,
+};
 use std::fs;
-use std::fs::File;
 use std::io::Write;
-use std::process::Command;
+use std::process::{Command, Stdio};
 
-fn assert_compiler(input: &str, expected: Option<i32>) {
-    fs::write("./input", input).expect("failed to write the file");
+/// Forgets where each node came from, since printed source lines up
+/// differently.
+struct ClearSpans;
+
+impl VisitorMut for ClearSpans {
+    fn visit_function_mut(&mut self, function: &mut FunctionDef) {
+        function.span = Span::default();
+        walk_function_mut(self, function);
+    }
 
-    let handle = Command::new("./target/debug/ccompiler").output().unwrap();
+    fn visit_stmt_mut(&mut self, stmt: &mut Stmt) {
+        stmt.span = Span::default();
+        walk_stmt_mut(self, stmt);
+    }
 
-    let mut file = File::create("./tmp.s").unwrap();
-    file.write_all(&handle.stdout).unwrap();
+    fn visit_decl_mut(&mut self, decl: &mut Decl) {
+        decl.span = Span::default();
+        walk_decl_mut(self, decl);
+    }
+
+    fn visit_expr_mut(&mut self, expr: &mut Expr) {
+        expr.span = Span::default();
+        walk_expr_mut(self, expr);
+    }
+}
+
+fn parse_source(source: &str) -> Vec<Item> {
+    let lines = source.lines().map(str::to_string).collect();
+    let mut tokens = lex(&mut Preprocessor::new(), "input", lines).unwrap();
+    let mut program = parse(&mut tokens, 1).unwrap();
+    ClearSpans.visit_program_mut(&mut program);
+    program
+}
+
+/// Printing the program and parsing it again gives the same tree.
+fn assert_round_trip(input: &str) {
+    let program = parse_source(input);
+    for parenthesize in [false, true] {
+        let printed = print_program(&program, &PrintOptions { parenthesize });
+        assert_eq!(parse_source(&printed), program, "{}\n{}", input, printed);
+    }
+}
 
-    Command::new("cc")
-        .args(["-o", "tmp", "tmp.s"])
+/// Formatting a formatted program changes nothing.
+fn assert_formatted_stays(input: &str) {
+    let options = FormatOptions::default();
+    let formatted = format_source("input", input, &options).unwrap();
+    assert_eq!(
+        format_source("input", &formatted, &options).unwrap(),
+        formatted,
+        "{}",
+        input
+    );
+}
+
+fn assert_compiler(input: &str, expected: Option<i32>) {
+    assert_round_trip(input);
+    assert_formatted_stays(input);
+    fs::write("./input", input).expect("failed to write the file");
+
+    // Don't let a stale binary from a previous case stand in for a failed build.
+    let _ = fs::remove_file("./tmp");
+    let handle = Command::new("./target/debug/ccompiler")
+        .args(["-o", "tmp", "input"])
         .output()
         .unwrap();
+    assert!(handle.status.success(), "{}", input);
 
     let output = Command::new("./tmp").output().unwrap();
     assert_eq!(output.status.code(), expected, "{}", input);
@@ -50,4 +113,346 @@ fn test_compiler() {
     assert_compiler("c = 4;return c - 2;", Some(2));
     assert_compiler("if (1+1==2) 5;", Some(5));
     assert_compiler("if (1+3==2) 5; else 11;", Some(11));
+    assert_compiler("(char)300;", Some(44));
+    assert_compiler("(short)65537;", Some(1));
+    assert_compiler("(unsigned char)-1;", Some(255));
+    assert_compiler("char c = 200; c < 0;", Some(1));
+    assert_compiler("unsigned char c = 200; c;", Some(200));
+    assert_compiler("(unsigned)-1 < 1;", Some(0));
+    assert_compiler("unsigned int u = 10; u / 3;", Some(3));
+    assert_compiler("int a = 2147483647; a + 1 < 0;", Some(1));
+    assert_compiler("int a = 3; int *p = &a; *p;", Some(3));
+    assert_compiler("int a = 7; long q = (long)&a; *(int *)q;", Some(7));
+    assert_compiler("int a = 261; *(char *)&a = 1; a - 256;", Some(1));
+    assert_compiler("int a = 1; int *p = &a; (p + 1) - p;", Some(1));
+    assert_compiler("const int a = 3; a;", Some(3));
+    assert_compiler("int a = 1; int *const p = &a; *p = 5; a;", Some(5));
+    assert_compiler("char *s = \"a\" \"bc\"; *(s + 2);", Some(99));
+    assert_compiler("char *s = \"\\n\"; *s + *(s + 1);", Some(10));
+    // `input` is the name of the file.
+    assert_compiler("char *s = __FILE__; *s;", Some(105));
+    assert_compiler("char *d = __DATE__; *(d + 3) == 32;", Some(1));
+    assert_compiler("volatile int v = 2; v + v;", Some(4));
+    assert_compiler("const int a = 1; int *p = (int *)&a; *p = 9; a;", Some(9));
+    assert_compiler("_Bool b = 5; b;", Some(1));
+    assert_compiler("#include <stdbool.h>\nbool b = 256; b;", Some(1));
+    assert_compiler("#include <stdbool.h>\nbool b = false; b;", Some(0));
+    assert_compiler("#include <stdbool.h>\ntrue + true;", Some(2));
+    assert_compiler("(_Bool)-1 == 1;", Some(1));
+    assert_compiler("int a = 0; _Bool b = &a; b;", Some(1));
+    assert_compiler("long x = 65536; x = x * 65536; (_Bool)x;", Some(1));
+    assert_compiler("unsigned char c = 2; _Bool b = c; b = b + 1; b;", Some(1));
+    // Without <stdbool.h> the names are free for the program's own use.
+    assert_compiler("int true = 3; int bool = 4; true + bool;", Some(7));
+    assert_compiler("a = 0; if (0) a = 1; if (1) a = a + 2; a;", Some(2));
+    assert_compiler("if (0) 5;", Some(0));
+    assert_compiler(
+        "int add(int a, int b) { return a + b; } int main() { return add(3, 4); }",
+        Some(7),
+    );
+    assert_compiler(
+        "int fib(int n) { if (n <= 1) return n; return fib(n - 1) + fib(n - 2); } fib(10);",
+        Some(55),
+    );
+    assert_compiler(
+        "int add(int a, int b) { return a + b; }
+         int sub(int a, int b) { return a - b; }
+         int main() {
+             int (*op)(int, int) = add;
+             int x = op(10, 3);
+             op = &sub;
+             return x + op(10, 3);
+         }",
+        Some(20),
+    );
+    assert_compiler(
+        "int twice(int (*f)(int), int x) { return f(f(x)); }
+         int inc(int x) { return x + 1; }
+         int main() { return twice(inc, 5); }",
+        Some(7),
+    );
+    assert_compiler(
+        "int inc(int x) { return x + 1; } int (*f)(int) = inc; (*f)(1) + (**f)(1);",
+        Some(4),
+    );
+    assert_compiler(
+        "int inc(int x) { return x + 1; }
+         long p = (long)inc;
+         int (*f)(int) = (int (*)(int))p;
+         f(41);",
+        Some(42),
+    );
+    assert_compiler("int abs(int); int (*f)(int) = abs; f(-5);", Some(5));
+    assert_compiler(
+        "void set(int *p) { *p = 9; } int main() { int a = 0; set(&a); return a; }",
+        Some(9),
+    );
+    assert_compiler("asm(\"nop\"); 3;", Some(3));
+    assert_compiler("int x = 20; asm(\"add %0, 1\" : \"+r\"(x)); x;", Some(21));
+    assert_compiler(
+        "int x = 5; int y = 0; asm(\"mov %0, %1\\n\\tadd %0, %0\" : \"=r\"(y) : \"r\"(x)); y;",
+        Some(10),
+    );
+    assert_compiler(
+        "int x = 3; int y; asm(\"imul %0, %0\" : \"=r\"(y) : \"0\"(x)); y;",
+        Some(9),
+    );
+    assert_compiler(
+        "long x = 1; asm(\"shl %q0, %1\" : \"+r\"(x) : \"i\"(3) : \"cc\"); x;",
+        Some(8),
+    );
+    assert_compiler(
+        "unsigned int lo; unsigned int hi;
+         asm volatile(\"rdtsc\" : \"=a\"(lo), \"=d\"(hi));
+         long t = hi; t = t * 65536 * 65536 + lo; t != 0;",
+        Some(1),
+    );
+    assert_compiler(
+        "unsigned int a; unsigned int b; unsigned int c; unsigned int d;
+         asm(\"cpuid\" : \"=a\"(a), \"=b\"(b), \"=c\"(c), \"=d\"(d) : \"a\"(0));
+         (a > 0) + (b != 0);",
+        Some(2),
+    );
+    assert_compiler(
+        "int x = 7; asm(\"mov r12, 1\" : : : \"r12\", \"memory\"); x;",
+        Some(7),
+    );
+    assert_compiler(
+        "#define N 3\n#define TYPE long\nTYPE x = N;\n#undef N\nint N = 4; x + N;",
+        Some(7),
+    );
+    assert_compiler(
+        "#define SQUARE(x) ((x) * (x))\n#define ID(...) __VA_ARGS__\nSQUARE(ID(3)) + __LINE__;",
+        Some(12),
+    );
+    assert_compiler(
+        "#define DEBUG 1
+         #if DEBUG && !defined(RELEASE)
+         int x = 5;
+         #else
+         int x = 6;
+         #endif
+         x;",
+        Some(5),
+    );
+    assert_compiler(
+        "// Comments are whitespace.\n\tint a = 3; /* a block\n spanning lines */ int b = 4;\r\n#define SUM \\\n a + b\nSUM; // 7",
+        Some(7),
+    );
+}
+
+/// Runs `input` built at `-O<opt_level>`, returning its exit code.
+fn run_optimized(input: &str, opt_level: u8) -> Option<i32> {
+    let (source, binary) = ("./tmp_fold.c", format!("./tmp_fold{}", opt_level));
+    fs::write(source, input).unwrap();
+    let _ = fs::remove_file(&binary);
+    let handle = Command::new("./target/debug/ccompiler")
+        .args([&format!("-O{}", opt_level), "-o", &binary, source])
+        .output()
+        .unwrap();
+    assert!(handle.status.success(), "{}", input);
+    let code = Command::new(&binary).output().unwrap().status.code();
+    fs::remove_file(&binary).unwrap();
+    code
+}
+
+#[test]
+fn test_constant_folding() {
+    // Folded constants have the value the unoptimized code computes, with
+    // every intermediate result as wide and as signed as its type.
+    let cases = [
+        ("(int)(((unsigned)0 - 1) / 2) == 2147483647;", 1),
+        ("(unsigned)0 - 1 < 1;", 0),
+        ("(unsigned)0 - 1 <= (unsigned)0 - 2;", 0),
+        ("((unsigned)0 - 1) / 2 / 16777216;", 127),
+        ("((unsigned long)0 - 1) / 2 > 0;", 1),
+        ("(0 - 7) / 2 == 0 - 3;", 1),
+        ("2147483647 + 1 < 0;", 1),
+        ("(char)(100 + 100) < 0;", 1),
+        ("(unsigned char)(200 + 100);", 44),
+        ("(unsigned short)(0 - 1) == 65535;", 1),
+        ("(_Bool)256 + (_Bool)0;", 1),
+    ];
+    for (input, expected) in cases {
+        assert_eq!(run_optimized(input, 0), Some(expected), "{}", input);
+        assert_eq!(run_optimized(input, 1), Some(expected), "{}", input);
+    }
+    fs::remove_file("./tmp_fold.c").unwrap();
+}
+
+#[test]
+fn test_driver() {
+    let ccompiler = |args: &[&str], stdin: &str| {
+        let mut child = Command::new("./target/debug/ccompiler")
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        // A compiler that fails before reading stdin, as on '--emit=ir',
+        // closes the pipe early.
+        let _ = child.stdin.take().unwrap().write_all(stdin.as_bytes());
+        child.wait_with_output().unwrap()
+    };
+
+    // Assembly from stdin goes to stdout.
+    let output = ccompiler(&["-S", "-"], "42;");
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("main:"));
+
+    // Compilation can stop after any phase.
+    let output = ccompiler(&["--emit=tokens", "-"], "1+2;");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "1:1\tNum(1)\n1:2\tAdd\n1:3\tNum(2)\n1:4\tSemicolon\n"
+    );
+    let output = ccompiler(&["--emit=ast", "-"], "1+2;");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "Binary +: int\n  Num 1: int\n  Num 2: int\n"
+    );
+    // The tree is the one the assembly is generated from.
+    let output = ccompiler(&["--emit=ast", "-O1", "-"], "1+2;");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Num 3: int\n");
+    let output = ccompiler(&["--emit=ir", "-"], "1+2;");
+    assert_eq!(output.status.code(), Some(1));
+
+    // Errors go to stderr, with a failing exit code.
+    let output = ccompiler(&["-"], "1 +;");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("<stdin>:1:"));
+    // Every error is reported when there is no limit.
+    let output = ccompiler(&["-fmax-errors=0", "-"], "1 +; 2 +; 3 +;");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stderr.matches("error[").count(), 3, "{}", stderr);
+    assert!(!stderr.contains("too many errors"));
+
+    let output = ccompiler(&["missing.c"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("cannot read 'missing.c'"));
+
+    let output = ccompiler(
+        &["-c", "-o", "tmp_driver.o", "-"],
+        "int main() { return 3; }",
+    );
+    assert!(output.status.success());
+    assert!(fs::metadata("tmp_driver.o").is_ok());
+    fs::remove_file("tmp_driver.o").unwrap();
+
+    // Each file is compiled on its own, at the same time as the others, and
+    // all are linked together with libraries. Both C files have variables
+    // and labels of their own.
+    fs::create_dir_all("tmp_driver").unwrap();
+    fs::write(
+        "tmp_driver/add.c",
+        "int add(int a, int b) { int x = a; if (b) x = x + b; return x; }",
+    )
+    .unwrap();
+    fs::write(
+        "tmp_driver/twice.s",
+        ".intel_syntax noprefix\n.globl twice\ntwice:\n  lea eax, [rdi + rdi]\n  ret\n\
+         .section .note.GNU-stack,\"\",@progbits\n",
+    )
+    .unwrap();
+    fs::write(
+        "tmp_driver/main.c",
+        "int add(int a, int b); int twice(int x); int abs(int x);
+         int main() { int x = 0; if (1) x = add(twice(3), abs(-4)); return x; }",
+    )
+    .unwrap();
+    let output = ccompiler(&["-c", "-o", "tmp_driver/add.o", "tmp_driver/add.c"], "");
+    assert!(output.status.success());
+    for args in [["tmp_driver/add.o", "-j1"], ["tmp_driver/add.c", "-j2"]] {
+        let output = ccompiler(
+            &[
+                "-o",
+                "tmp_driver/main",
+                "tmp_driver/main.c",
+                args[0],
+                "tmp_driver/twice.s",
+                args[1],
+                "-Ltmp_driver",
+                "-lc",
+            ],
+            "",
+        );
+        assert!(output.status.success(), "{:?}", output);
+        // Not even the linker has anything to say.
+        assert!(output.stderr.is_empty(), "{:?}", output);
+        let output = Command::new("./tmp_driver/main").output().unwrap();
+        assert_eq!(output.status.code(), Some(10));
+        fs::remove_file("tmp_driver/main").unwrap();
+    }
+
+    // Diagnostics come in the order of the files.
+    fs::write("tmp_driver/bad1.c", "1 +;").unwrap();
+    fs::write("tmp_driver/bad2.c", "2 +;").unwrap();
+    let output = ccompiler(&["-S", "tmp_driver/bad1.c", "tmp_driver/bad2.c"], "");
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let first = stderr.find("bad1.c").unwrap();
+    assert!(stderr.find("bad2.c").is_some_and(|second| second > first));
+
+    // Intermediate files are only kept when asked for.
+    let output = ccompiler(&["-o", "tmp_driver/main", "tmp_driver/add.c"], "");
+    assert!(!output.status.success(), "there is no main to link");
+    assert!(fs::metadata("add.s").is_err());
+    let output = ccompiler(
+        &[
+            "-save-temps",
+            "-c",
+            "-o",
+            "tmp_driver/add.o",
+            "tmp_driver/add.c",
+        ],
+        "",
+    );
+    assert!(output.status.success());
+    assert!(fs::read_to_string("add.s").unwrap().contains("add:"));
+    fs::remove_file("add.s").unwrap();
+    // Kept files are named after their input, so inputs named alike clash.
+    fs::create_dir_all("tmp_driver/other").unwrap();
+    fs::copy("tmp_driver/add.c", "tmp_driver/other/add.c").unwrap();
+    let output = ccompiler(
+        &[
+            "-save-temps",
+            "-o",
+            "tmp_driver/main",
+            "tmp_driver/add.c",
+            "tmp_driver/other/add.c",
+        ],
+        "",
+    );
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("'tmp_driver/other/add.c' as 'add.s'"));
+    assert!(fs::metadata("add.s").is_err());
+
+    // fmt rewrites files in place, unless it is only to check them.
+    fs::write("tmp_driver/fmt.c", "int f(int a){return a*2;}").unwrap();
+    let output = ccompiler(&["fmt", "--check", "tmp_driver/fmt.c"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("tmp_driver/fmt.c"));
+    let output = ccompiler(&["fmt", "tmp_driver/fmt.c"], "");
+    assert!(output.status.success());
+    assert_eq!(
+        fs::read_to_string("tmp_driver/fmt.c").unwrap(),
+        "int f(int a) {\n    return a * 2;\n}\n"
+    );
+    let output = ccompiler(&["fmt", "--check", "tmp_driver/fmt.c"], "");
+    assert!(output.status.success());
+    // Files that do not parse are left alone.
+    let output = ccompiler(&["fmt", "tmp_driver/bad1.c"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(fs::read_to_string("tmp_driver/bad1.c").unwrap(), "1 +;");
+    let output = ccompiler(
+        &["fmt", "--width=20", "-"],
+        "x = aaaa + bbbb + cccc + dddd;",
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "x = aaaa + bbbb\n        + cccc\n        + dddd;\n"
+    );
+    fs::remove_dir_all("tmp_driver").unwrap();
 }